@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, Response, StatusCode};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Server};
+use ring::digest::{digest, SHA256};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey};
+
+use crate::acme::Error;
+use crate::der;
+use crate::x509::{self, KeyPair};
+
+const HTTP01_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Extension carrying the digest of the key authorization in TLS-ALPN-01 certificates (RFC 8737).
+const ACME_IDENTIFIER: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 1, 31];
+
+const TLS_ALPN01_VALIDITY: Duration = Duration::from_secs(7 * 86400);
+
+/// Challenges being validated, by tokens for HTTP-01 and by domains for TLS-ALPN-01.
+#[derive(Default)]
+pub(crate) struct Challenges {
+    http01: Mutex<HashMap<String, String>>,
+    tls_alpn01: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl Challenges {
+    pub(crate) fn add_http01(&self, token: &str, key_authorization: String) {
+        self.http01
+            .lock()
+            .unwrap()
+            .insert(token.to_owned(), key_authorization);
+    }
+
+    pub(crate) fn add_tls_alpn01(
+        &self,
+        domain: &str,
+        key_authorization: &str,
+    ) -> Result<(), Error> {
+        let key = KeyPair::generate().map_err(|_| Error::Key)?;
+        let now = SystemTime::now();
+        let acme_identifier = x509::extension(
+            ACME_IDENTIFIER,
+            true,
+            &der::octet_string(digest(&SHA256, key_authorization.as_bytes()).as_ref()),
+        );
+
+        let certificate = x509::self_signed(
+            &key,
+            &[domain.to_owned()],
+            now..now + TLS_ALPN01_VALIDITY,
+            &[acme_identifier],
+        )
+        .map_err(|_| Error::Key)?;
+
+        let signing_key =
+            any_supported_type(&PrivateKey(key.pkcs8().to_vec())).map_err(|_| Error::Key)?;
+
+        self.tls_alpn01.lock().unwrap().insert(
+            domain.to_owned(),
+            Arc::new(CertifiedKey::new(
+                vec![Certificate(certificate)],
+                signing_key,
+            )),
+        );
+
+        Ok(())
+    }
+
+    pub(crate) fn remove(&self, domain: &str, token: &str) {
+        self.http01.lock().unwrap().remove(token);
+        self.tls_alpn01.lock().unwrap().remove(domain);
+    }
+
+    pub(crate) fn tls_alpn01(&self, domain: &str) -> Option<Arc<CertifiedKey>> {
+        self.tls_alpn01.lock().unwrap().get(domain).cloned()
+    }
+
+    fn respond(&self, request: &Request<Body>) -> Response<Body> {
+        let key_authorization = match request.method() {
+            &Method::GET => request
+                .uri()
+                .path()
+                .strip_prefix(HTTP01_PATH)
+                .and_then(|t| self.http01.lock().unwrap().get(t).cloned()),
+            _ => None,
+        };
+
+        match key_authorization {
+            Some(k) => Response::builder()
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .body(Body::from(k)),
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty()),
+        }
+        .unwrap()
+    }
+}
+
+/// Answers HTTP-01 challenges in cleartext. Other requests are answered with 404.
+pub(crate) async fn serve_http01(
+    incoming: AddrIncoming,
+    challenges: Arc<Challenges>,
+) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_| {
+        let challenges = Arc::clone(&challenges);
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let response = challenges.respond(&request);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    Server::builder(incoming).serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tls_alpn01_certificates_carry_the_key_authorization_digest() {
+        let challenges = Challenges::default();
+        challenges
+            .add_tls_alpn01("example.test", "token.thumbprint")
+            .unwrap();
+
+        let key = challenges.tls_alpn01("example.test").unwrap();
+        assert_eq!(key.cert.len(), 1);
+        let der = &key.cert[0].0;
+        let certificate = x509::Certificate::parse(der).unwrap();
+        assert_eq!(certificate.names, ["example.test"]);
+
+        let digest = digest(&SHA256, b"token.thumbprint");
+        let value = der::octet_string(digest.as_ref());
+        assert_eq!(certificate.extension(ACME_IDENTIFIER), Some(&value[..]));
+
+        // The extension must be critical.
+        let extension = x509::extension(ACME_IDENTIFIER, true, &value);
+        assert!(der.windows(extension.len()).any(|w| w == extension));
+
+        assert!(challenges.tls_alpn01("other.test").is_none());
+    }
+
+    #[test]
+    fn removes_answered_challenges() {
+        let challenges = Challenges::default();
+        challenges.add_http01("token", "token.thumbprint".to_owned());
+        challenges
+            .add_tls_alpn01("example.test", "token.thumbprint")
+            .unwrap();
+
+        challenges.remove("example.test", "token");
+
+        assert!(challenges.tls_alpn01("example.test").is_none());
+        assert!(challenges.http01.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn answers_http01_challenges() {
+        let challenges = Challenges::default();
+        challenges.add_http01("token", "token.thumbprint".to_owned());
+
+        let request = |method, path: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response =
+            challenges.respond(&request(Method::GET, "/.well-known/acme-challenge/token"));
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body, "token.thumbprint");
+
+        for (method, path) in [
+            (Method::GET, "/.well-known/acme-challenge/other"),
+            (Method::POST, "/.well-known/acme-challenge/token"),
+            (Method::GET, "/token"),
+        ] {
+            assert_eq!(
+                challenges.respond(&request(method, path)).status(),
+                StatusCode::NOT_FOUND
+            );
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use std::path::Path;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::header::{CONTENT_TYPE, LOCATION};
+use http::response::Parts;
+use http::{Method, Request, StatusCode};
+use hyper::client::HttpConnector;
+use hyper::Body;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use ring::digest::{digest, SHA256};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::{ClientConfig, RootCertStore};
+use serde_json::{json, Value};
+use tokio::time::{sleep, timeout};
+
+use crate::acme::Error;
+
+const REPLAY_NONCE: &str = "replay-nonce";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+
+/// Attempts to send a request with a fresh nonce when the server rejects one.
+const NONCE_ATTEMPTS: usize = 3;
+
+/// Polls of an authorization or an order, until it is no longer pending.
+const POLL_ATTEMPTS: usize = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Time to wait for a response of the ACME server, including its body.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+type HttpClient = hyper::Client<HttpsConnector<HttpConnector>>;
+
+pub(crate) fn base64url(data: &[u8]) -> String {
+    base64::encode_config(data, base64::URL_SAFE_NO_PAD)
+}
+
+pub(crate) struct Response {
+    pub(crate) location: Option<String>,
+    pub(crate) body: Bytes,
+}
+
+impl Response {
+    pub(crate) fn json(&self) -> Result<Value, Error> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Client of an ACME server (RFC 8555), signing requests with the account key in ES256.
+pub(crate) struct Client {
+    http: HttpClient,
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+    key: EcdsaKeyPair,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    pub(crate) async fn connect(
+        directory: &str,
+        ca_cert: Option<&Path>,
+        account_key: &[u8],
+    ) -> Result<Self, Error> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs()? {
+            // Ignores certificates of the system that webpki cannot parse.
+            let _ = roots.add(&rustls::Certificate(cert.0));
+        }
+
+        if let Some(path) = ca_cert {
+            let pem = std::fs::read(path)?;
+            roots.add_parsable_certificates(&rustls_pemfile::certs(&mut pem.as_slice())?);
+        }
+
+        let tls = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let http = hyper::Client::builder().build(
+            HttpsConnectorBuilder::new()
+                .with_tls_config(tls)
+                .https_only()
+                .enable_http1()
+                .build(),
+        );
+
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, account_key)
+            .map_err(|_| Error::Key)?;
+
+        let request = Request::builder().uri(directory).body(Body::empty())?;
+        let (_, body) = send(&http, request).await?;
+        let directory: Value = serde_json::from_slice(&body)?;
+        let url = |name: &'static str| -> Result<String, Error> {
+            directory[name]
+                .as_str()
+                .map(str::to_owned)
+                .ok_or(Error::Protocol("directory without a required URL"))
+        };
+
+        Ok(Self {
+            new_nonce: url("newNonce")?,
+            new_account: url("newAccount")?,
+            new_order: url("newOrder")?,
+            http,
+            key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    fn coordinates(&self) -> (String, String) {
+        // The public key is uncompressed: 0x04, then the X and Y coordinates.
+        let (x, y) = self.key.public_key().as_ref()[1..].split_at(32);
+
+        (base64url(x), base64url(y))
+    }
+
+    fn jwk(&self) -> Value {
+        let (x, y) = self.coordinates();
+
+        json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y })
+    }
+
+    /// JWK thumbprint of the account key (RFC 7638), part of key authorizations.
+    pub(crate) fn thumbprint(&self) -> String {
+        // The members must be sorted and without whitespaces.
+        let (x, y) = self.coordinates();
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+
+        base64url(digest(&SHA256, jwk.as_bytes()).as_ref())
+    }
+
+    /// Finds or creates the account of the key, which requires agreeing to the terms of service.
+    pub(crate) async fn register(
+        &mut self,
+        contact: &[String],
+        agree_tos: bool,
+    ) -> Result<(), Error> {
+        if !agree_tos {
+            return Err(Error::TermsNotAgreed);
+        }
+
+        let url = self.new_account.clone();
+        let response = self
+            .post(
+                &url,
+                Some(json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+
+        self.kid = Some(
+            response
+                .location
+                .ok_or(Error::Protocol("account without a URL"))?,
+        );
+
+        Ok(())
+    }
+
+    pub(crate) async fn new_order(&mut self, domains: &[String]) -> Result<Response, Error> {
+        let identifiers = domains
+            .iter()
+            .map(|d| json!({ "type": "dns", "value": d }))
+            .collect::<Vec<_>>();
+
+        let url = self.new_order.clone();
+        self.post(&url, Some(json!({ "identifiers": identifiers })))
+            .await
+    }
+
+    /// Fetches the object until its status is no longer `pending` nor `processing`.
+    pub(crate) async fn poll(&mut self, url: &str) -> Result<Value, Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            let object = self.post(url, None).await?.json()?;
+            match object["status"].as_str() {
+                Some("pending" | "processing") => sleep(POLL_INTERVAL).await,
+                _ => return Ok(object),
+            }
+        }
+
+        Err(Error::Timeout(url.to_owned()))
+    }
+
+    /// Sends a signed request, or a POST-as-GET request without the payload.
+    pub(crate) async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> Result<Response, Error> {
+        let payload = payload
+            .map(|p| base64url(p.to_string().as_bytes()))
+            .unwrap_or_default();
+        let mut attempts = 0;
+
+        loop {
+            attempts += 1;
+
+            let nonce = match self.nonce.take() {
+                Some(n) => n,
+                None => self.fetch_nonce().await?,
+            };
+
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+
+            let protected = base64url(protected.to_string().as_bytes());
+            let signature = self
+                .key
+                .sign(
+                    &SystemRandom::new(),
+                    format!("{}.{}", protected, payload).as_bytes(),
+                )
+                .map_err(|_| Error::Key)?;
+
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": base64url(signature.as_ref()),
+            });
+
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(body.to_string()))?;
+            let (parts, body) = send(&self.http, request).await?;
+            let header = |name| {
+                parts
+                    .headers
+                    .get(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_owned)
+            };
+
+            self.nonce = header(REPLAY_NONCE);
+
+            let response = Response {
+                location: header(LOCATION.as_str()),
+                body,
+            };
+
+            if parts.status.is_success() {
+                return Ok(response);
+            }
+
+            let problem = response.json().unwrap_or_default();
+            if problem["type"] == BAD_NONCE && attempts < NONCE_ATTEMPTS {
+                continue;
+            }
+
+            return Err(Error::Problem {
+                status: parts.status,
+                problem: format!(
+                    "{} {}",
+                    problem["type"].as_str().unwrap_or_default(),
+                    problem["detail"].as_str().unwrap_or_default()
+                ),
+            });
+        }
+    }
+
+    async fn fetch_nonce(&self) -> Result<String, Error> {
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri(&self.new_nonce)
+            .body(Body::empty())?;
+        let (parts, _) = send(&self.http, request).await?;
+
+        match parts.status {
+            StatusCode::OK | StatusCode::NO_CONTENT => parts
+                .headers
+                .get(REPLAY_NONCE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned)
+                .ok_or(Error::Protocol("no nonce returned")),
+            _ => Err(Error::Protocol("failed to fetch a nonce")),
+        }
+    }
+}
+
+/// Sends a request and reads the whole response, within [`REQUEST_TIMEOUT`].
+async fn send(http: &HttpClient, request: Request<Body>) -> Result<(Parts, Bytes), Error> {
+    let url = request.uri().to_string();
+    let response = async {
+        let (parts, body) = http.request(request).await?.into_parts();
+        Ok::<_, Error>((parts, hyper::body::to_bytes(body).await?))
+    };
+
+    timeout(REQUEST_TIMEOUT, response)
+        .await
+        .map_err(|_| Error::Timeout(url))?
+}
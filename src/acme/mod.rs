@@ -0,0 +1,739 @@
+mod challenge;
+mod client;
+
+use std::collections::BTreeSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use http::StatusCode;
+use hyper::server::conn::AddrIncoming;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+use tracing::{error, info, warn};
+
+use crate::acme::challenge::Challenges;
+use crate::acme::client::{base64url, Client};
+use crate::socket;
+use crate::tls;
+use crate::x509::{self, KeyPair};
+
+/// ALPN protocol ID of TLS-ALPN-01 validation handshakes.
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+const ACCOUNT_KEY: &str = "account.key";
+const CERT_CHAIN: &str = "cert.pem";
+const PRIVATE_KEY: &str = "privkey.pem";
+
+/// Interval to retry obtaining a certificate at after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Longest sleep between checks for renewal, so that changes of the clock are noticed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(86400);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("HTTP error: {0}")]
+    Http(#[from] hyper::Error),
+
+    #[error("HTTP semantics error: {0}")]
+    HttpSemantics(#[from] http::Error),
+
+    #[error("Invalid URL: {0}")]
+    Uri(#[from] http::uri::InvalidUri),
+
+    #[error("Invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("ACME server responded {status}: {problem}")]
+    Problem { status: StatusCode, problem: String },
+
+    #[error("Unexpected ACME response: {0}")]
+    Protocol(&'static str),
+
+    #[error("Validation failed: {0}")]
+    Invalid(String),
+
+    #[error("Timed out while waiting for {0}")]
+    Timeout(String),
+
+    #[error("The terms of service of the ACME server must be agreed to in order to register.")]
+    TermsNotAgreed,
+
+    #[error("The certificate is for {0}, not for the configured domains.")]
+    OtherDomains(String),
+
+    #[error("Failed to generate or use a key.")]
+    Key,
+
+    #[error(transparent)]
+    Tls(#[from] tls::Error),
+}
+
+/// Challenge type to prove the control of the domains by.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Challenge {
+    /// Answered in TLS handshakes of the HTTP/1.1 and HTTP/2 endpoint, which must be reachable
+    /// on port 443 of the domains.
+    #[default]
+    TlsAlpn01,
+
+    /// Answered by a cleartext listener, which must be reachable on port 80 of the domains.
+    Http01,
+}
+
+impl Challenge {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::TlsAlpn01 => "tls-alpn-01",
+            Self::Http01 => "http-01",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown challenge type: {0}")]
+pub struct UnknownChallenge(String);
+
+impl FromStr for Challenge {
+    type Err = UnknownChallenge;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tls-alpn-01" => Ok(Self::TlsAlpn01),
+            "http-01" => Ok(Self::Http01),
+            _ => Err(UnknownChallenge(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// URL of the directory of the ACME server.
+    pub directory: String,
+
+    pub domains: Vec<String>,
+
+    /// Contact URLs of the account, such as `mailto:admin@example.com`.
+    pub contact: Vec<String>,
+
+    /// Whether the terms of service of the ACME server are agreed to, without which the account
+    /// cannot be registered.
+    pub agree_tos: bool,
+
+    /// Directory to store the account key, the certificate and its private key in.
+    pub storage: PathBuf,
+
+    pub challenge: Challenge,
+
+    /// Address of the cleartext listener answering HTTP-01 challenges.
+    pub http01_bind: SocketAddr,
+
+    /// Root certificates in PEM to trust the ACME server by, in addition to the ones of the
+    /// system, such as the one of a local Pebble instance.
+    pub ca_cert: Option<PathBuf>,
+
+    /// Renews the certificate once it expires within this duration, or within a third of its
+    /// lifetime if shorter.
+    pub renew_before: Duration,
+}
+
+impl Config {
+    pub fn new<D, P>(directory: D, domains: Vec<String>, storage: P) -> Self
+    where
+        D: Into<String>,
+        P: Into<PathBuf>,
+    {
+        Self {
+            directory: directory.into(),
+            domains,
+            contact: Vec::new(),
+            agree_tos: false,
+            storage: storage.into(),
+            challenge: Challenge::default(),
+            http01_bind: SocketAddr::from(([0, 0, 0, 0], 80)),
+            ca_cert: None,
+            renew_before: Duration::from_secs(30 * 86400),
+        }
+    }
+}
+
+struct Issued {
+    key: Arc<CertifiedKey>,
+    validity: Range<SystemTime>,
+}
+
+/// Obtains and renews the certificate from an ACME server (RFC 8555), and resolves it for
+/// handshakes of both endpoints.
+pub struct Acme {
+    config: Config,
+    issued: RwLock<Option<Issued>>,
+    challenges: Arc<Challenges>,
+}
+
+impl Acme {
+    /// Loads the stored certificate, if any. Otherwise handshakes fail until [`run`](Self::run)
+    /// obtains one.
+    pub fn new(config: Config) -> Result<Self, Error> {
+        std::fs::create_dir_all(&config.storage)?;
+
+        let acme = Self {
+            config,
+            issued: RwLock::new(None),
+            challenges: Arc::default(),
+        };
+
+        if let Err(e) = acme.load() {
+            warn!("Ignoring the stored certificate: {}", e);
+        }
+
+        Ok(acme)
+    }
+
+    /// Obtains the certificate if not stored, then renews it before it expires. Also serves
+    /// HTTP-01 challenges if chosen. Runs until an error on the listener.
+    pub async fn run(self: Arc<Self>) -> Result<(), Error> {
+        if self.config.challenge == Challenge::Http01 {
+            let listener = socket::bind_tcp(self.config.http01_bind, 1)?.remove(0);
+            let incoming = AddrIncoming::from_listener(TcpListener::from_std(listener)?)?;
+            let challenges = Arc::clone(&self.challenges);
+
+            info!(
+                "ACME HTTP-01 listener started at: {}",
+                self.config.http01_bind
+            );
+            tokio::spawn(async move {
+                if let Err(e) = challenge::serve_http01(incoming, challenges).await {
+                    error!("{}", e);
+                }
+            });
+        }
+
+        loop {
+            let due = self.renewal_due();
+            if !due.is_zero() {
+                sleep(due.min(CHECK_INTERVAL)).await;
+                continue;
+            }
+
+            match self.obtain().await {
+                Ok(_) => info!(
+                    "Obtained a certificate for {}.",
+                    self.config.domains.join(", ")
+                ),
+                Err(e) => {
+                    error!("Failed to obtain a certificate: {}", e);
+                    sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.config.storage.join(name)
+    }
+
+    fn load(&self) -> Result<(), Error> {
+        let cert_chain = self.path(CERT_CHAIN);
+        if !cert_chain.exists() {
+            return Ok(());
+        }
+
+        let certs = rustls_pemfile::certs(&mut fs::read(cert_chain)?.as_slice())?;
+        let names = certs
+            .first()
+            .and_then(|c| x509::Certificate::parse(c))
+            .ok_or(Error::Protocol("unreadable certificate"))?
+            .names;
+        if !same_names(&names, &self.config.domains) {
+            return Err(Error::OtherDomains(names.join(", ")));
+        }
+
+        let key = tls::load_private_key(
+            &self.path(PRIVATE_KEY),
+            certs.first().cloned().map(Certificate).as_ref(),
+        )?;
+
+        self.install(certs, key.0)
+    }
+
+    fn install(&self, certs: Vec<Vec<u8>>, key: Vec<u8>) -> Result<(), Error> {
+        let validity = certs
+            .first()
+            .and_then(|c| x509::Certificate::parse(c))
+            .ok_or(Error::Protocol("unreadable certificate"))?
+            .validity;
+
+        let key = any_supported_type(&PrivateKey(key)).map_err(|_| Error::Key)?;
+        let key = CertifiedKey::new(certs.into_iter().map(Certificate).collect(), key);
+
+        *self.issued.write().unwrap() = Some(Issued {
+            key: Arc::new(key),
+            validity,
+        });
+
+        Ok(())
+    }
+
+    /// Returns the duration until the certificate should be renewed, zero if there is none.
+    fn renewal_due(&self) -> Duration {
+        match &*self.issued.read().unwrap() {
+            Some(Issued { validity, .. }) => {
+                let lifetime = validity
+                    .end
+                    .duration_since(validity.start)
+                    .unwrap_or_default();
+                let renew_at = validity.end - self.config.renew_before.min(lifetime / 3);
+
+                renew_at
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default()
+            }
+            None => Duration::ZERO,
+        }
+    }
+
+    fn account_key(&self) -> Result<Vec<u8>, Error> {
+        let path = self.path(ACCOUNT_KEY);
+        if path.exists() {
+            return Ok(tls::load_private_key(&path, None)?.0);
+        }
+
+        let key = KeyPair::generate().map_err(|_| Error::Key)?;
+        let temp = temp_path(&path);
+        write_file(
+            &temp,
+            x509::pem("PRIVATE KEY", key.pkcs8()).as_bytes(),
+            true,
+        )?;
+        fs::rename(temp, path)?;
+
+        Ok(key.pkcs8().to_vec())
+    }
+
+    async fn obtain(&self) -> Result<(), Error> {
+        let mut client = Client::connect(
+            &self.config.directory,
+            self.config.ca_cert.as_deref(),
+            &self.account_key()?,
+        )
+        .await?;
+
+        client
+            .register(&self.config.contact, self.config.agree_tos)
+            .await?;
+
+        let order = client.new_order(&self.config.domains).await?;
+        let url = order
+            .location
+            .clone()
+            .ok_or(Error::Protocol("order without a URL"))?;
+        let order = order.json()?;
+
+        for authorization in order["authorizations"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+        {
+            self.authorize(&mut client, authorization).await?;
+        }
+
+        let key = KeyPair::generate().map_err(|_| Error::Key)?;
+        let csr = x509::csr(&key, &self.config.domains).map_err(|_| Error::Key)?;
+        let finalize = order["finalize"]
+            .as_str()
+            .ok_or(Error::Protocol("order without a finalize URL"))?;
+
+        client
+            .post(finalize, Some(json!({ "csr": base64url(&csr) })))
+            .await?;
+
+        let order = client.poll(&url).await?;
+        let certificate = match order["status"].as_str() {
+            Some("valid") => order["certificate"]
+                .as_str()
+                .ok_or(Error::Protocol("order without a certificate URL"))?,
+            _ => return Err(Error::Invalid(format!("order {}", url))),
+        };
+
+        let chain = client.post(certificate, None).await?.body;
+        let certs = rustls_pemfile::certs(&mut chain.as_ref())?;
+
+        self.install(certs, key.pkcs8().to_vec())?;
+        self.store(&chain, &x509::pem("PRIVATE KEY", key.pkcs8()))?;
+
+        Ok(())
+    }
+
+    /// Writes the certificate chain and its key into temporary files, then renames them into
+    /// place, so that neither is ever read half-written. A crash between the two renames leaves
+    /// a key that does not match the certificate, which [`load`](Self::load) refuses, so that a
+    /// new certificate is obtained.
+    fn store(&self, chain: &[u8], key: &str) -> io::Result<()> {
+        let (cert_chain, private_key) = (self.path(CERT_CHAIN), self.path(PRIVATE_KEY));
+        let (cert_chain_temp, private_key_temp) = (temp_path(&cert_chain), temp_path(&private_key));
+
+        write_file(&private_key_temp, key.as_bytes(), true)?;
+        write_file(&cert_chain_temp, chain, false)?;
+        fs::rename(private_key_temp, private_key)?;
+        fs::rename(cert_chain_temp, cert_chain)
+    }
+
+    async fn authorize(&self, client: &mut Client, url: &str) -> Result<(), Error> {
+        let authorization = client.post(url, None).await?.json()?;
+        if authorization["status"] == "valid" {
+            return Ok(());
+        }
+
+        let domain = authorization["identifier"]["value"]
+            .as_str()
+            .ok_or(Error::Protocol("authorization without an identifier"))?;
+        let challenge = authorization["challenges"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|c| c["type"] == self.config.challenge.name())
+            .ok_or(Error::Protocol("challenge of the chosen type not offered"))?;
+        let (token, challenge_url) = match (challenge["token"].as_str(), challenge["url"].as_str())
+        {
+            (Some(t), Some(u)) => (t, u),
+            _ => return Err(Error::Protocol("challenge without a token or a URL")),
+        };
+
+        let key_authorization = format!("{}.{}", token, client.thumbprint());
+        match self.config.challenge {
+            Challenge::Http01 => self.challenges.add_http01(token, key_authorization),
+            Challenge::TlsAlpn01 => self.challenges.add_tls_alpn01(domain, &key_authorization)?,
+        }
+
+        let result = async {
+            client.post(challenge_url, Some(json!({}))).await?;
+            client.poll(url).await
+        }
+        .await;
+
+        self.challenges.remove(domain, token);
+
+        match result?["status"].as_str() {
+            Some("valid") => Ok(()),
+            _ => Err(Error::Invalid(domain.to_owned())),
+        }
+    }
+}
+
+impl ResolvesServerCert for Acme {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if client_hello
+            .alpn()
+            .into_iter()
+            .flatten()
+            .any(|p| p == ACME_TLS_ALPN)
+        {
+            return client_hello
+                .server_name()
+                .and_then(|n| self.challenges.tls_alpn01(n));
+        }
+
+        self.issued
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|i| Arc::clone(&i.key))
+    }
+}
+
+/// Whether the names of a certificate are exactly the domains, ignoring the case and the notation
+/// of IP addresses.
+fn same_names(names: &[String], domains: &[String]) -> bool {
+    let canonical = |names: &[String]| {
+        names
+            .iter()
+            .map(|n| {
+                let literal = n.strip_prefix('[').and_then(|n| n.strip_suffix(']'));
+                match literal.unwrap_or(n).parse::<IpAddr>() {
+                    Ok(ip) => ip.to_string(),
+                    Err(_) => n.to_ascii_lowercase(),
+                }
+            })
+            .collect::<BTreeSet<_>>()
+    };
+
+    canonical(names) == canonical(domains)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    temp.into()
+}
+
+/// Writes a file down to the disk, readable only by the owner if private, as for private keys.
+fn write_file(path: &Path, contents: &[u8], private: bool) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    // The mode only applies on creation, so a file left over by an earlier attempt goes first.
+    #[cfg(unix)]
+    if private {
+        let _ = fs::remove_file(path);
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use rustls::client::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+    use rustls::internal::msgs::handshake::DigitallySignedStruct;
+    use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, ServerName};
+
+    use super::*;
+
+    /// Accepts any certificate, keeping the one presented.
+    #[derive(Default)]
+    struct Presented(Mutex<Option<Certificate>>);
+
+    impl ServerCertVerifier for Presented {
+        fn verify_server_cert(
+            &self,
+            end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            *self.0.lock().unwrap() = Some(end_entity.clone());
+            Ok(ServerCertVerified::assertion())
+        }
+
+        // webpki refuses the critical acmeIdentifier extension of challenge certificates.
+        fn verify_tls13_signature(
+            &self,
+            _message: &[u8],
+            _cert: &Certificate,
+            _dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+    }
+
+    /// Performs a handshake in memory, offering the ALPN protocols, and returns the certificate
+    /// presented by the resolver.
+    fn handshake(acme: Arc<Acme>, alpn: &[&[u8]]) -> Result<Option<Certificate>, rustls::Error> {
+        let mut server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(acme);
+        server_config.alpn_protocols = vec![b"h2".to_vec(), ACME_TLS_ALPN.to_vec()];
+
+        let presented = Arc::new(Presented::default());
+        let mut client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::clone(&presented) as _)
+            .with_no_client_auth();
+        client_config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+
+        let mut server = ServerConnection::new(Arc::new(server_config))?;
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), "example.test".try_into().unwrap())?;
+
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets()?;
+
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+
+        let certificate = presented.0.lock().unwrap().take();
+        Ok(certificate)
+    }
+
+    #[test]
+    fn answers_tls_alpn01_handshakes_with_the_challenge_certificate() {
+        let acme = Arc::new(acme("tls-alpn-01"));
+        let now = SystemTime::now();
+        let key = KeyPair::generate().unwrap();
+        let chain = certificate(&key, now..now + Duration::from_secs(86400));
+        let certs = rustls_pemfile::certs(&mut chain.as_bytes()).unwrap();
+        acme.install(certs.clone(), key.pkcs8().to_vec()).unwrap();
+
+        // Without a pending challenge, validation handshakes fail.
+        assert!(handshake(Arc::clone(&acme), &[ACME_TLS_ALPN]).is_err());
+
+        acme.challenges
+            .add_tls_alpn01("example.test", "token.thumbprint")
+            .unwrap();
+        let challenge = acme.challenges.tls_alpn01("example.test").unwrap();
+
+        assert_eq!(
+            handshake(Arc::clone(&acme), &[ACME_TLS_ALPN]).unwrap(),
+            Some(challenge.cert[0].clone())
+        );
+        assert_eq!(
+            handshake(Arc::clone(&acme), &[b"h2"]).unwrap(),
+            Some(Certificate(certs[0].clone()))
+        );
+
+        let _ = fs::remove_dir_all(&acme.config.storage);
+    }
+
+    fn acme(test: &str) -> Acme {
+        let storage =
+            std::env::temp_dir().join(format!("h123-acme-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&storage);
+
+        Acme::new(Config::new(
+            "https://localhost:14000/dir",
+            vec!["example.test".to_string()],
+            storage,
+        ))
+        .unwrap()
+    }
+
+    fn certificate(key: &KeyPair, validity: Range<SystemTime>) -> String {
+        let der = x509::self_signed(key, &["example.test".to_string()], validity, &[]).unwrap();
+        x509::pem("CERTIFICATE", &der)
+    }
+
+    #[test]
+    fn stores_and_loads_the_certificate() {
+        let acme = acme("store");
+        assert!(acme.issued.read().unwrap().is_none());
+        assert_eq!(acme.renewal_due(), Duration::ZERO);
+
+        let now = SystemTime::now();
+        let key = KeyPair::generate().unwrap();
+        let chain = certificate(&key, now..now + Duration::from_secs(90 * 86400));
+        acme.store(chain.as_bytes(), &x509::pem("PRIVATE KEY", key.pkcs8()))
+            .unwrap();
+
+        let storage = acme.config.storage.clone();
+        let mut files = fs::read_dir(&storage)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, [CERT_CHAIN, PRIVATE_KEY]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(storage.join(PRIVATE_KEY))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let acme = Acme::new(acme.config.clone()).unwrap();
+        assert!(acme.issued.read().unwrap().is_some());
+
+        // Renewed 30 days before the expiry, as a third of the lifetime is longer.
+        let due = acme.renewal_due().as_secs();
+        assert!((59 * 86400..=60 * 86400).contains(&due), "{}", due);
+
+        fs::remove_dir_all(storage).unwrap();
+    }
+
+    #[test]
+    fn ignores_a_stored_certificate_of_another_key() {
+        let acme = acme("mismatch");
+        let now = SystemTime::now();
+        let chain = certificate(
+            &KeyPair::generate().unwrap(),
+            now..now + Duration::from_secs(86400),
+        );
+        let key = KeyPair::generate().unwrap();
+        acme.store(chain.as_bytes(), &x509::pem("PRIVATE KEY", key.pkcs8()))
+            .unwrap();
+
+        let acme = Acme::new(acme.config.clone()).unwrap();
+        assert!(acme.issued.read().unwrap().is_none());
+        assert!(matches!(
+            acme.load(),
+            Err(Error::Tls(tls::Error::KeyMismatch(_)))
+        ));
+
+        fs::remove_dir_all(&acme.config.storage).unwrap();
+    }
+
+    #[test]
+    fn ignores_a_stored_certificate_of_other_domains() {
+        let acme = acme("domains");
+        let now = SystemTime::now();
+        let key = KeyPair::generate().unwrap();
+        let der = x509::self_signed(
+            &key,
+            &["other.test".to_string()],
+            now..now + Duration::from_secs(86400),
+            &[],
+        )
+        .unwrap();
+        acme.store(
+            x509::pem("CERTIFICATE", &der).as_bytes(),
+            &x509::pem("PRIVATE KEY", key.pkcs8()),
+        )
+        .unwrap();
+
+        let acme = Acme::new(acme.config.clone()).unwrap();
+        assert!(acme.issued.read().unwrap().is_none());
+        assert!(matches!(acme.load(), Err(Error::OtherDomains(n)) if n == "other.test"));
+
+        fs::remove_dir_all(&acme.config.storage).unwrap();
+    }
+
+    #[test]
+    fn compares_names_regardless_of_order_case_and_notation() {
+        let names = |n: &[&str]| n.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+
+        assert!(same_names(
+            &names(&["www.example.test", "example.test", "::1"]),
+            &names(&["Example.test", "[::1]", "www.example.test"])
+        ));
+        assert!(!same_names(
+            &names(&["example.test"]),
+            &names(&["example.test", "www.example.test"])
+        ));
+        assert!(!same_names(
+            &names(&["example.test", "www.example.test"]),
+            &names(&["example.test"])
+        ));
+    }
+
+    #[test]
+    fn keeps_the_account_key() {
+        let acme = acme("account");
+        let key = acme.account_key().unwrap();
+        assert_eq!(acme.account_key().unwrap(), key);
+        assert!(!temp_path(&acme.path(ACCOUNT_KEY)).exists());
+
+        fs::remove_dir_all(&acme.config.storage).unwrap();
+    }
+}
@@ -57,32 +57,111 @@ where
     where
         U: Send + 'async_trait,
     {
+        let (parts, body) = u.into_parts();
         let mut builder = Request::builder()
-            .method(u.method().clone())
-            .uri(u.uri().clone())
-            .version(u.version());
+            .method(parts.method)
+            .uri(parts.uri)
+            .version(parts.version);
 
-        for (k, v) in u.headers() {
+        for (k, v) in &parts.headers {
             builder = builder.header(k, v.clone());
         }
 
-        builder
-            .body(self.u_to_v(u.into_body()).await?)
-            .map_err(Error::boxed)
+        if let Some(extensions) = builder.extensions_mut() {
+            *extensions = parts.extensions;
+        }
+
+        builder.body(self.u_to_v(body).await?).map_err(Error::boxed)
     }
 
     async fn v_to_u(&self, v: Response<V>) -> Result<Response<U>>
     where
         V: Send + 'async_trait,
     {
-        let mut builder = Response::builder().status(v.status()).version(v.version());
+        let (parts, body) = v.into_parts();
+        let mut builder = Response::builder()
+            .status(parts.status)
+            .version(parts.version);
 
-        for (k, v) in v.headers() {
+        for (k, v) in &parts.headers {
             builder = builder.header(k, v.clone());
         }
 
+        if let Some(extensions) = builder.extensions_mut() {
+            *extensions = parts.extensions;
+        }
+
         self.response_header(builder)
-            .body(self.v_to_u(v.into_body()).await?)
+            .body(self.v_to_u(body).await?)
             .map_err(Error::boxed)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use http::header::{HeaderValue, SET_COOKIE, VARY};
+    use http::{Method, StatusCode, Version};
+
+    use super::*;
+
+    struct Identity;
+
+    #[async_trait]
+    impl Adapter<String, Vec<u8>> for Identity {
+        async fn u_to_v(&self, u: String) -> Result<Vec<u8>> {
+            Ok(u.into_bytes())
+        }
+
+        async fn v_to_u(&self, v: Vec<u8>) -> Result<String> {
+            String::from_utf8(v).map_err(Error::boxed)
+        }
+    }
+
+    impl HttpHeaderAdapter for Identity {}
+
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    struct Marker(u8);
+
+    #[tokio::test]
+    async fn keeps_parts_of_requests() {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("https://example.com/a?b")
+            .version(Version::HTTP_2)
+            .header(VARY, "a")
+            .header(VARY, "b")
+            .extension(Marker(1))
+            .body("body".to_string())
+            .unwrap();
+
+        let request = HttpAdapter::u_to_v(&Identity, request).await.unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(request.uri(), "https://example.com/a?b");
+        assert_eq!(request.version(), Version::HTTP_2);
+        assert_eq!(
+            request.headers().get_all(VARY).iter().collect::<Vec<_>>(),
+            [HeaderValue::from_static("a"), HeaderValue::from_static("b")]
+        );
+        assert_eq!(request.extensions().get(), Some(&Marker(1)));
+        assert_eq!(request.body(), b"body");
+    }
+
+    #[tokio::test]
+    async fn keeps_parts_of_responses() {
+        let response = Response::builder()
+            .status(StatusCode::CREATED)
+            .version(Version::HTTP_11)
+            .header(SET_COOKIE, "a=1")
+            .header(SET_COOKIE, "b=2")
+            .extension(Marker(2))
+            .body(b"body".to_vec())
+            .unwrap();
+
+        let response = HttpAdapter::v_to_u(&Identity, response).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.version(), Version::HTTP_11);
+        assert_eq!(response.headers().get_all(SET_COOKIE).iter().count(), 2);
+        assert_eq!(response.extensions().get(), Some(&Marker(2)));
+        assert_eq!(response.body(), "body");
+    }
+}
@@ -0,0 +1,360 @@
+//! Just enough DER to build and read certificates, without pulling in an ASN.1 crate.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) const BOOLEAN: u8 = 0x01;
+pub(crate) const INTEGER: u8 = 0x02;
+pub(crate) const BIT_STRING: u8 = 0x03;
+pub(crate) const OCTET_STRING: u8 = 0x04;
+pub(crate) const NULL: u8 = 0x05;
+pub(crate) const OID: u8 = 0x06;
+pub(crate) const ENUMERATED: u8 = 0x0a;
+pub(crate) const UTF8_STRING: u8 = 0x0c;
+pub(crate) const PRINTABLE_STRING: u8 = 0x13;
+pub(crate) const IA5_STRING: u8 = 0x16;
+pub(crate) const UTC_TIME: u8 = 0x17;
+pub(crate) const GENERALIZED_TIME: u8 = 0x18;
+pub(crate) const SEQUENCE: u8 = 0x30;
+pub(crate) const SET: u8 = 0x31;
+
+/// Tag of a constructed, context-specific element such as `[0]`.
+pub(crate) const fn context(n: u8) -> u8 {
+    0xa0 | n
+}
+
+/// Tag of a primitive, context-specific element, such as an implicitly tagged string.
+pub(crate) const fn context_primitive(n: u8) -> u8 {
+    0x80 | n
+}
+
+pub(crate) fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+
+    out.extend_from_slice(content);
+    out
+}
+
+pub(crate) fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    tlv(SEQUENCE, &items.concat())
+}
+
+pub(crate) fn set(items: &[Vec<u8>]) -> Vec<u8> {
+    tlv(SET, &items.concat())
+}
+
+/// Encodes an unsigned big-endian integer.
+pub(crate) fn integer(bytes: &[u8]) -> Vec<u8> {
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    let bytes = &bytes[skip.min(bytes.len().saturating_sub(1))..];
+    match bytes.first() {
+        Some(b) if b & 0x80 != 0 => tlv(INTEGER, &[&[0], bytes].concat()),
+        Some(_) => tlv(INTEGER, bytes),
+        None => tlv(INTEGER, &[0]),
+    }
+}
+
+pub(crate) fn oid(arcs: &[u64]) -> Vec<u8> {
+    let mut content = Vec::new();
+    let first =
+        arcs.first().copied().unwrap_or_default() * 40 + arcs.get(1).copied().unwrap_or_default();
+    for arc in std::iter::once(first).chain(arcs.iter().skip(2).copied()) {
+        let mut groups = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            groups.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+
+        content.extend(groups.iter().rev());
+    }
+
+    tlv(OID, &content)
+}
+
+pub(crate) fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    tlv(BIT_STRING, &[&[0], bytes].concat())
+}
+
+pub(crate) fn octet_string(bytes: &[u8]) -> Vec<u8> {
+    tlv(OCTET_STRING, bytes)
+}
+
+pub(crate) fn utf8_string(s: &str) -> Vec<u8> {
+    tlv(UTF8_STRING, s.as_bytes())
+}
+
+/// Encodes a time as UTCTime until 2049, and as GeneralizedTime after, as X.509 requires.
+pub(crate) fn time(time: SystemTime) -> Vec<u8> {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let secs = secs.rem_euclid(86400);
+    let rest = format!(
+        "{:02}{:02}{:02}{:02}{:02}Z",
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+
+    match year {
+        1950..=2049 => tlv(UTC_TIME, format!("{:02}{}", year % 100, rest).as_bytes()),
+        _ => tlv(GENERALIZED_TIME, format!("{:04}{}", year, rest).as_bytes()),
+    }
+}
+
+/// An element read from DER.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Element<'a> {
+    pub(crate) tag: u8,
+    pub(crate) content: &'a [u8],
+
+    /// Whole encoding of the element, including the tag and the length.
+    pub(crate) raw: &'a [u8],
+}
+
+impl<'a> Element<'a> {
+    /// Reads the elements inside a constructed element.
+    pub(crate) fn reader(&self) -> Reader<'a> {
+        Reader::new(self.content)
+    }
+
+    pub(crate) fn time(&self) -> Option<SystemTime> {
+        let s = std::str::from_utf8(self.content).ok()?.strip_suffix('Z')?;
+        let (year, rest) = match self.tag {
+            UTC_TIME => {
+                let year: i64 = s.get(..2)?.parse().ok()?;
+                (if year < 50 { 2000 + year } else { 1900 + year }, &s[2..])
+            }
+            GENERALIZED_TIME => (s.get(..4)?.parse().ok()?, &s[4..]),
+            _ => return None,
+        };
+
+        let field = |i: usize| -> Option<i64> { rest.get(i * 2..i * 2 + 2)?.parse().ok() };
+        let days = days_from_civil(year, field(0)?, field(1)?);
+        let secs = days * 86400 + field(2)? * 3600 + field(3)? * 60 + field(4)?;
+
+        UNIX_EPOCH.checked_add(Duration::from_secs(u64::try_from(secs).ok()?))
+    }
+}
+
+/// Reads consecutive DER elements. Reading stops at the end or at malformed input.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Reads the next element if it has the tag.
+    pub(crate) fn expect(&mut self, tag: u8) -> Option<Element<'a>> {
+        self.next().filter(|e| e.tag == tag)
+    }
+
+    /// Reads the next element if it has the tag, leaving it unread otherwise.
+    pub(crate) fn optional(&mut self, tag: u8) -> Option<Element<'a>> {
+        let mut peek = *self;
+        let element = peek.expect(tag)?;
+        *self = peek;
+        Some(element)
+    }
+}
+
+impl<'a> Iterator for Reader<'a> {
+    type Item = Element<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&tag, rest) = self.data.split_first()?;
+        let (&first, rest) = rest.split_first()?;
+        let (len, rest) = match first {
+            0..=0x7f => (first as usize, rest),
+            0x81..=0x84 => {
+                let n = (first & 0x7f) as usize;
+                let len = rest
+                    .get(..n)?
+                    .iter()
+                    .fold(0usize, |l, b| l << 8 | *b as usize);
+                (len, &rest[n..])
+            }
+            _ => return None,
+        };
+
+        let content = rest.get(..len)?;
+        let header = self.data.len() - rest.len();
+        let element = Element {
+            tag,
+            content,
+            raw: &self.data[..header + len],
+        };
+
+        self.data = &rest[len..];
+        Some(element)
+    }
+}
+
+// Conversions between days since the Unix epoch and the proleptic Gregorian calendar.
+// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn encodes_lengths() {
+        assert_eq!(tlv(OCTET_STRING, &[]), [OCTET_STRING, 0]);
+        assert_eq!(tlv(OCTET_STRING, &[0; 0x7f])[..2], [OCTET_STRING, 0x7f]);
+        assert_eq!(
+            tlv(OCTET_STRING, &[0; 0x80])[..3],
+            [OCTET_STRING, 0x81, 0x80]
+        );
+        assert_eq!(
+            tlv(OCTET_STRING, &[0; 0x100])[..4],
+            [OCTET_STRING, 0x82, 0x01, 0x00]
+        );
+    }
+
+    #[test]
+    fn encodes_integers() {
+        assert_eq!(integer(&[]), [INTEGER, 1, 0]);
+        assert_eq!(integer(&[0, 0]), [INTEGER, 1, 0]);
+        assert_eq!(integer(&[0x7f]), [INTEGER, 1, 0x7f]);
+        assert_eq!(integer(&[0x80]), [INTEGER, 2, 0, 0x80]);
+        assert_eq!(integer(&[0, 0, 1]), [INTEGER, 1, 1]);
+        assert_eq!(integer(&[0, 0x80, 1]), [INTEGER, 3, 0, 0x80, 1]);
+    }
+
+    #[test]
+    fn encodes_oids() {
+        assert_eq!(
+            oid(&[1, 2, 840, 10045, 2, 1]),
+            [OID, 7, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01]
+        );
+        assert_eq!(
+            oid(&[1, 3, 14, 3, 2, 26]),
+            [OID, 5, 0x2b, 0x0e, 0x03, 0x02, 0x1a]
+        );
+    }
+
+    #[test]
+    fn switches_to_generalized_time_after_2049() {
+        let cases = [
+            (0, UTC_TIME, "700101000000Z"),
+            (1_792_381_057, UTC_TIME, "261019033737Z"),
+            (2_524_607_999, UTC_TIME, "491231235959Z"),
+            (2_524_608_000, GENERALIZED_TIME, "20500101000000Z"),
+            (4_945_981_057, GENERALIZED_TIME, "21260925033737Z"),
+        ];
+
+        for (secs, tag, text) in cases {
+            let encoded = time(at(secs));
+            assert_eq!(encoded, tlv(tag, text.as_bytes()), "{}", text);
+
+            let element = Reader::new(&encoded).next().unwrap();
+            assert_eq!(element.time(), Some(at(secs)), "{}", text);
+        }
+    }
+
+    #[test]
+    fn reads_utc_time_before_1970_as_none() {
+        let encoded = tlv(UTC_TIME, b"500101000000Z");
+        let element = Reader::new(&encoded).next().unwrap();
+        assert_eq!(element.time(), None);
+    }
+
+    #[test]
+    fn reads_nested_elements() {
+        let inner = [integer(&[1]), octet_string(b"ab")];
+        let encoded = sequence(&[sequence(&inner), utf8_string("x")]);
+
+        let mut reader = Reader::new(&encoded);
+        let outer = reader.expect(SEQUENCE).unwrap();
+        assert_eq!(outer.raw, encoded.as_slice());
+        assert!(reader.next().is_none());
+
+        let mut reader = outer.reader();
+        let nested = reader.expect(SEQUENCE).unwrap();
+        assert_eq!(nested.raw, sequence(&inner).as_slice());
+        assert_eq!(reader.expect(UTF8_STRING).unwrap().content, b"x");
+
+        let mut reader = nested.reader();
+        assert!(reader.optional(BOOLEAN).is_none());
+        assert_eq!(reader.optional(INTEGER).unwrap().content, [1]);
+        assert!(reader.expect(INTEGER).is_none());
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn stops_at_malformed_input() {
+        let long = tlv(OCTET_STRING, &[7; 0x100]);
+        assert_eq!(Reader::new(&long).next().unwrap().content.len(), 0x100);
+
+        // Truncated content, truncated length, indefinite length, and a missing length.
+        for data in [
+            &long[..long.len() - 1],
+            &long[..3],
+            &[SEQUENCE, 0x80, 0, 0][..],
+            &[SEQUENCE][..],
+        ] {
+            assert!(Reader::new(data).next().is_none());
+        }
+    }
+
+    #[test]
+    fn converts_civil_dates() {
+        for (date, days) in [
+            ((1970, 1, 1), 0),
+            ((1969, 12, 31), -1),
+            ((2000, 3, 1), 11017),
+            ((2024, 2, 29), 19782),
+            ((1900, 3, 1), -25508),
+            ((2100, 2, 28), 47540),
+        ] {
+            assert_eq!(days_from_civil(date.0, date.1, date.2), days, "{:?}", date);
+            assert_eq!(civil_from_days(days), date);
+        }
+
+        for days in (-800_000..800_000).step_by(997) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+}
@@ -1,19 +1,39 @@
 use std::convert::Infallible;
+use std::future::Future;
 use std::marker::PhantomData;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 use bytes::Bytes;
-use http::{Request, Response, StatusCode};
+use http::header::HeaderValue;
+use http::{HeaderMap, Request, Response, StatusCode};
+use hyper::body::{HttpBody, SizeHint};
+use hyper::rt::Executor;
 use hyper::server::conn::AddrIncoming;
 use hyper::service::{make_service_fn, service_fn, Service};
 use hyper::{Body, Server};
-use tracing::{error, info};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{error::Elapsed, timeout};
+use tracing::{error, info, warn};
 
+use crate::acme::ACME_TLS_ALPN;
 use crate::convert::HttpAdapter;
-use crate::h12::tls::{TlsAcceptor, TlsStream};
+use crate::h12::tls::{Active, TlsAcceptor, TlsStream};
 use crate::h12::BodyAdapter;
+use crate::metadata::{EarlyData, PreloadLinks, Priority};
+use crate::options::{EarlyDataPolicy, InvalidOptions, Limits, Options, Timeouts};
+use crate::proxy::forward::Forwarder;
+use crate::proxy::{udp, AllowList, ForwardProxy};
 use crate::service::call_service;
+use crate::shutdown::Shutdown;
+use crate::socket;
+use crate::websocket::{self, WebSocketHandler};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -30,31 +50,108 @@ pub enum Error {
     Conversion(#[from] crate::convert::Error),
 
     #[error("Service error: {0}")]
-    Service(Box<dyn std::error::Error>),
+    Service(Box<dyn std::error::Error + Send>),
+
+    #[error("Acceptor task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
+
+    #[error("Timed out while receiving the request body.")]
+    RequestTimeout,
+
+    #[error("Timed out while waiting for the service to respond.")]
+    ResponseTimeout,
+}
+
+impl Error {
+    fn status(&self) -> StatusCode {
+        match self {
+            Self::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            Self::ResponseTimeout => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+const MAX_EARLY_DATA_SIZE: u32 = 16384;
+
+/// Handlers of requests that are not passed to the service.
+#[derive(Clone, Default)]
+struct Handlers {
+    websocket: Option<Arc<dyn WebSocketHandler>>,
+    connect_udp: Option<Arc<AllowList>>,
+    forward_proxy: Option<Arc<Forwarder>>,
+}
+
+impl Handlers {
+    fn uses_extended_connect(&self) -> bool {
+        self.websocket.is_some() || self.connect_udp.is_some()
+    }
 }
 
 pub struct Endpoint<S, E> {
-    rustls_config: rustls::ServerConfig,
+    rustls_config: Arc<rustls::ServerConfig>,
     bind_to: SocketAddr,
     service: Arc<S>,
+    alt_svc: Option<HeaderValue>,
+    timeouts: Timeouts,
+    limits: Limits,
+    acceptors: usize,
+    shutdown: Shutdown,
+    handlers: Handlers,
     _phantom: PhantomData<fn() -> E>,
 }
 
 impl<S, E> Endpoint<S, E> {
-    pub fn new<A>(rustls_config: &rustls::ServerConfig, bind_to: A, service: Arc<S>) -> Self
+    pub fn new<A>(
+        rustls_config: &rustls::ServerConfig,
+        bind_to: A,
+        service: Arc<S>,
+        options: &Options,
+        shutdown: Shutdown,
+    ) -> Result<Self, InvalidOptions>
     where
         A: Into<SocketAddr>,
     {
         let mut rustls_config = rustls_config.clone();
 
         rustls_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        rustls_config.max_early_data_size = match options.early_data {
+            EarlyDataPolicy::Disabled => 0,
+            EarlyDataPolicy::SafeMethods => MAX_EARLY_DATA_SIZE,
+        };
+
+        let bind_to = bind_to.into();
 
-        Self {
-            rustls_config,
-            bind_to: bind_to.into(),
+        Ok(Self {
+            rustls_config: Arc::new(rustls_config),
+            bind_to,
             service,
+            alt_svc: options.alt_svc.header_value(bind_to.port())?,
+            timeouts: options.timeouts.clone(),
+            limits: options.limits.clone(),
+            acceptors: options.acceptors,
+            shutdown,
+            handlers: Handlers::default(),
             _phantom: PhantomData,
-        }
+        })
+    }
+
+    pub fn websocket(&mut self, handler: Arc<dyn WebSocketHandler>) {
+        self.handlers.websocket = Some(handler);
+    }
+
+    pub fn connect_udp(&mut self, allow_list: AllowList) {
+        self.handlers.connect_udp = Some(Arc::new(allow_list));
+    }
+
+    pub fn forward_proxy(&mut self, proxy: ForwardProxy) {
+        self.handlers.forward_proxy = Some(Arc::new(Forwarder::new(proxy)));
+    }
+
+    pub fn acme_tls_alpn(&mut self) {
+        Arc::make_mut(&mut self.rustls_config)
+            .alpn_protocols
+            .push(ACME_TLS_ALPN.to_vec());
     }
 }
 
@@ -63,9 +160,37 @@ where
     S: Service<Request<Bytes>, Response = Response<Bytes>, Error = E>,
     S: Send + Sync + Clone + 'static,
     S::Future: Send,
-    E: std::error::Error + 'static,
+    E: std::error::Error + Send + 'static,
 {
     pub async fn begin(self) -> Result<(), Error> {
+        let listeners = socket::bind_tcp(self.bind_to, self.acceptors)?;
+        let connections = self
+            .limits
+            .tcp_connections
+            .map(|n| Arc::new(Semaphore::new(n)));
+
+        let this = Arc::new(self);
+        let mut acceptors = JoinSet::new();
+        for listener in listeners {
+            let incoming = AddrIncoming::from_listener(TcpListener::from_std(listener)?)?;
+
+            acceptors.spawn(Arc::clone(&this).serve(incoming, connections.clone()));
+        }
+
+        info!("HTTP/1.1 and HTTP/2 endpoint started at: {}", &this.bind_to);
+
+        while let Some(r) = acceptors.join_next().await {
+            r??;
+        }
+
+        Ok(())
+    }
+
+    async fn serve(
+        self: Arc<Self>,
+        incoming: AddrIncoming,
+        connections: Option<Arc<Semaphore>>,
+    ) -> Result<(), Error> {
         let make_service = make_service_fn(|stream: &TlsStream| {
             info!(
                 "Connecting from {}",
@@ -76,47 +201,380 @@ where
             );
 
             let service = Arc::clone(&self.service);
+            let timeouts = self.timeouts.clone();
+            let alt_svc = self.alt_svc.clone();
+            let shutdown = self.shutdown.clone();
+            let early_data = stream.early_data();
+            let activity = stream.activity();
+            let info = stream.info();
+            let handlers = self.handlers.clone();
             async move {
-                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                Ok::<_, Infallible>(service_fn(move |mut request: Request<Body>| {
                     let service = Arc::clone(&service);
+                    let timeouts = timeouts.clone();
+                    let handlers = handlers.clone();
+                    let adapter = BodyAdapter::new(alt_svc.clone(), shutdown.clone());
+                    // Pipelined requests may all have been read from early data.
+                    let early = early_data.load(Ordering::Acquire);
+                    let mut info = info.lock().unwrap().clone();
+                    info.version = request.version();
+                    request.extensions_mut().insert(info);
+                    // Tunnels upgraded from the request keep the connection busy on their own, and
+                    // are closed with the connections once the grace period of a shutdown elapses.
+                    request.extensions_mut().insert(activity.clone());
+                    request.extensions_mut().insert(shutdown.clone());
+                    let active = activity.begin();
                     async move {
-                        let adapter = BodyAdapter::new(self.bind_to.port());
-                        match Self::handle(adapter, request, &service).await {
-                            Ok(r) => Ok::<_, Infallible>(r),
+                        let response = match Self::handle(
+                            adapter, &handlers, request, early, &service, &timeouts,
+                        )
+                        .await
+                        {
+                            Ok(r) => r,
                             Err(e) => {
                                 error!("{}", e);
 
-                                Ok(Response::builder()
-                                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                                Response::builder()
+                                    .status(e.status())
                                     .body(Body::empty())
-                                    .unwrap())
+                                    .unwrap()
                             }
-                        }
+                        };
+
+                        Ok::<_, Infallible>(response.map(|inner| ActiveBody {
+                            inner,
+                            _active: active,
+                        }))
                     }
                 }))
             }
         });
 
-        let server = Server::builder(TlsAcceptor::new(
-            Arc::new(self.rustls_config),
-            AddrIncoming::bind(&self.bind_to)?,
+        let mut builder = Server::builder(TlsAcceptor::new(
+            Arc::clone(&self.rustls_config),
+            incoming,
+            self.timeouts.clone(),
+            connections,
         ))
-        .serve(make_service);
+        .executor(ConnectionExecutor {
+            shutdown: self.shutdown.clone(),
+        });
+
+        if let Some(d) = self.timeouts.header_read {
+            builder = builder.http1_header_read_timeout(d);
+        }
+
+        if let Some(n) = self.limits.h2_streams {
+            builder = builder.http2_max_concurrent_streams(n);
+        }
+
+        if self.handlers.uses_extended_connect() {
+            builder = builder.http2_enable_connect_protocol();
+        }
 
-        info!("HTTP/1.1 and HTTP/2 endpoint started at: {}", &self.bind_to);
+        let server = builder.serve(make_service).with_graceful_shutdown({
+            let mut shutdown = self.shutdown.clone();
+            async move {
+                shutdown.requested().await;
+            }
+        });
+
+        tokio::select! {
+            r = server => Ok(r?),
+            _ = self.shutdown.clone().deadline() => {
+                warn!("Grace period elapsed, closing remaining HTTP/1.1 and HTTP/2 connections.");
 
-        Ok(server.await?)
+                Ok(())
+            }
+        }
     }
 
     async fn handle(
         adapter: BodyAdapter,
+        handlers: &Handlers,
         request: Request<Body>,
+        early: bool,
         service: &Arc<S>,
+        timeouts: &Timeouts,
     ) -> Result<Response<Body>, Error> {
-        let response = call_service(service, adapter.u_to_v(request).await?)
+        if early && !EarlyData::allows(request.method()) {
+            return Ok(Response::builder()
+                .status(EarlyData::too_early())
+                .body(Body::empty())?);
+        }
+
+        if let Some(h) = &handlers.websocket {
+            if websocket::is_websocket(&request) {
+                return Ok(websocket::upgrade(request, Arc::clone(h))?);
+            }
+        }
+
+        if let Some(a) = &handlers.connect_udp {
+            if udp::is_connect_udp(&request) {
+                return Ok(udp::connect(request, a).await?);
+            }
+        }
+
+        if let Some(f) = &handlers.forward_proxy {
+            if Forwarder::is_proxy_request(&request) {
+                return Ok(f.serve(request).await?);
+            }
+        }
+
+        let mut request = with_timeout(timeouts.request_body, adapter.u_to_v(request))
             .await
+            .map_err(|_| Error::RequestTimeout)??;
+        if early {
+            request.extensions_mut().insert(EarlyData);
+        }
+
+        let preload = PreloadLinks::default();
+        request.extensions_mut().insert(preload.clone());
+
+        let priority = Priority::default().merge(request.headers());
+        request.extensions_mut().insert(priority);
+
+        let mut response = with_timeout(timeouts.response, call_service(service, request))
+            .await
+            .map_err(|_| Error::ResponseTimeout)?
             .map_err(|e| Error::Service(Box::new(e)))?;
 
+        preload.apply(response.headers_mut());
+
         Ok(adapter.v_to_u(response).await?)
     }
 }
+
+/// Response body keeping its request in flight until it has been sent, so that the connection is
+/// not idle while the response waits for the service or for flow control.
+struct ActiveBody {
+    inner: Body,
+    _active: Active,
+}
+
+impl HttpBody for ActiveBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.inner).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Spawns the connection tasks of hyper, cancelling them once the grace period of a shutdown
+/// elapses so that remaining connections are closed.
+#[derive(Clone)]
+struct ConnectionExecutor {
+    shutdown: Shutdown,
+}
+
+impl<F> Executor<F> for ConnectionExecutor
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    fn execute(&self, future: F) {
+        self.shutdown.spawn(future);
+    }
+}
+
+async fn with_timeout<F>(duration: Option<Duration>, future: F) -> Result<F::Output, Elapsed>
+where
+    F: Future,
+{
+    match duration {
+        Some(d) => timeout(d, future).await,
+        None => Ok(future.await),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use std::time::SystemTime;
+
+    use rustls::client::{ServerCertVerified, ServerCertVerifier};
+    use rustls::{Certificate, ClientConfig, ClientConnection, ServerName};
+    use tokio::sync::oneshot;
+
+    use super::*;
+    use crate::options::EarlyDataPolicy;
+    use crate::service::StaticFileService;
+    use crate::tls::CertResolver;
+
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    /// Starts an endpoint accepting early data, serving files from an empty directory.
+    async fn serve_early_data() -> SocketAddr {
+        let keys = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys");
+        let resolver = CertResolver::load(keys.join("ec.crt"), keys.join("ec.pem")).unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        let options = Options {
+            early_data: EarlyDataPolicy::SafeMethods,
+            ..Options::default()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let root = std::env::temp_dir().join(format!("h123-early-data-{}", std::process::id()));
+        let (shutdown, handle) = Shutdown::new();
+        let endpoint = Endpoint::new(
+            &config,
+            addr,
+            Arc::new(StaticFileService::new(root)),
+            &options,
+            shutdown,
+        )
+        .unwrap();
+
+        tokio::spawn(async move {
+            let _handle = handle;
+            Arc::new(endpoint)
+                .serve(AddrIncoming::from_listener(listener).unwrap(), None)
+                .await
+        });
+
+        addr
+    }
+
+    /// Sends `early` in early data if the session allows it, and `rest` once the handshake has
+    /// completed. Returns whether the early data was accepted, and what was read until the server
+    /// closed the connection.
+    fn exchange(
+        config: Arc<ClientConfig>,
+        addr: SocketAddr,
+        early: &[u8],
+        rest: &[u8],
+    ) -> (bool, String) {
+        let mut connection =
+            ClientConnection::new(config, "localhost".try_into().unwrap()).unwrap();
+        let mut socket = std::net::TcpStream::connect(addr).unwrap();
+        if let Some(mut writer) = connection.early_data() {
+            writer.write_all(early).unwrap();
+        }
+
+        while connection.is_handshaking() {
+            connection.complete_io(&mut socket).unwrap();
+        }
+
+        let accepted = connection.is_early_data_accepted();
+        let mut stream = rustls::Stream::new(&mut connection, &mut socket);
+        stream.write_all(rest).unwrap();
+
+        let mut responses = String::new();
+        // Only what was read before the connection closed matters.
+        let _ = stream.read_to_string(&mut responses);
+
+        (accepted, responses)
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_in_early_data_are_all_too_early() {
+        let addr = serve_early_data().await;
+
+        let mut config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        config.enable_early_data = true;
+        let config = Arc::new(config);
+
+        let (accepted, _) = tokio::task::spawn_blocking({
+            let config = Arc::clone(&config);
+            move || {
+                // The first connection obtains a session ticket allowing early data.
+                exchange(
+                    config,
+                    addr,
+                    b"",
+                    b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                )
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!accepted);
+
+        let (accepted, responses) = tokio::task::spawn_blocking(move || {
+            exchange(
+                config,
+                addr,
+                b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n\
+                  POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\
+                  Connection: close\r\n\r\n",
+                b"",
+            )
+        })
+        .await
+        .unwrap();
+
+        assert!(accepted);
+        assert_eq!(responses.matches("HTTP/1.1 425 ").count(), 2);
+    }
+
+    #[tokio::test]
+    async fn connections_run_until_the_deadline() {
+        let (shutdown, handle) = Shutdown::new();
+        let executor = ConnectionExecutor { shutdown };
+
+        let (finished, finished_rx) = oneshot::channel();
+        executor.execute(async move {
+            finished.send(()).unwrap();
+        });
+
+        let (held, held_rx) = oneshot::channel::<()>();
+        executor.execute(async move {
+            let _held = held;
+            pending::<()>().await
+        });
+
+        timeout(Duration::from_secs(5), finished_rx)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The sender is dropped only when the pending task is cancelled.
+        handle.shutdown(Duration::from_millis(10));
+        assert!(timeout(Duration::from_secs(5), held_rx)
+            .await
+            .unwrap()
+            .is_err());
+    }
+}
@@ -3,22 +3,26 @@ mod tls;
 
 use async_trait::async_trait;
 use bytes::Bytes;
-use http::header::ALT_SVC;
+use http::header::{HeaderValue, ALT_SVC};
 use http::response::Builder;
 use hyper::body::to_bytes;
 use hyper::Body;
 
 use crate::convert::{Adapter, Error as ConversionError, HttpHeaderAdapter};
+use crate::metadata::SuppressAltSvc;
+use crate::shutdown::Shutdown;
 
 pub use endpoint::{Endpoint, Error};
+pub(crate) use tls::Activity;
 
 struct BodyAdapter {
-    port: u16,
+    alt_svc: Option<HeaderValue>,
+    shutdown: Shutdown,
 }
 
 impl BodyAdapter {
-    fn new(port: u16) -> Self {
-        Self { port }
+    fn new(alt_svc: Option<HeaderValue>, shutdown: Shutdown) -> Self {
+        Self { alt_svc, shutdown }
     }
 }
 
@@ -35,12 +39,62 @@ impl Adapter<Body, Bytes> for BodyAdapter {
 
 impl HttpHeaderAdapter for BodyAdapter {
     fn response_header(&self, response: Builder) -> Builder {
-        response.header(
-            ALT_SVC,
-            format!(
-                "h3=\":{}\"; ma=86400, h3-29=\":{}\"; ma=86400",
-                self.port, self.port
-            ),
-        )
+        let suppressed = response
+            .extensions_ref()
+            .map(|e| e.get::<SuppressAltSvc>().is_some())
+            .unwrap_or(false);
+
+        // Leaves the advertisement of the service alone, as a second field would contradict it.
+        let advertised = response
+            .headers_ref()
+            .map(|h| h.contains_key(ALT_SVC))
+            .unwrap_or(false);
+
+        match &self.alt_svc {
+            Some(_) if suppressed || advertised => response,
+            // Tells clients to forget the advertisement, as the HTTP/3 endpoint is going away.
+            Some(_) if self.shutdown.is_requested() => response.header(ALT_SVC, "clear"),
+            Some(v) => response.header(ALT_SVC, v.clone()),
+            None => response,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http::Response;
+
+    use super::*;
+
+    fn alt_svc(adapter: &BodyAdapter, response: Builder) -> Option<HeaderValue> {
+        let response = adapter.response_header(response).body(()).unwrap();
+        response.headers().get(ALT_SVC).cloned()
+    }
+
+    #[test]
+    fn advertises_unless_suppressed_or_set_by_the_service() {
+        let (shutdown, _handle) = Shutdown::new();
+        let adapter = BodyAdapter::new(Some(HeaderValue::from_static("h3=\":443\"")), shutdown);
+
+        assert_eq!(
+            alt_svc(&adapter, Response::builder()).unwrap(),
+            "h3=\":443\""
+        );
+        assert!(alt_svc(&adapter, Response::builder().extension(SuppressAltSvc)).is_none());
+        assert_eq!(
+            alt_svc(&adapter, Response::builder().header(ALT_SVC, "clear")).unwrap(),
+            "clear"
+        );
+    }
+
+    #[test]
+    fn clears_the_advertisement_on_shutdown() {
+        let (shutdown, handle) = Shutdown::new();
+        let adapter = BodyAdapter::new(Some(HeaderValue::from_static("h3=\":443\"")), shutdown);
+
+        handle.shutdown(Duration::from_secs(1));
+        assert_eq!(alt_svc(&adapter, Response::builder()).unwrap(), "clear");
     }
 }
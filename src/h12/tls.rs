@@ -1,32 +1,235 @@
-use std::io;
+use std::io::{self, Read};
 use std::net::SocketAddr;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
+use std::time::Duration;
 
+use bytes::Bytes;
 use futures::{ready, Future};
 use hyper::server::accept::Accept;
 use hyper::server::conn::{AddrIncoming, AddrStream};
 use rustls::ServerConfig;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::{sleep, Instant, Sleep};
+use tracing::warn;
+
+use crate::acme::ACME_TLS_ALPN;
+use crate::metadata::{ClientCertificate, ConnectionInfo};
+use crate::options::Timeouts;
 
 enum State {
     Handshaking(tokio_rustls::Accept<AddrStream>),
     Streaming(tokio_rustls::server::TlsStream<AddrStream>),
 }
 
+/// Requests in flight on a connection, including the ones sending their responses and tunnels
+/// upgraded from them. The connection is idle only while there are none.
+#[derive(Clone)]
+pub(crate) struct Activity {
+    state: Arc<Mutex<ActivityState>>,
+}
+
+struct ActivityState {
+    requests: usize,
+    idle_since: Instant,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ActivityState {
+                requests: 0,
+                idle_since: Instant::now(),
+            })),
+        }
+    }
+
+    /// Marks a request in flight until the guard is dropped.
+    pub(crate) fn begin(&self) -> Active {
+        self.state.lock().unwrap().requests += 1;
+
+        Active {
+            activity: self.clone(),
+        }
+    }
+
+    /// Returns the instant the connection became idle at, or `None` while it is busy.
+    fn idle_since(&self) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        match state.requests {
+            0 => Some(state.idle_since),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) struct Active {
+    activity: Activity,
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        let mut state = self.activity.state.lock().unwrap();
+        state.requests -= 1;
+        state.idle_since = Instant::now();
+    }
+}
+
 // tokio_rustls::server::TlsStream doesn't expose constructor methods,
 // so we have to TlsAcceptor::accept and handshake to have access to it
 // TlsStream implements AsyncRead/AsyncWrite handshaking tokio_rustls::Accept first
 pub struct TlsStream {
     state: State,
+    idle_timeout: Option<Duration>,
+    // Deadline of the handshake first, then of the idle timeout after the handshake.
+    timer: Option<Pin<Box<Sleep>>>,
+    activity: Activity,
+    _permit: Option<OwnedSemaphorePermit>,
+    // Early data received during the handshake, to be read before the rest of the stream.
+    early: Option<Bytes>,
+    // Set while every byte read so far arrived before the handshake completed.
+    early_data: Arc<AtomicBool>,
+    // Filled with the TLS parameters once the handshake completes.
+    info: Arc<Mutex<ConnectionInfo>>,
+    // Set on TLS-ALPN-01 validation handshakes, which carry no requests.
+    challenge: bool,
 }
 
 impl TlsStream {
-    fn new(stream: AddrStream, config: Arc<ServerConfig>) -> TlsStream {
+    fn new(
+        stream: AddrStream,
+        config: Arc<ServerConfig>,
+        timeouts: &Timeouts,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> TlsStream {
+        let info = ConnectionInfo::new(Some(stream.remote_addr()), http::Version::HTTP_11);
         let accept = tokio_rustls::TlsAcceptor::from(config).accept(stream);
         TlsStream {
             state: State::Handshaking(accept),
+            // Zero disables the idle timeout, as in QUIC.
+            idle_timeout: timeouts.idle.filter(|d| !d.is_zero()),
+            timer: timeouts.handshake.map(|d| Box::pin(sleep(d))),
+            activity: Activity::new(),
+            _permit: permit,
+            early: None,
+            early_data: Arc::new(AtomicBool::new(false)),
+            info: Arc::new(Mutex::new(info)),
+            challenge: false,
+        }
+    }
+
+    /// Returns the information of the connection, complete once the handshake has completed.
+    pub fn info(&self) -> Arc<Mutex<ConnectionInfo>> {
+        Arc::clone(&self.info)
+    }
+
+    /// Returns the requests in flight, which suspend the idle timeout.
+    pub fn activity(&self) -> Activity {
+        self.activity.clone()
+    }
+
+    /// Returns a flag set while the requests read so far have arrived as TLS 1.3 early data, which
+    /// is cleared once the connection reads data sent after the handshake.
+    pub fn early_data(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.early_data)
+    }
+
+    fn complete_handshake(&mut self, mut stream: tokio_rustls::server::TlsStream<AddrStream>) {
+        let mut early = Vec::new();
+        if let Some(mut reader) = stream.get_mut().1.early_data() {
+            let _ = reader.read_to_end(&mut early);
+        }
+
+        if !early.is_empty() {
+            self.early = Some(Bytes::from(early));
+            self.early_data.store(true, Ordering::Release);
+        }
+
+        {
+            let session = stream.get_ref().1;
+            let mut info = self.info.lock().unwrap();
+
+            info.alpn = session.alpn_protocol().map(<[u8]>::to_vec);
+            info.tls_version = session.protocol_version();
+            info.cipher_suite = session.negotiated_cipher_suite().map(|s| s.suite());
+            info.server_name = session.sni_hostname().map(str::to_owned);
+            info.client_certificate = session.peer_certificates().and_then(ClientCertificate::new);
+
+            self.challenge = session.alpn_protocol() == Some(ACME_TLS_ALPN);
+        }
+
+        self.state = State::Streaming(stream);
+        self.touch();
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let State::Handshaking(ref mut accept) = self.state {
+            match Pin::new(accept).poll(cx) {
+                Poll::Ready(Ok(stream)) => self.complete_handshake(stream),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return self.poll_timer(cx, Poll::Pending),
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+
+    fn touch(&mut self) {
+        self.timer = match (self.idle_timeout, self.timer.take()) {
+            (Some(d), Some(mut timer)) => {
+                timer.as_mut().reset(Instant::now() + d);
+                Some(timer)
+            }
+            (Some(d), None) => Some(Box::pin(sleep(d))),
+            (None, _) => None,
+        };
+    }
+
+    fn poll_timer<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        if let Poll::Ready(r) = poll {
+            self.touch();
+            return Poll::Ready(r);
+        }
+
+        loop {
+            let timer = match self.timer.as_mut() {
+                Some(t) => t,
+                None => return Poll::Pending,
+            };
+
+            ready!(timer.as_mut().poll(cx));
+
+            let idle_timeout = match (&self.state, self.idle_timeout) {
+                (State::Streaming(_), Some(d)) => d,
+                _ => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "TLS handshake timed out",
+                    )))
+                }
+            };
+
+            // Only a connection without requests in flight can be idle, however long a
+            // response or a tunnel goes without reads or writes.
+            let deadline = match self.activity.idle_since() {
+                None => Instant::now() + idle_timeout,
+                Some(since) if since + idle_timeout > Instant::now() => since + idle_timeout,
+                Some(_) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "connection idle timed out",
+                    )))
+                }
+            };
+
+            timer.as_mut().reset(deadline);
         }
     }
 
@@ -45,17 +248,34 @@ impl AsyncRead for TlsStream {
         buf: &mut ReadBuf,
     ) -> Poll<io::Result<()>> {
         let pin = self.get_mut();
-        match pin.state {
-            State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
-                Ok(mut stream) => {
-                    let result = Pin::new(&mut stream).poll_read(cx, buf);
-                    pin.state = State::Streaming(stream);
-                    result
-                }
-                Err(err) => Poll::Ready(Err(err)),
-            },
+        ready!(pin.poll_handshake(cx))?;
+
+        // Ends validation connections right after the handshake, as the validator does.
+        if pin.challenge {
+            return Poll::Ready(Ok(()));
+        }
+
+        if let Some(early) = pin.early.as_mut() {
+            let len = early.len().min(buf.remaining());
+            buf.put_slice(&early.split_to(len));
+            if early.is_empty() {
+                pin.early = None;
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        let filled = buf.filled().len();
+        let poll = match pin.state {
             State::Streaming(ref mut stream) => Pin::new(stream).poll_read(cx, buf),
+            State::Handshaking(_) => unreachable!(),
+        };
+
+        if buf.filled().len() > filled {
+            pin.early_data.store(false, Ordering::Release);
         }
+
+        pin.poll_timer(cx, poll)
     }
 }
 
@@ -66,42 +286,57 @@ impl AsyncWrite for TlsStream {
         buf: &[u8],
     ) -> Poll<io::Result<usize>> {
         let pin = self.get_mut();
-        match pin.state {
-            State::Handshaking(ref mut accept) => match ready!(Pin::new(accept).poll(cx)) {
-                Ok(mut stream) => {
-                    let result = Pin::new(&mut stream).poll_write(cx, buf);
-                    pin.state = State::Streaming(stream);
-                    result
-                }
-                Err(err) => Poll::Ready(Err(err)),
-            },
+        ready!(pin.poll_handshake(cx))?;
+
+        let poll = match pin.state {
             State::Streaming(ref mut stream) => Pin::new(stream).poll_write(cx, buf),
-        }
+            State::Handshaking(_) => unreachable!(),
+        };
+
+        pin.poll_timer(cx, poll)
     }
 
-    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        match self.state {
-            State::Handshaking(_) => Poll::Ready(Ok(())),
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let pin = self.get_mut();
+        let poll = match pin.state {
+            State::Handshaking(_) => return Poll::Ready(Ok(())),
             State::Streaming(ref mut stream) => Pin::new(stream).poll_flush(cx),
-        }
+        };
+
+        pin.poll_timer(cx, poll)
     }
 
-    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        match self.state {
-            State::Handshaking(_) => Poll::Ready(Ok(())),
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let pin = self.get_mut();
+        let poll = match pin.state {
+            State::Handshaking(_) => return Poll::Ready(Ok(())),
             State::Streaming(ref mut stream) => Pin::new(stream).poll_shutdown(cx),
-        }
+        };
+
+        pin.poll_timer(cx, poll)
     }
 }
 
 pub struct TlsAcceptor {
     config: Arc<ServerConfig>,
     incoming: AddrIncoming,
+    timeouts: Timeouts,
+    connections: Option<Arc<Semaphore>>,
 }
 
 impl TlsAcceptor {
-    pub fn new(config: Arc<ServerConfig>, incoming: AddrIncoming) -> TlsAcceptor {
-        TlsAcceptor { config, incoming }
+    pub fn new(
+        config: Arc<ServerConfig>,
+        incoming: AddrIncoming,
+        timeouts: Timeouts,
+        connections: Option<Arc<Semaphore>>,
+    ) -> TlsAcceptor {
+        TlsAcceptor {
+            config,
+            incoming,
+            timeouts,
+            connections,
+        }
     }
 }
 
@@ -114,10 +349,56 @@ impl Accept for TlsAcceptor {
         cx: &mut Context<'_>,
     ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
         let pin = self.get_mut();
-        match ready!(Pin::new(&mut pin.incoming).poll_accept(cx)) {
-            Some(Ok(sock)) => Poll::Ready(Some(Ok(TlsStream::new(sock, pin.config.clone())))),
-            Some(Err(e)) => Poll::Ready(Some(Err(e))),
-            None => Poll::Ready(None),
+        loop {
+            let sock = match ready!(Pin::new(&mut pin.incoming).poll_accept(cx)) {
+                Some(Ok(sock)) => sock,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => return Poll::Ready(None),
+            };
+
+            let permit = match &pin.connections {
+                Some(s) => match Arc::clone(s).try_acquire_owned() {
+                    Ok(p) => Some(p),
+                    Err(_) => {
+                        warn!(
+                            "Refusing connection from {}: too many connections.",
+                            sock.remote_addr()
+                        );
+
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            return Poll::Ready(Some(Ok(TlsStream::new(
+                sock,
+                pin.config.clone(),
+                &pin.timeouts,
+                permit,
+            ))));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_while_requests_are_in_flight() {
+        let activity = Activity::new();
+        assert!(activity.idle_since().is_some());
+
+        let first = activity.begin();
+        let second = activity.begin();
+        assert!(activity.idle_since().is_none());
+
+        drop(first);
+        assert!(activity.idle_since().is_none());
+
+        let before = Instant::now();
+        drop(second);
+        assert!(activity.idle_since().unwrap() >= before);
+    }
+}
@@ -1,18 +1,32 @@
 use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use bytes::{Buf, Bytes};
+use bytes::{Buf, Bytes, BytesMut};
 use h3::server::RequestStream;
 use h3_quinn::BidiStream;
 use http::header::CONTENT_LENGTH;
-use http::{HeaderMap, Request, Response};
+use http::{HeaderMap, Method, Request, Response, Version};
 use hyper::service::Service;
 use quinn::Connecting;
-use tracing::info;
+use rustls::ProtocolVersion;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::interval;
+use tracing::{error, info, warn};
 
 use crate::convert::HttpAdapter;
+use crate::h3::crypto::Handshake;
+use crate::h3::qlog::{headers_of, Qlog};
+use crate::h3::scheduler::Scheduler;
 use crate::h3::BodyAdapter;
+use crate::metadata::{ClientCertificate, ConnectionInfo, EarlyData, PreloadLinks, Priority};
+use crate::proxy::forward::Forwarder;
 use crate::service::call_service;
+use crate::shutdown::Shutdown;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -38,54 +52,240 @@ pub enum Error {
     Service(Box<dyn std::error::Error + Send>),
 }
 
+/// Size of chunks to send response bodies in, so that more urgent ones can cut in between.
+const CHUNK_SIZE: usize = 16 * 1024;
+
+/// Interval to record the statistics of the connection in the qlog at.
+const QLOG_STATS_INTERVAL: Duration = Duration::from_millis(250);
+
 pub struct Connection {
     inner: h3::server::Connection<h3_quinn::Connection, Bytes>,
+    quic: quinn::Connection,
+    established: Arc<AtomicBool>,
+    info: ConnectionInfo,
+    qlog: Option<Arc<Qlog>>,
 }
 
 impl Connection {
-    pub async fn new(connecting: Connecting) -> Result<Self, Error> {
-        let connection = connecting.await?;
-        let id = connection.connection.stable_id();
+    pub async fn new(
+        connecting: Connecting,
+        early_data: bool,
+        qlog_dir: Option<&Path>,
+    ) -> Result<Self, Error> {
+        let established = Arc::new(AtomicBool::new(true));
+        let connection = match early_data {
+            true => match connecting.into_0rtt() {
+                Ok((connection, accepted)) => {
+                    established.store(false, Ordering::Release);
+                    tokio::spawn({
+                        let established = Arc::clone(&established);
+                        async move {
+                            accepted.await;
+                            established.store(true, Ordering::Release);
+                        }
+                    });
+
+                    connection
+                }
+                Err(connecting) => connecting.await?,
+            },
+            false => connecting.await?,
+        };
+
+        let quic = connection.connection.clone();
+        let id = quic.stable_id();
+        let info = Self::info_of(&quic);
+        let qlog = match qlog_dir {
+            Some(d) => match Qlog::create(d, Self::group_id_of(&quic), quic.remote_address()).await
+            {
+                Ok(q) => Some(Arc::new(q)),
+                Err(e) => {
+                    warn!("Failed to create a qlog file: {}", e);
+
+                    None
+                }
+            },
+            None => None,
+        };
+
         let inner = h3::server::Connection::new(h3_quinn::Connection::new(connection)).await?;
 
         info!("HTTP/3 connection initiated from connection ID {}", id);
 
-        Ok(Self { inner })
+        Ok(Self {
+            inner,
+            quic,
+            established,
+            info,
+            qlog,
+        })
     }
 
-    pub async fn begin<S, E>(mut self, service: &Arc<S>) -> Result<(), Error>
+    fn handshake_of(connection: &quinn::Connection) -> Option<Box<Handshake>> {
+        connection
+            .handshake_data()
+            .and_then(|d| d.downcast::<Handshake>().ok())
+    }
+
+    /// Returns the original destination connection ID in hex, which groups the traces of a
+    /// connection in qlog, or the stable ID if it is unknown.
+    fn group_id_of(connection: &quinn::Connection) -> String {
+        match Self::handshake_of(connection).and_then(|h| h.original_dst_cid) {
+            Some(cid) => cid.iter().map(|b| format!("{:02x}", b)).collect(),
+            None => connection.stable_id().to_string(),
+        }
+    }
+
+    fn info_of(connection: &quinn::Connection) -> ConnectionInfo {
+        let mut info = ConnectionInfo::new(Some(connection.remote_address()), Version::HTTP_3);
+        if let Some(handshake) = Self::handshake_of(connection) {
+            info.alpn = handshake.data.protocol;
+            info.server_name = handshake.data.server_name;
+        }
+
+        // QUIC always runs on TLS 1.3.
+        info.tls_version = Some(ProtocolVersion::TLSv1_3);
+        info.quic_connection = Some(connection.stable_id());
+        info
+    }
+
+    /// Fills in the client certificate if not known yet. Under 0-RTT, the connection is accepted
+    /// before the handshake completes, when only the certificate of a resumed session is known.
+    fn identify_client(info: &mut ConnectionInfo, connection: &quinn::Connection) {
+        if info.client_certificate.is_none() {
+            info.client_certificate = connection
+                .peer_identity()
+                .and_then(|i| i.downcast::<Vec<rustls::Certificate>>().ok())
+                .and_then(|c| ClientCertificate::new(&c));
+        }
+    }
+
+    pub async fn begin<S, E>(
+        mut self,
+        service: &Arc<S>,
+        forwarder: Option<Arc<Forwarder>>,
+        shutdown: Shutdown,
+    ) -> Result<(), Error>
     where
         S: Service<Request<Bytes>, Response = Response<Bytes>, Error = E>,
         S: Send + Sync + Clone + 'static,
         S::Future: Send,
         E: std::error::Error + Send + 'static,
     {
-        loop {
-            let (request, stream) = match self.inner.accept().await? {
-                Some(v) => v,
-                None => return Ok(()),
+        let result = self.serve(service, forwarder, shutdown).await;
+
+        if let Some(qlog) = &self.qlog {
+            let reason = match &result {
+                Ok(_) => "closed".to_owned(),
+                Err(e) => e.to_string(),
             };
 
-            info!(
-                "Incoming request accepted: {} {}",
-                request.method(),
-                request.uri()
-            );
+            qlog.closed(&self.quic, &reason);
+        }
+
+        result
+    }
+
+    async fn serve<S, E>(
+        &mut self,
+        service: &Arc<S>,
+        forwarder: Option<Arc<Forwarder>>,
+        mut shutdown: Shutdown,
+    ) -> Result<(), Error>
+    where
+        S: Service<Request<Bytes>, Response = Response<Bytes>, Error = E>,
+        S: Send + Sync + Clone + 'static,
+        S::Future: Send,
+        E: std::error::Error + Send + 'static,
+    {
+        // Requests are aborted with the connection, along with the tunnels they serve.
+        let mut requests = JoinSet::new();
+        let scheduler = Arc::new(Scheduler::new());
+        let mut going_away = false;
+        let mut qlog_stats = interval(QLOG_STATS_INTERVAL);
+
+        loop {
+            tokio::select! {
+                accepted = self.inner.accept() => {
+                    let (request, stream) = match accepted? {
+                        Some(v) => v,
+                        None => break,
+                    };
+
+                    info!(
+                        "Incoming request accepted: {} {}",
+                        request.method(),
+                        request.uri()
+                    );
+
+                    // Requests accepted before the handshake completes may have been sent in 0-RTT.
+                    let early = !self.established.load(Ordering::Acquire);
+                    if !early {
+                        Self::identify_client(&mut self.info, &self.quic);
+                    }
+
+                    let service = Arc::clone(service);
+                    let scheduler = Arc::clone(&scheduler);
+                    let info = self.info.clone();
+                    let qlog = self.qlog.clone();
+                    let forwarder = forwarder.clone();
+                    requests.spawn(async move {
+                        let result = match &forwarder {
+                            Some(f) if request.method() == Method::CONNECT => {
+                                Self::tunnel(request, stream, early, f).await
+                            }
+                            _ => Self::handle(request, stream, early, info, service, &scheduler, qlog).await,
+                        };
+
+                        if let Err(e) = result {
+                            error!("{}", e);
+                        }
+                    });
+                },
+                Some(_) = requests.join_next(), if !requests.is_empty() => {},
+                _ = shutdown.requested(), if !going_away => {
+                    going_away = true;
 
-            tokio::spawn(Self::handle(request, stream, Arc::clone(service)));
+                    // Sends GOAWAY so that the client stops opening new requests on this connection.
+                    self.inner.shutdown(0).await?;
+                },
+                _ = qlog_stats.tick(), if self.qlog.is_some() => {
+                    if let Some(qlog) = &self.qlog {
+                        qlog.stats(&self.quic);
+                    }
+                },
+            }
         }
+
+        while requests.join_next().await.is_some() {}
+
+        Ok(())
     }
 
     async fn handle<S, E>(
         request: Request<()>,
         mut stream: RequestStream<BidiStream<Bytes>, Bytes>,
+        early: bool,
+        info: ConnectionInfo,
         service: Arc<S>,
+        scheduler: &Scheduler,
+        qlog: Option<Arc<Qlog>>,
     ) -> Result<(), Error>
     where
         S: Service<Request<Bytes>, Response = Response<Bytes>, Error = E> + Send + Sync + Clone,
         S::Future: Send,
         E: std::error::Error + Send + 'static,
     {
+        if let Some(qlog) = &qlog {
+            qlog.headers_parsed(headers_of(
+                &[
+                    (":method", request.method().to_string()),
+                    (":path", request.uri().to_string()),
+                ],
+                request.headers(),
+            ));
+        }
+
         let content_length = request
             .headers()
             .get(CONTENT_LENGTH)
@@ -96,23 +296,242 @@ impl Connection {
             .unwrap_or(0);
 
         let mut buffer = Vec::with_capacity(content_length);
-        if let Some(data) = stream.recv_data().await? {
+        while let Some(data) = stream.recv_data().await? {
             data.reader().read_to_end(&mut buffer)?;
         }
 
+        if early && !EarlyData::allows(request.method()) {
+            stream
+                .send_response(
+                    Response::builder()
+                        .status(EarlyData::too_early())
+                        .body(())?,
+                )
+                .await?;
+            stream.finish().await?;
+
+            return Ok(());
+        }
+
         let adapter = BodyAdapter::new(Bytes::from(buffer));
-        let response = call_service(&service, adapter.u_to_v(request).await?)
+        let mut request = adapter.u_to_v(request).await?;
+        request.extensions_mut().insert(info);
+        if early {
+            request.extensions_mut().insert(EarlyData);
+        }
+
+        let preload = PreloadLinks::default();
+        request.extensions_mut().insert(preload.clone());
+
+        let priority = Priority::default().merge(request.headers());
+        request.extensions_mut().insert(priority);
+
+        let mut response = call_service(&service, request)
             .await
             .map_err(|e| Error::Service(Box::new(e)))?;
 
-        stream
-            .send_response(adapter.v_to_u(response).await?)
-            .await?;
+        preload.apply(response.headers_mut());
 
-        stream.send_data(adapter.into_inner()?).await?;
+        let priority = priority.merge(response.headers());
+
+        let response = adapter.v_to_u(response).await?;
+        if let Some(qlog) = &qlog {
+            qlog.headers_created(headers_of(
+                &[(":status", response.status().as_u16().to_string())],
+                response.headers(),
+            ));
+        }
+
+        stream.send_response(response).await?;
+
+        let mut body = adapter.into_inner()?;
+        if let Some(qlog) = &qlog {
+            qlog.data_created(body.len());
+        }
+        let mut slot = scheduler.schedule(priority).await;
+        while !body.is_empty() {
+            slot.turn().await;
+            slot.send(stream.send_data(body.split_to(body.len().min(CHUNK_SIZE))))
+                .await?;
+        }
+
+        drop(slot);
         stream.send_trailers(HeaderMap::new()).await?;
         stream.finish().await?;
 
         Ok(())
     }
+
+    /// Serves a `CONNECT` request of the forward proxy, streaming between the request stream and
+    /// the target in both directions.
+    async fn tunnel(
+        request: Request<()>,
+        mut stream: RequestStream<BidiStream<Bytes>, Bytes>,
+        early: bool,
+        forwarder: &Forwarder,
+    ) -> Result<(), Error> {
+        let refusal = match early {
+            true => Response::builder()
+                .status(EarlyData::too_early())
+                .body(())?,
+            false => match forwarder.connect(&request).await {
+                Ok((upstream, target)) => {
+                    stream.send_response(Response::new(())).await?;
+                    info!("Tunnelling to {}", target);
+
+                    return Self::copy_bidirectional(&mut stream, upstream).await;
+                }
+                Err(response) => response,
+            },
+        };
+
+        stream.send_response(refusal).await?;
+        stream.finish().await?;
+
+        Ok(())
+    }
+
+    async fn copy_bidirectional(
+        stream: &mut RequestStream<BidiStream<Bytes>, Bytes>,
+        mut upstream: TcpStream,
+    ) -> Result<(), Error> {
+        let mut buffer = BytesMut::new();
+        let mut receiving = true;
+
+        loop {
+            buffer.reserve(CHUNK_SIZE);
+
+            tokio::select! {
+                data = stream.recv_data(), if receiving => match data? {
+                    Some(mut data) => upstream.write_all_buf(&mut data).await?,
+                    None => {
+                        // The client has finished sending, so the target gets a FIN as well.
+                        upstream.shutdown().await?;
+                        receiving = false;
+                    }
+                },
+                read = upstream.read_buf(&mut buffer) => {
+                    if read? == 0 {
+                        break;
+                    }
+
+                    stream.send_data(buffer.split().freeze()).await?;
+                }
+            }
+        }
+
+        stream.finish().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use futures::StreamExt;
+    use ring::hkdf;
+    use rustls::client::{ServerCertVerified, ServerCertVerifier};
+    use rustls::server::{ClientCertVerified, ClientCertVerifier};
+    use rustls::{Certificate, DistinguishedNames, ServerName};
+
+    use super::*;
+    use crate::h3::crypto::ServerCrypto;
+    use crate::tls::{self, CertResolver};
+
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    impl ClientCertVerifier for AnyCertificate {
+        fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+            Some(Vec::new())
+        }
+
+        fn verify_client_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _now: SystemTime,
+        ) -> Result<ClientCertVerified, rustls::Error> {
+            Ok(ClientCertVerified::assertion())
+        }
+    }
+
+    #[tokio::test]
+    async fn identifies_clients_in_0rtt_and_once_the_handshake_completes() {
+        let keys = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys");
+        let resolver = CertResolver::load(keys.join("ec.crt"), keys.join("ec.pem")).unwrap();
+        let chain = resolver.certificate_chain();
+
+        let mut server = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(Arc::new(AnyCertificate))
+            .with_cert_resolver(Arc::new(resolver));
+        server.alpn_protocols = vec![b"h3".to_vec()];
+        server.max_early_data_size = u32::MAX;
+        let server = quinn::ServerConfig::new(
+            Arc::new(ServerCrypto::new(server)),
+            Arc::new(hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(&[0; 64])),
+        );
+        let (endpoint, mut incoming) =
+            quinn::Endpoint::server(server, ([127, 0, 0, 1], 0).into()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        let key = tls::load_private_key(&keys.join("ec.pem"), chain.first()).unwrap();
+        let mut client = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+            .with_single_cert(chain, key)
+            .unwrap();
+        client.alpn_protocols = vec![b"h3".to_vec()];
+        client.enable_early_data = true;
+        let mut client_endpoint = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+        client_endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(client)));
+
+        // The first connection obtains a session ticket allowing 0-RTT.
+        let (first, _first) = tokio::join!(
+            async { incoming.next().await.unwrap().await.unwrap() },
+            async {
+                client_endpoint
+                    .connect(addr, "localhost")
+                    .unwrap()
+                    .await
+                    .unwrap()
+            },
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        first.connection.close(0u32.into(), b"");
+
+        let connecting = client_endpoint.connect(addr, "localhost").unwrap();
+        let (client, _) = connecting.into_0rtt().ok().unwrap();
+        let (connection, accepted) = incoming.next().await.unwrap().into_0rtt().ok().unwrap();
+
+        // rustls restores the certificate of the resumed session before the handshake completes.
+        let mut info = Connection::info_of(&connection.connection);
+        Connection::identify_client(&mut info, &connection.connection);
+        let resumed = info.client_certificate.take().unwrap();
+        assert_eq!(resumed.subject.as_deref(), Some("CN=localhost"));
+
+        accepted.await;
+        Connection::identify_client(&mut info, &connection.connection);
+        let certificate = info.client_certificate.unwrap();
+        assert_eq!(certificate.chain, resumed.chain);
+
+        drop(client);
+    }
 }
@@ -0,0 +1,231 @@
+use std::any::Any;
+use std::sync::Arc;
+
+use quinn::crypto::rustls::HandshakeData;
+use quinn::crypto::{
+    self, ExportKeyingMaterialError, HeaderKey, KeyPair, Keys, PacketKey, UnsupportedVersion,
+};
+use quinn::VarInt;
+use quinn_proto::coding::Codec;
+use quinn_proto::transport_parameters::TransportParameters;
+use quinn_proto::{ConnectionId, Side, TransportError};
+
+/// ID of the `original_destination_connection_id` transport parameter (RFC 9000, section 18.2).
+const ORIGINAL_DESTINATION_CONNECTION_ID: u64 = 0x00;
+
+/// Data of a handshake, with the destination connection ID of the first Initial packet of the
+/// client, which quinn does not expose otherwise.
+pub(crate) struct Handshake {
+    pub(crate) data: HandshakeData,
+    pub(crate) original_dst_cid: Option<Vec<u8>>,
+}
+
+/// TLS configuration of QUIC connections, whose handshake data is a [`Handshake`].
+pub(crate) struct ServerCrypto {
+    inner: Arc<rustls::ServerConfig>,
+}
+
+impl ServerCrypto {
+    pub(crate) fn new(config: rustls::ServerConfig) -> Self {
+        Self {
+            inner: Arc::new(config),
+        }
+    }
+}
+
+impl crypto::ServerConfig for ServerCrypto {
+    fn initial_keys(
+        &self,
+        version: u32,
+        dst_cid: &ConnectionId,
+        side: Side,
+    ) -> Result<Keys, UnsupportedVersion> {
+        crypto::ServerConfig::initial_keys(&*self.inner, version, dst_cid, side)
+    }
+
+    fn retry_tag(&self, version: u32, orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; 16] {
+        crypto::ServerConfig::retry_tag(&*self.inner, version, orig_dst_cid, packet)
+    }
+
+    fn start_session(
+        self: Arc<Self>,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Box<dyn crypto::Session> {
+        Box::new(Session {
+            inner: Arc::clone(&self.inner).start_session(version, params),
+            original_dst_cid: original_dst_cid(params),
+        })
+    }
+}
+
+/// Reads the original destination connection ID back from the encoded transport parameters of the
+/// server, as quinn keeps the field private.
+fn original_dst_cid(params: &TransportParameters) -> Option<Vec<u8>> {
+    let mut encoded = Vec::new();
+    params.write(&mut encoded);
+
+    let mut remaining = &encoded[..];
+    while !remaining.is_empty() {
+        let id = VarInt::decode(&mut remaining).ok()?.into_inner();
+        let len = VarInt::decode(&mut remaining).ok()?.into_inner() as usize;
+        if remaining.len() < len {
+            return None;
+        }
+
+        let (value, rest) = remaining.split_at(len);
+        if id == ORIGINAL_DESTINATION_CONNECTION_ID {
+            return Some(value.to_vec());
+        }
+
+        remaining = rest;
+    }
+
+    None
+}
+
+struct Session {
+    inner: Box<dyn crypto::Session>,
+    original_dst_cid: Option<Vec<u8>>,
+}
+
+impl crypto::Session for Session {
+    fn initial_keys(&self, dst_cid: &ConnectionId, side: Side) -> Keys {
+        self.inner.initial_keys(dst_cid, side)
+    }
+
+    fn handshake_data(&self) -> Option<Box<dyn Any>> {
+        let data = self
+            .inner
+            .handshake_data()?
+            .downcast::<HandshakeData>()
+            .ok()?;
+
+        Some(Box::new(Handshake {
+            data: *data,
+            original_dst_cid: self.original_dst_cid.clone(),
+        }))
+    }
+
+    fn peer_identity(&self) -> Option<Box<dyn Any>> {
+        self.inner.peer_identity()
+    }
+
+    fn early_crypto(&self) -> Option<(Box<dyn HeaderKey>, Box<dyn PacketKey>)> {
+        self.inner.early_crypto()
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        self.inner.early_data_accepted()
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.inner.is_handshaking()
+    }
+
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<bool, TransportError> {
+        self.inner.read_handshake(buf)
+    }
+
+    fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
+        self.inner.transport_parameters()
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Keys> {
+        self.inner.write_handshake(buf)
+    }
+
+    fn next_1rtt_keys(&mut self) -> Option<KeyPair<Box<dyn PacketKey>>> {
+        self.inner.next_1rtt_keys()
+    }
+
+    fn is_valid_retry(&self, orig_dst_cid: &ConnectionId, header: &[u8], payload: &[u8]) -> bool {
+        self.inner.is_valid_retry(orig_dst_cid, header, payload)
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        self.inner.export_keying_material(output, label, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::SystemTime;
+
+    use futures::StreamExt;
+    use ring::hkdf;
+    use rustls::client::{ServerCertVerified, ServerCertVerifier};
+    use rustls::{Certificate, ServerName};
+
+    use super::*;
+    use crate::tls::CertResolver;
+
+    struct AnyCertificate;
+
+    impl ServerCertVerifier for AnyCertificate {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &Certificate,
+            _intermediates: &[Certificate],
+            _server_name: &ServerName,
+            _scts: &mut dyn Iterator<Item = &[u8]>,
+            _ocsp_response: &[u8],
+            _now: SystemTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+    }
+
+    #[tokio::test]
+    async fn handshake_data_carries_the_original_destination_connection_id() {
+        let keys = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys");
+        let resolver = CertResolver::load(keys.join("ec.crt"), keys.join("ec.pem")).unwrap();
+        let mut server = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        server.alpn_protocols = vec![b"h3".to_vec()];
+        let server = quinn::ServerConfig::new(
+            Arc::new(ServerCrypto::new(server)),
+            Arc::new(hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(&[0; 64])),
+        );
+        let (endpoint, mut incoming) =
+            quinn::Endpoint::server(server, ([127, 0, 0, 1], 0).into()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+
+        let mut client = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+            .with_no_client_auth();
+        client.alpn_protocols = vec![b"h3".to_vec()];
+        let client_endpoint = quinn::Endpoint::client(([127, 0, 0, 1], 0).into()).unwrap();
+        let connecting = client_endpoint
+            .connect_with(
+                quinn::ClientConfig::new(Arc::new(client)),
+                addr,
+                "localhost",
+            )
+            .unwrap();
+
+        let (accepted, _) = tokio::join!(
+            async { incoming.next().await.unwrap().await.unwrap() },
+            async { connecting.await.unwrap() },
+        );
+
+        let handshake = accepted
+            .connection
+            .handshake_data()
+            .unwrap()
+            .downcast::<Handshake>()
+            .unwrap();
+        assert_eq!(handshake.data.protocol.as_deref(), Some(&b"h3"[..]));
+        let cid = handshake.original_dst_cid.unwrap();
+        assert!((8..=20).contains(&cid.len()), "{:?}", cid);
+    }
+}
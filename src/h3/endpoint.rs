@@ -1,5 +1,6 @@
 use std::marker::PhantomData;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use bytes::Bytes;
@@ -7,10 +8,21 @@ use futures::StreamExt;
 use h3::error::Code;
 use http::{Request, Response};
 use hyper::service::Service;
-use quinn::ServerConfig;
-use tracing::{error, info};
+use quinn::congestion::{BbrConfig, CubicConfig, NewRenoConfig};
+use quinn::{Connecting, EndpointConfig, IdleTimeout, ServerConfig, TransportConfig, VarInt};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::{hkdf, hmac};
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
 
 use crate::h3::connection::{Connection, Error as ConnectionError};
+use crate::h3::crypto::ServerCrypto;
+use crate::h3::retry::{HandshakeGuard, LoadMonitor};
+use crate::options::{CongestionController, EarlyDataPolicy, InvalidOptions, Options, RetryPolicy};
+use crate::proxy::forward::Forwarder;
+use crate::proxy::ForwardProxy;
+use crate::shutdown::Shutdown;
+use crate::socket;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -19,32 +31,140 @@ pub enum Error {
 
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
+
+    #[error("QUIC configuration error: {0}")]
+    Config(#[from] quinn::ConfigError),
+
+    #[error("Acceptor task failed: {0}")]
+    Join(#[from] tokio::task::JoinError),
 }
 
 pub struct Endpoint<S, E> {
+    endpoint_config: EndpointConfig,
+    max_udp_payload_size: Option<u16>,
     config: ServerConfig,
+    retry: RetryPolicy,
+    early_data: bool,
     bind_to: SocketAddr,
+    acceptors: usize,
+    qlog_dir: Option<PathBuf>,
     service: Arc<S>,
+    forwarder: Option<Arc<Forwarder>>,
+    shutdown: Shutdown,
     _phantom: PhantomData<fn() -> E>,
 }
 
 impl<S, E> Endpoint<S, E> {
-    pub fn new<A>(rustls_config: &rustls::ServerConfig, bind_to: A, service: Arc<S>) -> Self
+    pub fn new<A>(
+        rustls_config: &rustls::ServerConfig,
+        bind_to: A,
+        service: Arc<S>,
+        options: &Options,
+        shutdown: Shutdown,
+    ) -> Result<Self, InvalidOptions>
     where
         A: Into<SocketAddr>,
     {
         let mut rustls_config = rustls_config.clone();
 
-        rustls_config.max_early_data_size = u32::MAX;
+        let early_data = options.early_data == EarlyDataPolicy::SafeMethods;
+
+        // quinn accepts 0-RTT only if the maximum is exactly u32::MAX.
+        rustls_config.max_early_data_size = if early_data { u32::MAX } else { 0 };
         rustls_config.alpn_protocols = vec![b"h3".to_vec()];
 
-        Self {
-            config: ServerConfig::with_crypto(Arc::new(rustls_config)),
+        let validation = &options.address_validation;
+        // A random key, as quinn generates for ServerConfig::with_crypto.
+        let mut random_key = [0; 64];
+        let token_key = match &validation.token_key {
+            Some(key) => key.as_slice(),
+            None => {
+                SystemRandom::new()
+                    .fill(&mut random_key)
+                    .expect("the system random generator is available");
+                &random_key
+            }
+        };
+        let mut config = ServerConfig::new(
+            Arc::new(ServerCrypto::new(rustls_config)),
+            Arc::new(hkdf::Salt::new(hkdf::HKDF_SHA256, &[]).extract(token_key)),
+        );
+
+        config.transport = Arc::new(transport_config(options)?);
+        config.use_retry(validation.retry == RetryPolicy::Always);
+
+        if let Some(n) = options.limits.quic_connections {
+            config.concurrent_connections(n);
+        }
+
+        Ok(Self {
+            endpoint_config: match &validation.reset_key {
+                Some(key) => EndpointConfig::new(Arc::new(hmac::Key::new(hmac::HMAC_SHA256, key))),
+                None => EndpointConfig::default(),
+            },
+            max_udp_payload_size: options.transport.max_udp_payload_size,
+            config,
+            retry: validation.retry,
+            early_data,
             bind_to: bind_to.into(),
+            acceptors: options.acceptors,
+            qlog_dir: options.qlog_dir.clone(),
             service,
+            forwarder: None,
+            shutdown,
             _phantom: PhantomData,
+        })
+    }
+
+    pub fn forward_proxy(&mut self, proxy: ForwardProxy) {
+        self.forwarder = Some(Arc::new(Forwarder::new(proxy)));
+    }
+}
+
+fn transport_config(options: &Options) -> Result<TransportConfig, InvalidOptions> {
+    let mut transport = TransportConfig::default();
+    let tuning = &options.transport;
+
+    match tuning.congestion_controller {
+        CongestionController::NewReno => {
+            transport.congestion_controller_factory(Arc::new(NewRenoConfig::default()))
+        }
+        CongestionController::Cubic => {
+            transport.congestion_controller_factory(Arc::new(CubicConfig::default()))
+        }
+        CongestionController::Bbr => {
+            transport.congestion_controller_factory(Arc::new(BbrConfig::default()))
+        }
+    };
+
+    let idle_timeout = match options.timeouts.idle {
+        Some(d) if !d.is_zero() => {
+            Some(IdleTimeout::try_from(d).map_err(|_| InvalidOptions::IdleTimeout(d))?)
         }
+        _ => None,
+    };
+
+    transport
+        .max_idle_timeout(idle_timeout)
+        .keep_alive_interval(tuning.keep_alive_interval);
+
+    if let Some(n) = options.limits.h3_streams {
+        transport.max_concurrent_bidi_streams(VarInt::from_u32(n));
+    }
+
+    if let Some(n) = tuning.stream_receive_window {
+        transport.stream_receive_window(VarInt::from_u32(n));
+    }
+
+    if let Some(n) = tuning.receive_window {
+        transport.receive_window(VarInt::from_u32(n));
+    }
+
+    if let Some(d) = tuning.initial_rtt {
+        transport.initial_rtt(d);
     }
+
+    Ok(transport)
 }
 
 impl<S, E> Endpoint<S, E>
@@ -54,47 +174,177 @@ where
     S::Future: Send,
     E: std::error::Error + Send + 'static,
 {
-    pub async fn begin(self) -> Result<(), Error> {
-        let (endpoint, mut incoming) = quinn::Endpoint::server(self.config, self.bind_to)?;
+    pub async fn begin(mut self) -> Result<(), Error> {
+        if let Some(size) = self.max_udp_payload_size {
+            self.endpoint_config.max_udp_payload_size(size.into())?;
+        }
 
-        info!("HTTP/3 endpoint started at: {}", &self.bind_to);
+        let sockets = socket::bind_udp(self.bind_to, self.acceptors)?;
+        let this = Arc::new(self);
+        let mut acceptors = JoinSet::new();
+        for socket in sockets {
+            acceptors.spawn(Arc::clone(&this).serve(socket));
+        }
 
-        while let Some(connection) = incoming.next().await {
-            info!("Connecting from {}", connection.remote_address());
+        info!("HTTP/3 endpoint started at: {}", &this.bind_to);
 
-            let service = Arc::clone(&self.service);
-            tokio::spawn(async move {
-                let connection = match Connection::new(connection).await {
-                    Ok(c) => c,
-                    Err(e) => {
-                        error!("{}", e);
+        while let Some(r) = acceptors.join_next().await {
+            r??;
+        }
 
-                        return;
-                    }
-                };
-
-                match connection.begin(&service).await {
-                    Ok(c) => c,
-                    Err(e) => {
-                        if let ConnectionError::H3(ref e) = e {
-                            if e.try_get_code()
-                                .map(|c| c == Code::H3_NO_ERROR || c == 0x0)
-                                .unwrap_or(true)
-                            {
-                                info!("Connection closed with no error.");
-
-                                return;
-                            }
-                        }
-
-                        error!("{}", e);
+        Ok(())
+    }
+
+    async fn serve(self: Arc<Self>, socket: UdpSocket) -> Result<(), Error> {
+        let (endpoint, mut incoming) = quinn::Endpoint::new(
+            self.endpoint_config.clone(),
+            Some(self.config.clone()),
+            socket,
+        )?;
+        let load = match self.retry {
+            RetryPolicy::UnderLoad { handshakes } => Some(Arc::new(LoadMonitor::new(
+                endpoint.clone(),
+                &self.config,
+                handshakes,
+            ))),
+            _ => None,
+        };
+        let mut connections = JoinSet::new();
+        let mut shutdown = self.shutdown.clone();
+
+        loop {
+            tokio::select! {
+                connecting = incoming.next() => match connecting {
+                    Some(c) => {
+                        connections.spawn(Self::handle(
+                            c,
+                            load.as_ref().map(|l| l.handshake()),
+                            self.early_data,
+                            self.qlog_dir.clone(),
+                            Arc::clone(&self.service),
+                            self.forwarder.clone(),
+                            self.shutdown.clone(),
+                        ));
                     }
-                };
-            });
+                    None => break,
+                },
+                Some(_) = connections.join_next(), if !connections.is_empty() => {},
+                _ = shutdown.requested() => break,
+            }
+        }
+
+        // Refuse any connection attempts from now on, while in-flight ones are drained.
+        if let Some(load) = &load {
+            load.close();
+        }
+
+        endpoint.set_server_config(None);
+
+        tokio::select! {
+            _ = async { while connections.join_next().await.is_some() {} } => {},
+            _ = self.shutdown.clone().deadline() => {
+                warn!("Grace period elapsed, closing remaining HTTP/3 connections.");
+
+                // Requests still in flight are cut off after the GOAWAY; drained connections have
+                // closed already.
+                endpoint.close(
+                    VarInt::from_u64(Code::H3_NO_ERROR.value()).unwrap(),
+                    b"shutting down",
+                );
+                connections.shutdown().await;
+            }
         }
 
         endpoint.wait_idle().await;
 
         Ok(())
     }
+
+    async fn handle(
+        connecting: Connecting,
+        handshake: Option<HandshakeGuard>,
+        early_data: bool,
+        qlog_dir: Option<PathBuf>,
+        service: Arc<S>,
+        forwarder: Option<Arc<Forwarder>>,
+        shutdown: Shutdown,
+    ) {
+        info!("Connecting from {}", connecting.remote_address());
+
+        let connection = Connection::new(connecting, early_data, qlog_dir.as_deref()).await;
+
+        drop(handshake);
+
+        let connection = match connection {
+            Ok(c) => c,
+            Err(e) => {
+                error!("{}", e);
+
+                return;
+            }
+        };
+
+        match connection.begin(&service, forwarder, shutdown).await {
+            Ok(c) => c,
+            Err(e) => {
+                if let ConnectionError::H3(ref e) = e {
+                    if e.try_get_code()
+                        .map(|c| c == Code::H3_NO_ERROR || c == 0x0)
+                        .unwrap_or(true)
+                    {
+                        info!("Connection closed with no error.");
+
+                        return;
+                    }
+                }
+
+                error!("{}", e);
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn with_idle(idle: Option<Duration>) -> Options {
+        let mut options = Options::default();
+        options.timeouts.idle = idle;
+        options
+    }
+
+    #[test]
+    fn idle_timeout_out_of_range_is_an_error() {
+        let idle = Duration::from_secs(u64::MAX);
+
+        assert!(matches!(
+            transport_config(&with_idle(Some(idle))),
+            Err(InvalidOptions::IdleTimeout(d)) if d == idle
+        ));
+    }
+
+    /// Reads the idle timeout back, which `TransportConfig` only exposes through `Debug`.
+    fn idle_timeout_of(options: &Options) -> String {
+        let debug = format!("{:?}", transport_config(options).unwrap());
+        let field = &debug[debug.find("max_idle_timeout: ").unwrap()..];
+
+        field[..field.find(',').unwrap()].to_owned()
+    }
+
+    #[test]
+    fn zero_idle_timeout_disables_it() {
+        // quinn defaults to 10 seconds, which must not apply either.
+        assert_eq!(
+            idle_timeout_of(&with_idle(Some(Duration::ZERO))),
+            "max_idle_timeout: None"
+        );
+        assert_eq!(idle_timeout_of(&with_idle(None)), "max_idle_timeout: None");
+        assert_eq!(
+            idle_timeout_of(&with_idle(Some(Duration::from_secs(60)))),
+            "max_idle_timeout: Some(60000)"
+        );
+    }
 }
@@ -1,5 +1,9 @@
 mod connection;
+mod crypto;
 mod endpoint;
+mod qlog;
+mod retry;
+mod scheduler;
 
 use std::sync::Arc;
 
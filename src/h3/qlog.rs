@@ -0,0 +1,368 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use http::header::{HeaderName, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE};
+use http::HeaderMap;
+use serde_json::{json, Value};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::spawn_blocking;
+use tracing::warn;
+
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+/// Maximum number of records waiting to be written. Events are dropped past it, rather than
+/// buffered without bound while the disk is slower than the connection.
+const RECORD_QUEUE: usize = 4096;
+
+/// Headers whose values are replaced in traces, as they carry credentials.
+const REDACTED_HEADERS: [HeaderName; 4] = [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION, SET_COOKIE];
+const REDACTED: &str = "[redacted]";
+
+#[derive(Clone, Copy, Default)]
+struct Snapshot {
+    tx_datagrams: u64,
+    tx_bytes: u64,
+    rx_datagrams: u64,
+    rx_bytes: u64,
+    congestion_events: u64,
+    rtt: Duration,
+    cwnd: u64,
+}
+
+impl Snapshot {
+    fn of(connection: &quinn::Connection) -> Self {
+        let stats = connection.stats();
+
+        Self {
+            tx_datagrams: stats.udp_tx.datagrams,
+            tx_bytes: stats.udp_tx.bytes,
+            rx_datagrams: stats.udp_rx.datagrams,
+            rx_bytes: stats.udp_rx.bytes,
+            congestion_events: stats.path.congestion_events,
+            rtt: stats.path.rtt,
+            cwnd: stats.path.cwnd,
+        }
+    }
+}
+
+/// Writes a qlog trace of a QUIC connection in JSON-SEQ.
+///
+/// quinn 0.8 does not expose packets and frames, so the trace consists of HTTP/3 events and of
+/// events derived from the statistics of the connection. Records are written to the file on the
+/// blocking thread pool, so that a slow disk does not stall the connection.
+pub(crate) struct Qlog {
+    records: Sender<Vec<u8>>,
+    dropping: AtomicBool,
+    start: Instant,
+    last: Mutex<Snapshot>,
+}
+
+impl Qlog {
+    /// Creates the trace of a connection in `{reference time}-{group ID}.sqlog`.
+    pub(crate) async fn create(
+        dir: &Path,
+        group_id: String,
+        remote_addr: SocketAddr,
+    ) -> io::Result<Self> {
+        let reference_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let path = dir.join(format!("{}-{}.sqlog", reference_time, group_id));
+        let file = spawn_blocking(move || File::create(path))
+            .await
+            .map_err(io::Error::other)??;
+
+        let (records, receiver) = channel(RECORD_QUEUE);
+        tokio::spawn(write_records(BufWriter::new(file), receiver));
+
+        let qlog = Self {
+            records,
+            dropping: AtomicBool::new(false),
+            start: Instant::now(),
+            last: Mutex::new(Snapshot::default()),
+        };
+
+        qlog.write(&json!({
+            "qlog_version": "0.3",
+            "qlog_format": "JSON-SEQ",
+            "title": "h123",
+            "trace": {
+                "vantage_point": { "type": "server" },
+                "common_fields": {
+                    "group_id": group_id,
+                    "time_format": "relative",
+                    "reference_time": reference_time as u64,
+                },
+            },
+        }));
+
+        qlog.event(
+            "connectivity:connection_started",
+            json!({
+                "ip_version": if remote_addr.is_ipv4() { "ipv4" } else { "ipv6" },
+                "src_ip": remote_addr.ip().to_string(),
+                "src_port": remote_addr.port(),
+            }),
+        );
+
+        Ok(qlog)
+    }
+
+    pub(crate) fn headers_parsed(&self, headers: Value) {
+        self.event(
+            "http:frame_parsed",
+            json!({ "frame": { "frame_type": "headers", "headers": headers } }),
+        );
+    }
+
+    pub(crate) fn headers_created(&self, headers: Value) {
+        self.event(
+            "http:frame_created",
+            json!({ "frame": { "frame_type": "headers", "headers": headers } }),
+        );
+    }
+
+    pub(crate) fn data_created(&self, length: usize) {
+        self.event(
+            "http:frame_created",
+            json!({ "frame": { "frame_type": "data" }, "length": length }),
+        );
+    }
+
+    /// Records the changes in the statistics since the last call.
+    pub(crate) fn stats(&self, connection: &quinn::Connection) {
+        let stats = Snapshot::of(connection);
+        let last = std::mem::replace(&mut *self.last.lock().unwrap(), stats);
+
+        if stats.tx_datagrams > last.tx_datagrams {
+            self.event(
+                "transport:datagrams_sent",
+                json!({
+                    "count": stats.tx_datagrams - last.tx_datagrams,
+                    "raw": { "length": stats.tx_bytes - last.tx_bytes },
+                }),
+            );
+        }
+
+        if stats.rx_datagrams > last.rx_datagrams {
+            self.event(
+                "transport:datagrams_received",
+                json!({
+                    "count": stats.rx_datagrams - last.rx_datagrams,
+                    "raw": { "length": stats.rx_bytes - last.rx_bytes },
+                }),
+            );
+        }
+
+        if stats.congestion_events > last.congestion_events {
+            self.event(
+                "recovery:congestion_state_updated",
+                json!({ "new": "recovery", "trigger": "loss" }),
+            );
+        }
+
+        if stats.rtt != last.rtt || stats.cwnd != last.cwnd {
+            self.event(
+                "recovery:metrics_updated",
+                json!({
+                    "smoothed_rtt": stats.rtt.as_secs_f64() * 1000.0,
+                    "congestion_window": stats.cwnd,
+                }),
+            );
+        }
+    }
+
+    pub(crate) fn closed(&self, connection: &quinn::Connection, reason: &str) {
+        self.stats(connection);
+        self.event(
+            "connectivity:connection_closed",
+            json!({ "owner": "local", "reason": reason }),
+        );
+    }
+
+    fn event(&self, name: &str, data: Value) {
+        self.write(&json!({
+            "time": self.start.elapsed().as_secs_f64() * 1000.0,
+            "name": name,
+            "data": data,
+        }));
+    }
+
+    fn write(&self, record: &Value) {
+        let mut line = vec![RECORD_SEPARATOR];
+        serde_json::to_writer(&mut line, record).expect("JSON values always serialize");
+        line.push(b'\n');
+
+        match self.records.try_send(line) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    warn!("qlog records are queued faster than written, dropping events.");
+                }
+            }
+            // The writer has given up on the file, which it has warned about.
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+}
+
+/// Writes records to the file as they come, in batches of the ones queued in the meantime, until
+/// the [`Qlog`] is dropped.
+async fn write_records(mut writer: BufWriter<File>, mut records: Receiver<Vec<u8>>) {
+    while let Some(mut batch) = records.recv().await {
+        while let Ok(record) = records.try_recv() {
+            batch.extend(record);
+        }
+
+        let written = spawn_blocking(move || {
+            writer.write_all(&batch)?;
+            writer.flush()?;
+            Ok::<_, io::Error>(writer)
+        })
+        .await
+        .map_err(io::Error::other);
+
+        writer = match written {
+            Ok(Ok(w)) => w,
+            Ok(Err(e)) | Err(e) => {
+                warn!(
+                    "Failed to write qlog records, giving up on the trace: {}",
+                    e
+                );
+
+                return;
+            }
+        };
+    }
+}
+
+/// Converts headers into the qlog representation, with pseudo-headers first and credentials
+/// redacted.
+pub(crate) fn headers_of(pseudo: &[(&str, String)], headers: &HeaderMap) -> Value {
+    pseudo
+        .iter()
+        .map(|(n, v)| json!({ "name": n, "value": v }))
+        .chain(headers.iter().map(|(n, v)| {
+            let value = match REDACTED_HEADERS.contains(n) {
+                true => REDACTED.into(),
+                false => String::from_utf8_lossy(v.as_bytes()),
+            };
+
+            json!({ "name": n.as_str(), "value": value })
+        }))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn redacts_credentials() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer secret"));
+        headers.insert(COOKIE, HeaderValue::from_static("session=secret"));
+        headers.insert(
+            PROXY_AUTHORIZATION,
+            HeaderValue::from_static("Basic c2VjcmV0"),
+        );
+        headers.insert(SET_COOKIE, HeaderValue::from_static("session=secret"));
+        headers.insert("accept", HeaderValue::from_static("text/html"));
+
+        let value = headers_of(&[(":method", "GET".to_owned())], &headers);
+        let fields = value.as_array().unwrap();
+
+        assert_eq!(fields[0], json!({ "name": ":method", "value": "GET" }));
+        for field in &fields[1..] {
+            let expected = match field["name"].as_str().unwrap() {
+                "accept" => "text/html",
+                _ => REDACTED,
+            };
+            assert_eq!(field["value"], expected, "{}", field["name"]);
+        }
+        assert!(!value.to_string().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn writes_records_in_json_seq() {
+        let dir = std::env::temp_dir().join(format!("h123-qlog-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let qlog = Qlog::create(&dir, "0123abcd".to_owned(), ([127, 0, 0, 1], 4433).into())
+            .await
+            .unwrap();
+        qlog.data_created(42);
+        drop(qlog);
+
+        // The writer task finishes the file once the qlog is dropped.
+        let path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let mut records = Vec::new();
+        for _ in 0..100 {
+            records = fs::read(&path).unwrap();
+            if records.split(|b| *b == RECORD_SEPARATOR).count() == 4 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(path.to_str().unwrap().ends_with("-0123abcd.sqlog"));
+
+        let records = records
+            .split(|b| *b == RECORD_SEPARATOR)
+            .skip(1)
+            .map(|r| serde_json::from_slice::<Value>(r).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["qlog_format"], "JSON-SEQ");
+        assert_eq!(records[1]["name"], "connectivity:connection_started");
+        assert_eq!(records[2]["name"], "http:frame_created");
+        assert_eq!(records[2]["data"]["length"], 42);
+    }
+
+    #[tokio::test]
+    async fn drops_events_while_the_queue_is_full() {
+        let dir = std::env::temp_dir().join(format!("h123-qlog-full-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let qlog = Qlog::create(&dir, "full".to_owned(), ([127, 0, 0, 1], 4433).into())
+            .await
+            .unwrap();
+
+        // The writer cannot run on this thread until the test yields.
+        for length in 0..RECORD_QUEUE * 2 {
+            qlog.data_created(length);
+        }
+        assert!(qlog.dropping.load(Ordering::Relaxed));
+        drop(qlog);
+
+        let path = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let mut records = 0;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            records = fs::read(&path)
+                .unwrap()
+                .iter()
+                .filter(|b| **b == RECORD_SEPARATOR)
+                .count();
+            if records == RECORD_QUEUE {
+                break;
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(records, RECORD_QUEUE);
+    }
+}
@@ -0,0 +1,79 @@
+use std::sync::{Arc, Mutex};
+
+use quinn::ServerConfig;
+use tracing::info;
+
+#[derive(Default)]
+struct State {
+    handshakes: usize,
+    retrying: bool,
+    closed: bool,
+}
+
+/// Requires address validation with Retry packets while too many handshakes are in progress.
+pub(crate) struct LoadMonitor {
+    endpoint: quinn::Endpoint,
+    normal: ServerConfig,
+    retry: ServerConfig,
+    threshold: usize,
+    state: Mutex<State>,
+}
+
+impl LoadMonitor {
+    pub(crate) fn new(endpoint: quinn::Endpoint, config: &ServerConfig, threshold: usize) -> Self {
+        let mut retry = config.clone();
+
+        retry.use_retry(true);
+
+        Self {
+            endpoint,
+            normal: config.clone(),
+            retry,
+            threshold,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    pub(crate) fn handshake(self: &Arc<Self>) -> HandshakeGuard {
+        let mut state = self.state.lock().unwrap();
+
+        state.handshakes += 1;
+        if !state.closed && !state.retrying && state.handshakes > self.threshold {
+            info!(
+                "{} handshakes in progress, requiring address validation.",
+                state.handshakes
+            );
+
+            state.retrying = true;
+            self.endpoint.set_server_config(Some(self.retry.clone()));
+        }
+
+        HandshakeGuard(Arc::clone(self))
+    }
+
+    /// Stops switching the server configuration, so that it can be removed on shutdown.
+    pub(crate) fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+    }
+
+    fn complete(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.handshakes -= 1;
+        // Switches back at the half of the threshold, not to flap around it.
+        if !state.closed && state.retrying && state.handshakes <= self.threshold / 2 {
+            info!("Load has settled, no longer requiring address validation.");
+
+            state.retrying = false;
+            self.endpoint.set_server_config(Some(self.normal.clone()));
+        }
+    }
+}
+
+pub(crate) struct HandshakeGuard(Arc<LoadMonitor>);
+
+impl Drop for HandshakeGuard {
+    fn drop(&mut self) {
+        self.0.complete();
+    }
+}
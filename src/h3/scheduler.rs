@@ -0,0 +1,209 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::sync::{watch, Mutex, MutexGuard};
+use tokio::time::timeout;
+
+use crate::metadata::Priority;
+
+const URGENCY_LEVELS: usize = Priority::MAX_URGENCY as usize + 1;
+
+/// Duration a chunk may wait for flow control while keeping its slot. Past it, the slot is
+/// released until the chunk has been sent, so that a stalled stream does not hold back the others.
+const STALL_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Schedules sending response bodies on a connection by their priorities.
+/// Bodies wait while more urgent ones are being sent, and non-incremental bodies of the same
+/// urgency are sent one after another.
+pub(crate) struct Scheduler {
+    sending: watch::Sender<[usize; URGENCY_LEVELS]>,
+    sequential: [Mutex<()>; URGENCY_LEVELS],
+}
+
+impl Scheduler {
+    pub(crate) fn new() -> Self {
+        Self {
+            sending: watch::channel([0; URGENCY_LEVELS]).0,
+            sequential: Default::default(),
+        }
+    }
+
+    pub(crate) async fn schedule(&self, priority: Priority) -> Slot<'_> {
+        let mut slot = Slot {
+            scheduler: self,
+            urgency: priority.urgency as usize,
+            incremental: priority.incremental,
+            receiver: self.sending.subscribe(),
+            sending: false,
+            sequential: None,
+        };
+
+        slot.acquire().await;
+        slot
+    }
+}
+
+pub(crate) struct Slot<'a> {
+    scheduler: &'a Scheduler,
+    urgency: usize,
+    incremental: bool,
+    receiver: watch::Receiver<[usize; URGENCY_LEVELS]>,
+    // Set while the body counts as being sent, holding back less urgent ones.
+    sending: bool,
+    sequential: Option<MutexGuard<'a, ()>>,
+}
+
+impl Slot<'_> {
+    async fn acquire(&mut self) {
+        if self.sending {
+            return;
+        }
+
+        if !self.incremental {
+            self.sequential = Some(self.scheduler.sequential[self.urgency].lock().await);
+        }
+
+        let urgency = self.urgency;
+        self.scheduler.sending.send_modify(|s| s[urgency] += 1);
+        self.sending = true;
+    }
+
+    fn release(&mut self) {
+        if !self.sending {
+            return;
+        }
+
+        let urgency = self.urgency;
+        self.scheduler.sending.send_modify(|s| s[urgency] -= 1);
+        self.sending = false;
+        self.sequential = None;
+    }
+
+    /// Waits until no more urgent body is being sent, taking the slot back if it was released.
+    pub(crate) async fn turn(&mut self) {
+        self.acquire().await;
+
+        loop {
+            if self.receiver.borrow_and_update()[..self.urgency]
+                .iter()
+                .all(|n| *n == 0)
+            {
+                return;
+            }
+
+            if self.receiver.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Sends a chunk, releasing the slot if flow control holds it back for too long.
+    pub(crate) async fn send<F>(&mut self, chunk: F) -> F::Output
+    where
+        F: Future,
+    {
+        tokio::pin!(chunk);
+
+        match timeout(STALL_TIMEOUT, &mut chunk).await {
+            Ok(output) => output,
+            Err(_) => {
+                self.release();
+                chunk.await
+            }
+        }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::pending;
+
+    use super::*;
+
+    fn priority(urgency: u8, incremental: bool) -> Priority {
+        Priority {
+            urgency,
+            incremental,
+        }
+    }
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[tokio::test]
+    async fn waits_while_more_urgent_bodies_are_sent() {
+        let scheduler = Scheduler::new();
+        let urgent = scheduler.schedule(priority(1, false)).await;
+        let mut background = scheduler.schedule(priority(5, true)).await;
+
+        assert!(timeout(WAIT, background.turn()).await.is_err());
+
+        drop(urgent);
+        assert!(timeout(WAIT, background.turn()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn less_urgent_bodies_do_not_hold_back() {
+        let scheduler = Scheduler::new();
+        let _background = scheduler.schedule(priority(7, false)).await;
+        let mut urgent = scheduler.schedule(priority(0, false)).await;
+
+        assert!(timeout(WAIT, urgent.turn()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn stalled_bodies_do_not_hold_back_the_others() {
+        let scheduler = Scheduler::new();
+        let mut stalled = scheduler.schedule(priority(3, false)).await;
+        stalled.turn().await;
+
+        let others = async {
+            let mut same = scheduler.schedule(priority(3, false)).await;
+            same.turn().await;
+            drop(same);
+
+            let mut less_urgent = scheduler.schedule(priority(5, true)).await;
+            less_urgent.turn().await;
+        };
+
+        tokio::select! {
+            _ = stalled.send(pending::<()>()) => unreachable!(),
+            r = timeout(STALL_TIMEOUT * 10, others) => assert!(r.is_ok()),
+        }
+    }
+
+    #[tokio::test]
+    async fn bodies_keep_their_slot_between_chunks_sent_in_time() {
+        let scheduler = Scheduler::new();
+        let mut first = scheduler.schedule(priority(3, false)).await;
+        first.turn().await;
+        first.send(async {}).await;
+
+        assert!(timeout(WAIT, scheduler.schedule(priority(3, false)))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn non_incremental_bodies_of_the_same_urgency_take_turns() {
+        let scheduler = Scheduler::new();
+        let first = scheduler.schedule(priority(3, false)).await;
+
+        assert!(timeout(WAIT, scheduler.schedule(priority(3, false)))
+            .await
+            .is_err());
+        assert!(timeout(WAIT, scheduler.schedule(priority(3, true)))
+            .await
+            .is_ok());
+
+        drop(first);
+        assert!(timeout(WAIT, scheduler.schedule(priority(3, false)))
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,107 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use rustls::KeyLog;
+use tracing::warn;
+
+/// Writes TLS secrets in the NSS key log format, for tools like Wireshark to decrypt traffic.
+/// Shared by the TCP and QUIC endpoints, so that both append to the same file.
+pub(crate) struct KeyLogFile {
+    file: Mutex<File>,
+}
+
+impl KeyLogFile {
+    /// Opens the file to append to, creating it readable by the owner only on Unix.
+    pub(crate) fn open(path: &Path) -> io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+
+            options.mode(0o600);
+
+            let file = options.open(path)?;
+            if file.metadata()?.mode() & 0o077 != 0 {
+                warn!(
+                    "The TLS key log file {} is accessible by other users.",
+                    path.display()
+                );
+            }
+
+            Ok(Self {
+                file: Mutex::new(file),
+            })
+        }
+
+        #[cfg(not(unix))]
+        Ok(Self {
+            file: Mutex::new(options.open(path)?),
+        })
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+
+        // Writes each line at once, so that lines from concurrent handshakes do not interleave.
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            warn!("Failed to write a TLS key log line: {}", e);
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn appends_lines_in_the_nss_format() {
+        let path = std::env::temp_dir().join(format!("h123-keylog-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let key_log = KeyLogFile::open(&path).unwrap();
+        key_log.log("CLIENT_RANDOM", &[0x01, 0xab], &[0xff, 0x00]);
+        key_log.log("SERVER_TRAFFIC_SECRET_0", &[0x02], &[0x10]);
+        drop(key_log);
+
+        KeyLogFile::open(&path)
+            .unwrap()
+            .log("EXPORTER_SECRET", &[0x03], &[0x20]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            assert_eq!(
+                fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+                0o600
+            );
+        }
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            contents,
+            "CLIENT_RANDOM 01ab ff00\nSERVER_TRAFFIC_SECRET_0 02 10\nEXPORTER_SECRET 03 20\n"
+        );
+    }
+
+    #[test]
+    fn fails_on_unwritable_paths() {
+        let path = std::env::temp_dir().join("h123-no-such-dir").join("keylog");
+
+        assert!(KeyLogFile::open(&path).is_err());
+    }
+}
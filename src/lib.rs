@@ -1,8 +1,22 @@
 mod convert;
+mod der;
 mod h12;
 mod h3;
+mod key_log;
 mod server;
+mod shutdown;
+mod socket;
+mod x509;
 
+pub mod acme;
+pub mod metadata;
+pub mod ocsp;
+pub mod options;
+pub mod proxy;
 pub mod service;
+pub mod tls;
+pub mod websocket;
 
+pub use options::Options;
 pub use server::Server;
+pub use shutdown::ShutdownHandle;
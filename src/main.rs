@@ -1,27 +1,138 @@
 use std::error::Error;
-use std::fs::File;
-use std::io::BufReader;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use clap::Parser;
-use rustls::{Certificate, PrivateKey};
-use tracing::{error, info};
+use rustls::server::ResolvesServerCert;
+use tokio::sync::Notify;
+use tracing::{error, info, warn};
 
+use h123::acme::{self, Acme, Challenge};
+use h123::ocsp::{self, Stapler};
+use h123::options::{CongestionController, EarlyDataPolicy, RetryPolicy};
+use h123::proxy::{Credentials, Destination, ForwardProxy};
 use h123::service::StaticFileService;
-use h123::Server;
+use h123::tls::{
+    self, CertResolver, ClientAuth, DevCertificate, Parameters, RevocationList, Version,
+};
+use h123::websocket::Echo;
+use h123::{Options, Server};
+
+const MIN_KEY_LENGTH: usize = 32;
 
 /// An experimental HTTP server in Rust that supports HTTP/1.1, HTTP/2, and HTTP/3 over QUIC.
 #[derive(Parser)]
 struct Cli {
     /// Path to a certificate chain file in PEM format.
-    #[arg(long)]
-    cert_chain_pem: String,
+    #[arg(long, required_unless_present_any = ["acme_directory", "dev"])]
+    cert_chain_pem: Option<String>,
 
     /// Path to a private key file in PEM format.
+    #[arg(long, required_unless_present_any = ["acme_directory", "dev"])]
+    private_key_pem: Option<String>,
+
+    /// Serves a self-signed certificate generated at startup for localhost, instead of reading it
+    /// from files. Prints the flags for Chrome to trust it.
+    #[arg(long, conflicts_with_all = ["cert_chain_pem", "private_key_pem", "acme_directory"])]
+    dev: bool,
+
+    /// DNS name or IP address to add to the certificate of the development mode. Can be given more
+    /// than once.
+    #[arg(long, requires = "dev")]
+    dev_name: Vec<String>,
+
+    /// Seconds between checks of the certificate, key and CRL files for changes to reload.
+    /// They are also reloaded on SIGHUP.
+    #[arg(long, default_value_t = 60)]
+    cert_reload_interval: u64,
+
+    /// TLS versions to enable, separated by commas. HTTP/3 requires 1.3.
+    #[arg(long, value_delimiter = ',', default_value = "1.2,1.3")]
+    tls_versions: Vec<Version>,
+
+    /// Cipher suites to enable in the order of preference, separated by commas, such as
+    /// TLS13_AES_256_GCM_SHA384. Defaults to the safe defaults of rustls.
+    #[arg(long, value_delimiter = ',', value_parser = tls::cipher_suite)]
+    cipher_suites: Option<Vec<rustls::SupportedCipherSuite>>,
+
+    /// Key exchange groups to enable in the order of preference, separated by commas: X25519,
+    /// secp256r1 or secp384r1. Defaults to all of them.
+    #[arg(long, value_delimiter = ',', value_parser = tls::kx_group)]
+    kx_groups: Option<Vec<&'static rustls::SupportedKxGroup>>,
+
+    /// Path to a CA bundle in PEM format to verify client certificates against.
+    /// Enables TLS client authentication.
+    #[arg(long)]
+    client_ca: Option<PathBuf>,
+
+    /// Whether clients must present a certificate: required or optional.
+    #[arg(long, default_value = "required", requires = "client_ca")]
+    client_auth: ClientAuth,
+
+    /// Path to a certificate revocation list in PEM or DER format to reject client certificates by.
+    /// Checked for changes along with the certificate files, and reloaded on SIGHUP too.
+    #[arg(long, requires = "client_ca")]
+    client_crl: Option<PathBuf>,
+
+    /// Path to a DER file of an OCSP response to staple, read again on every refresh. The certificate
+    /// chain must contain the issuer to match the response by.
+    #[arg(long, conflicts_with_all = ["ocsp_fetch", "ocsp_responder", "dev", "acme_directory"])]
+    ocsp_response: Option<PathBuf>,
+
+    /// Fetches OCSP responses to staple from the responder in the certificate.
+    #[arg(long, conflicts_with_all = ["dev", "acme_directory"])]
+    ocsp_fetch: bool,
+
+    /// URL of the OCSP responder to fetch responses from, instead of the one in the certificate.
+    #[arg(long, conflicts_with_all = ["dev", "acme_directory"])]
+    ocsp_responder: Option<String>,
+
+    /// Seconds between refreshes of the stapled OCSP response.
+    #[arg(long, default_value_t = 3600)]
+    ocsp_refresh_interval: u64,
+
+    /// Hours before the nextUpdate of the stapled OCSP response to start warning at.
+    #[arg(long, default_value_t = 24)]
+    ocsp_warn_before: u64,
+
+    /// URL of the directory of an ACME server to obtain and renew the certificate from, instead of
+    /// reading it from files.
+    #[arg(long, requires_all = ["acme_domain", "acme_agree_tos"], conflicts_with_all = ["cert_chain_pem", "private_key_pem"])]
+    acme_directory: Option<String>,
+
+    /// Agrees to the terms of service of the ACME server, which registering an account requires.
+    #[arg(long)]
+    acme_agree_tos: bool,
+
+    /// Domain to obtain the certificate for by ACME. Can be given more than once.
+    #[arg(long)]
+    acme_domain: Vec<String>,
+
+    /// Contact URL of the ACME account, such as mailto:admin@example.com.
     #[arg(long)]
-    private_key_pem: String,
+    acme_contact: Vec<String>,
+
+    /// Directory to store the ACME account key and the certificate in.
+    #[arg(long, default_value = "acme")]
+    acme_storage: PathBuf,
+
+    /// Challenge type to validate the domains by: tls-alpn-01 or http-01.
+    #[arg(long, default_value = "tls-alpn-01")]
+    acme_challenge: Challenge,
+
+    /// Socket address of the cleartext listener answering HTTP-01 challenges.
+    #[arg(long, default_value = "0.0.0.0:80")]
+    acme_http01_bind: SocketAddr,
+
+    /// Path to a root certificate in PEM format to trust the ACME server by, such as Pebble's.
+    #[arg(long)]
+    acme_ca_cert: Option<PathBuf>,
+
+    /// Days before the expiry to renew the certificate at.
+    #[arg(long, default_value_t = 30)]
+    acme_renew_before: u64,
 
     /// Path to the document root.
     #[arg(short, long)]
@@ -30,68 +141,573 @@ struct Cli {
     /// Socket address to bind to.
     #[arg(short, long)]
     bind_to: SocketAddr,
+
+    /// Number of sockets to accept connections on per protocol, sharing the port by SO_REUSEPORT.
+    #[arg(long, default_value_t = 1)]
+    acceptors: usize,
+
+    /// Directory to write qlog traces of QUIC connections into.
+    #[arg(long)]
+    qlog_dir: Option<PathBuf>,
+
+    /// Path to a file to append TLS secrets to, for debugging with tools like Wireshark.
+    /// Defaults to the SSLKEYLOGFILE environment variable.
+    #[arg(long)]
+    key_log_file: Option<PathBuf>,
+
+    /// Seconds to wait for in-flight requests to complete on shutdown.
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Seconds to wait for a TLS handshake to complete.
+    #[arg(long)]
+    handshake_timeout: Option<u64>,
+
+    /// Seconds to wait for the header of a HTTP/1.1 request.
+    #[arg(long)]
+    header_read_timeout: Option<u64>,
+
+    /// Seconds a connection may stay idle, without requests in flight, before being closed.
+    /// Zero disables the idle timeout.
+    #[arg(long)]
+    idle_timeout: Option<u64>,
+
+    /// Seconds to wait for the body of a request.
+    #[arg(long)]
+    request_body_timeout: Option<u64>,
+
+    /// Seconds to wait for a response to be produced.
+    #[arg(long)]
+    response_timeout: Option<u64>,
+
+    /// Maximum number of concurrent TCP connections.
+    #[arg(long)]
+    max_tcp_connections: Option<usize>,
+
+    /// Maximum number of concurrent QUIC connections.
+    #[arg(long)]
+    max_quic_connections: Option<u32>,
+
+    /// Maximum number of concurrent streams per HTTP/2 connection.
+    #[arg(long)]
+    max_h2_streams: Option<u32>,
+
+    /// Maximum number of concurrent streams per HTTP/3 connection.
+    #[arg(long)]
+    max_h3_streams: Option<u32>,
+
+    /// Congestion controller of QUIC connections: new-reno, cubic or bbr.
+    #[arg(long, default_value = "cubic")]
+    congestion_controller: CongestionController,
+
+    /// Receive window of a QUIC stream in bytes.
+    #[arg(long)]
+    stream_receive_window: Option<u32>,
+
+    /// Receive window of a QUIC connection in bytes.
+    #[arg(long)]
+    receive_window: Option<u32>,
+
+    /// Seconds between keep-alive packets on QUIC connections.
+    #[arg(long)]
+    keep_alive_interval: Option<u64>,
+
+    /// Initial RTT estimate of QUIC connections in milliseconds.
+    #[arg(long)]
+    initial_rtt: Option<u64>,
+
+    /// Maximum UDP payload size of QUIC packets in bytes.
+    #[arg(long)]
+    max_udp_payload_size: Option<u16>,
+
+    /// Policy on TLS 1.3 and QUIC early data: disabled or safe-methods.
+    #[arg(long, default_value = "disabled")]
+    early_data: EarlyDataPolicy,
+
+    /// When to validate addresses of QUIC clients with Retry packets: never, always or under-load.
+    #[arg(long, default_value = "never")]
+    retry: RetryPolicy,
+
+    /// Number of handshakes in progress to start sending Retry packets at, in the under-load policy.
+    #[arg(long, default_value_t = RetryPolicy::DEFAULT_HANDSHAKES)]
+    retry_threshold: usize,
+
+    /// Path to a file of a secret to protect Retry tokens with.
+    #[arg(long)]
+    token_key_file: Option<PathBuf>,
+
+    /// Path to a file of a secret to derive stateless reset tokens from.
+    #[arg(long)]
+    reset_key_file: Option<PathBuf>,
+
+    /// Disables advertising the HTTP/3 endpoint in Alt-Svc headers.
+    #[arg(long)]
+    no_alt_svc: bool,
+
+    /// Protocols to advertise in Alt-Svc headers, separated by commas.
+    #[arg(long, value_delimiter = ',')]
+    alt_svc_protocols: Option<Vec<String>>,
+
+    /// Alternative host to advertise in Alt-Svc headers.
+    #[arg(long)]
+    alt_svc_host: Option<String>,
+
+    /// Port to advertise in Alt-Svc headers.
+    #[arg(long)]
+    alt_svc_port: Option<u16>,
+
+    /// Seconds the Alt-Svc advertisement is fresh for.
+    #[arg(long)]
+    alt_svc_max_age: Option<u64>,
+
+    /// Destination to allow proxying UDP to by CONNECT-UDP, as host:port where either can be *.
+    /// Loopback and private addresses are only reachable when given literally.
+    /// CONNECT-UDP is enabled when given at least once.
+    #[arg(long)]
+    connect_udp_allow: Vec<Destination>,
+
+    /// Destination to allow the forward proxy to connect to, as host:port where either can be *.
+    /// Loopback and private addresses are only reachable when given literally.
+    /// The forward proxy is enabled when given at least once.
+    #[arg(long)]
+    proxy_allow: Vec<Destination>,
+
+    /// Path to a file of user:password to require from clients of the forward proxy.
+    #[arg(long)]
+    proxy_credentials_file: Option<PathBuf>,
+
+    /// Path to echo messages back on WebSocket connections opened at, over HTTP/1.1 and HTTP/2.
+    #[arg(long)]
+    websocket_echo: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    #[cfg(target_family = "unix")]
-    {
-        use tokio::signal::unix::{signal, SignalKind};
+    if let Err(e) = run().await {
+        error!("{}", e);
+        std::process::exit(1);
+    }
 
-        let mut sigint = signal(SignalKind::interrupt())?;
-        let mut sigterm = signal(SignalKind::terminate())?;
+    Ok(())
+}
 
+#[cfg(target_family = "unix")]
+async fn wait_for_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select!(
+        _ = sigint.recv() => {},
+        _ = sigterm.recv() => {},
+    );
+
+    Ok(())
+}
+
+#[cfg(not(target_family = "unix"))]
+async fn wait_for_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Checks files for changes on every tick of the interval, and forcibly on SIGHUP.
+#[cfg(target_family = "unix")]
+async fn watch_files<F>(interval: Duration, mut check: F) -> std::io::Result<()>
+where
+    F: FnMut(bool),
+{
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
         tokio::select!(
-            r = run() => {
-                match r {
-                    Ok(_) => (),
-                    Err(e) => error!("{}", e),
-                }
-            },
-            _ = sigint.recv() => {},
-            _ = sigterm.recv() => {},
+            _ = sighup.recv() => check(true),
+            _ = interval.tick() => check(false),
         );
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+async fn watch_files<F>(interval: Duration, mut check: F) -> std::io::Result<()>
+where
+    F: FnMut(bool),
+{
+    let mut interval = tokio::time::interval(interval);
 
-        info!("Gracefully shutting down...");
+    loop {
+        interval.tick().await;
+        check(false);
     }
+}
+
+async fn watch_certificate(
+    resolver: Arc<CertResolver>,
+    interval: Duration,
+    reloaded: Arc<Notify>,
+) -> std::io::Result<()> {
+    watch_files(interval, |forced| {
+        let result = match forced {
+            true => resolver.reload().map(|_| true),
+            false => resolver.reload_if_modified(),
+        };
+
+        if reload_certificate(result) {
+            reloaded.notify_one();
+        }
+    })
+    .await
+}
+
+/// Reloads the certificate revocation list along with the certificate, warning once it is stale.
+async fn watch_crl(crl: Arc<RevocationList>, interval: Duration) -> std::io::Result<()> {
+    let mut warned = None;
+
+    watch_files(interval, |forced| {
+        let result = match forced {
+            true => crl.reload().map(|_| true),
+            false => crl.reload_if_modified(),
+        };
+
+        match result {
+            Ok(true) => info!("Reloaded the certificate revocation list."),
+            Ok(false) => (),
+            Err(e) => warn!(
+                "Keeping the current certificate revocation list, as reloading it failed: {}",
+                e
+            ),
+        }
+
+        let next_update = crl.next_update();
+        let overdue = next_update.and_then(|t| SystemTime::now().duration_since(t).ok());
+        if let Some(overdue) = overdue.filter(|_| warned != next_update) {
+            warn!(
+                "The certificate revocation list passed its nextUpdate {} minutes ago.",
+                overdue.as_secs() / 60
+            );
+            warned = next_update;
+        }
+    })
+    .await
+}
 
-    #[cfg(not(target_family = "unix"))]
-    match run().await {
-        Ok(_) => (),
-        Err(e) => error!("{}", e),
+/// Logs the result of reloading the certificate, returning whether it has been reloaded.
+fn reload_certificate(result: Result<bool, h123::tls::Error>) -> bool {
+    match result {
+        Ok(true) => {
+            info!("Reloaded the certificate.");
+            true
+        }
+        Ok(false) => false,
+        Err(e) => {
+            warn!(
+                "Keeping the current certificate, as reloading it failed: {}",
+                e
+            );
+            false
+        }
     }
+}
 
-    Ok(())
+/// Refreshes the stapled OCSP response periodically, and as soon as the certificate is reloaded.
+async fn staple_ocsp(
+    resolver: Arc<CertResolver>,
+    stapler: Stapler,
+    interval: Duration,
+    reloaded: Arc<Notify>,
+) {
+    loop {
+        if let Err(e) = stapler.refresh(&resolver).await {
+            warn!("Failed to refresh the OCSP response: {}", e);
+        }
+
+        tokio::select!(
+            _ = tokio::time::sleep(interval) => {},
+            _ = reloaded.notified() => {},
+        );
+    }
+}
+
+/// Renews the certificate of the development mode before it expires, printing the new hash.
+async fn renew_dev_certificate(certificate: Arc<DevCertificate>, origin: String) {
+    loop {
+        tokio::time::sleep(DevCertificate::RENEWAL_INTERVAL).await;
+
+        match certificate.renew() {
+            Ok(_) => print_dev_certificate(&certificate, &origin),
+            Err(e) => error!("Failed to renew the certificate: {}", e),
+        }
+    }
+}
+
+fn print_dev_certificate(certificate: &DevCertificate, origin: &str) {
+    let hash = certificate
+        .certificate_sha256()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+
+    info!(
+        "Generated a self-signed certificate valid for {} days.",
+        DevCertificate::VALIDITY.as_secs() / 86400
+    );
+    info!("SPKI SHA-256: {}", certificate.spki_sha256());
+    info!("Certificate SHA-256 for serverCertificateHashes: {}", hash);
+    info!(
+        "Run Chrome with: --origin-to-force-quic-on={} --ignore-certificate-errors-spki-list={}",
+        origin,
+        certificate.spki_sha256()
+    );
+}
+
+fn read_key(path: PathBuf) -> Result<Vec<u8>, Box<dyn Error>> {
+    let key = std::fs::read(&path)?;
+    if key.len() < MIN_KEY_LENGTH {
+        return Err(format!(
+            "{} must contain at least {} bytes of secret.",
+            path.display(),
+            MIN_KEY_LENGTH
+        )
+        .into());
+    }
+
+    Ok(key)
+}
+
+fn read_credentials(path: PathBuf) -> Result<Credentials, Box<dyn Error>> {
+    Ok(std::fs::read_to_string(path)?.parse()?)
 }
 
 async fn run() -> Result<(), Box<dyn Error>> {
     tracing_subscriber::fmt::init();
 
     let args = Cli::parse();
-    let rustls_config = &rustls::ServerConfig::builder()
-        .with_safe_default_cipher_suites()
-        .with_safe_default_kx_groups()
-        .with_protocol_versions(&[&rustls::version::TLS12, &rustls::version::TLS13])?
-        .with_no_client_auth()
-        .with_single_cert(
-            rustls_pemfile::certs(&mut BufReader::new(File::open(args.cert_chain_pem)?))?
-                .into_iter()
-                .map(Certificate)
-                .collect(),
-            rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(
-                args.private_key_pem,
-            )?))?
-            .into_iter()
-            .map(PrivateKey)
-            .next()
-            .unwrap(),
-        )?;
-
-    Ok(Server::new(
+    let tls_alpn01 = args.acme_directory.is_some() && args.acme_challenge == Challenge::TlsAlpn01;
+    let resolver: Arc<dyn ResolvesServerCert> = match args.acme_directory {
+        Some(directory) => {
+            let mut config = acme::Config::new(directory, args.acme_domain, args.acme_storage);
+
+            config.contact = args.acme_contact;
+            config.agree_tos = args.acme_agree_tos;
+            config.challenge = args.acme_challenge;
+            config.http01_bind = args.acme_http01_bind;
+            config.ca_cert = args.acme_ca_cert;
+            config.renew_before = Duration::from_secs(args.acme_renew_before * 86400);
+
+            let acme = Arc::new(Acme::new(config)?);
+            tokio::spawn({
+                let acme = Arc::clone(&acme);
+                async move {
+                    if let Err(e) = acme.run().await {
+                        error!("{}", e);
+                    }
+                }
+            });
+
+            acme
+        }
+        None if args.dev => {
+            let mut names = args.dev_name.clone();
+            names.extend(args.alt_svc_host.clone());
+
+            let certificate = Arc::new(DevCertificate::generate(&names)?);
+            let origin = format!("localhost:{}", args.bind_to.port());
+            print_dev_certificate(&certificate, &origin);
+            tokio::spawn(renew_dev_certificate(Arc::clone(&certificate), origin));
+
+            certificate
+        }
+        None => {
+            let resolver = Arc::new(CertResolver::load(
+                args.cert_chain_pem.unwrap_or_default(),
+                args.private_key_pem.unwrap_or_default(),
+            )?);
+
+            let reloaded = Arc::new(Notify::new());
+            let interval = Duration::from_secs(args.cert_reload_interval);
+            tokio::spawn({
+                let resolver = Arc::clone(&resolver);
+                let reloaded = Arc::clone(&reloaded);
+                async move {
+                    if let Err(e) = watch_certificate(resolver, interval, reloaded).await {
+                        error!("{}", e);
+                    }
+                }
+            });
+
+            let source = match (args.ocsp_response, args.ocsp_responder) {
+                (Some(path), _) => Some(ocsp::Source::File(path)),
+                (None, Some(url)) => Some(ocsp::Source::Responder(Some(url))),
+                (None, None) if args.ocsp_fetch => Some(ocsp::Source::Responder(None)),
+                (None, None) => None,
+            };
+
+            if let Some(source) = source {
+                let stapler =
+                    Stapler::new(source, Duration::from_secs(args.ocsp_warn_before * 3600))?;
+
+                tokio::spawn(staple_ocsp(
+                    Arc::clone(&resolver),
+                    stapler,
+                    Duration::from_secs(args.ocsp_refresh_interval),
+                    reloaded,
+                ));
+            }
+
+            resolver
+        }
+    };
+
+    let mut parameters = Parameters {
+        versions: args.tls_versions,
+        ..Default::default()
+    };
+
+    if let Some(cipher_suites) = args.cipher_suites {
+        parameters.cipher_suites = cipher_suites;
+    }
+
+    if let Some(kx_groups) = args.kx_groups {
+        parameters.kx_groups = kx_groups;
+    }
+
+    let builder = parameters.builder()?;
+
+    let crl = match args.client_crl {
+        Some(path) => {
+            let crl = Arc::new(RevocationList::load(path)?);
+            let interval = Duration::from_secs(args.cert_reload_interval);
+            tokio::spawn({
+                let crl = Arc::clone(&crl);
+                async move {
+                    if let Err(e) = watch_crl(crl, interval).await {
+                        error!("{}", e);
+                    }
+                }
+            });
+
+            Some(crl)
+        }
+        None => None,
+    };
+
+    let rustls_config = &match &args.client_ca {
+        Some(ca) => {
+            builder.with_client_cert_verifier(tls::client_cert_verifier(ca, args.client_auth, crl)?)
+        }
+        None => builder.with_no_client_auth(),
+    }
+    .with_cert_resolver(resolver);
+
+    let mut options = Options::default();
+    let timeouts = &mut options.timeouts;
+
+    timeouts.handshake = args
+        .handshake_timeout
+        .map(Duration::from_secs)
+        .or(timeouts.handshake);
+    timeouts.header_read = args
+        .header_read_timeout
+        .map(Duration::from_secs)
+        .or(timeouts.header_read);
+    timeouts.idle = args.idle_timeout.map(Duration::from_secs).or(timeouts.idle);
+    timeouts.request_body = args
+        .request_body_timeout
+        .map(Duration::from_secs)
+        .or(timeouts.request_body);
+    timeouts.response = args
+        .response_timeout
+        .map(Duration::from_secs)
+        .or(timeouts.response);
+
+    options.limits.tcp_connections = args.max_tcp_connections;
+    options.limits.quic_connections = args.max_quic_connections;
+    options.limits.h2_streams = args.max_h2_streams;
+    options.limits.h3_streams = args.max_h3_streams;
+
+    let transport = &mut options.transport;
+
+    transport.congestion_controller = args.congestion_controller;
+    transport.stream_receive_window = args.stream_receive_window;
+    transport.receive_window = args.receive_window;
+    transport.keep_alive_interval = args.keep_alive_interval.map(Duration::from_secs);
+    transport.initial_rtt = args.initial_rtt.map(Duration::from_millis);
+    transport.max_udp_payload_size = args.max_udp_payload_size;
+
+    options.acceptors = args.acceptors;
+    options.qlog_dir = args.qlog_dir;
+    options.key_log = args
+        .key_log_file
+        .or_else(|| std::env::var_os("SSLKEYLOGFILE").map(PathBuf::from));
+    options.early_data = args.early_data;
+
+    let validation = &mut options.address_validation;
+
+    validation.retry = match args.retry {
+        RetryPolicy::UnderLoad { .. } => RetryPolicy::UnderLoad {
+            handshakes: args.retry_threshold,
+        },
+        p => p,
+    };
+    validation.token_key = args.token_key_file.map(read_key).transpose()?;
+    validation.reset_key = args.reset_key_file.map(read_key).transpose()?;
+
+    let alt_svc = &mut options.alt_svc;
+
+    alt_svc.enabled = !args.no_alt_svc;
+    alt_svc.host = args.alt_svc_host;
+    alt_svc.port = args.alt_svc_port;
+
+    if let Some(protocols) = args.alt_svc_protocols {
+        alt_svc.protocols = protocols;
+    }
+
+    if let Some(max_age) = args.alt_svc_max_age {
+        alt_svc.max_age = Duration::from_secs(max_age);
+    }
+
+    let mut server = Server::with_options(
         rustls_config,
         args.bind_to,
         Arc::new(StaticFileService::new(args.document_root.canonicalize()?)),
-    )
-    .begin()
-    .await?)
+        &options,
+    )?;
+
+    if tls_alpn01 {
+        server = server.acme_tls_alpn();
+    }
+
+    if !args.connect_udp_allow.is_empty() {
+        server = server.connect_udp(args.connect_udp_allow.into_iter().collect());
+    }
+
+    if let Some(path) = args.websocket_echo {
+        server = server.websocket(Arc::new(Echo::new(path)));
+    }
+
+    if !args.proxy_allow.is_empty() {
+        server = server.forward_proxy(ForwardProxy {
+            allow_list: args.proxy_allow.into_iter().collect(),
+            credentials: args
+                .proxy_credentials_file
+                .map(read_credentials)
+                .transpose()?,
+        });
+    }
+
+    let shutdown = server.shutdown_handle();
+    let grace = Duration::from_secs(args.shutdown_timeout);
+    tokio::spawn(async move {
+        match wait_for_signal().await {
+            Ok(_) => {
+                info!("Gracefully shutting down...");
+                shutdown.shutdown(grace);
+            }
+            Err(e) => error!("{}", e),
+        }
+    });
+
+    Ok(server.begin().await?)
 }
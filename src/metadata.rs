@@ -0,0 +1,249 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use http::header::{HeaderMap, HeaderName, HeaderValue, LINK};
+use http::{Method, StatusCode, Version};
+use rustls::{Certificate, CipherSuite, ProtocolVersion};
+
+use crate::x509;
+
+/// Connection a request has arrived on, found in the extensions of every request.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub remote_addr: Option<SocketAddr>,
+    pub version: Version,
+
+    /// Protocol negotiated by ALPN.
+    pub alpn: Option<Vec<u8>>,
+
+    pub tls_version: Option<ProtocolVersion>,
+
+    /// Always `None` on HTTP/3, as quinn does not expose the negotiated cipher suite.
+    pub cipher_suite: Option<CipherSuite>,
+
+    /// Server name indicated by the client.
+    pub server_name: Option<String>,
+
+    /// Identifier of the QUIC connection, stable across changes of its connection IDs.
+    /// quinn does not expose the connection IDs themselves.
+    pub quic_connection: Option<usize>,
+
+    /// Certificate the client has been authenticated by, if any.
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(remote_addr: Option<SocketAddr>, version: Version) -> Self {
+        Self {
+            remote_addr,
+            version,
+            alpn: None,
+            tls_version: None,
+            cipher_suite: None,
+            server_name: None,
+            quic_connection: None,
+            client_certificate: None,
+        }
+    }
+}
+
+/// Certificate presented by the client and verified in the TLS handshake.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    /// Chain as sent by the client, starting from the end-entity certificate.
+    pub chain: Vec<Certificate>,
+
+    /// Subject of the end-entity certificate in the RFC 4514 format, such as `CN=client,O=Example`.
+    /// `None` if it could not be read, which the verifier in rustls may still have accepted.
+    pub subject: Option<String>,
+}
+
+impl ClientCertificate {
+    pub(crate) fn new(chain: &[Certificate]) -> Option<Self> {
+        let subject =
+            x509::Certificate::parse(&chain.first()?.0).and_then(|c| x509::format_name(c.subject));
+
+        Some(Self {
+            chain: chain.to_vec(),
+            subject,
+        })
+    }
+}
+
+/// Marks a request that arrived as TLS 1.3 or QUIC early data (0-RTT).
+/// Early data may have been replayed by an attacker, so only safe methods are served from it.
+#[derive(Clone, Copy, Debug)]
+pub struct EarlyData;
+
+impl EarlyData {
+    pub(crate) fn allows(method: &Method) -> bool {
+        method.is_safe()
+    }
+
+    pub(crate) fn too_early() -> StatusCode {
+        StatusCode::from_u16(425).unwrap()
+    }
+}
+
+/// Suppresses the `Alt-Svc` header when inserted into the extensions of a response.
+#[derive(Clone, Copy, Debug)]
+pub struct SuppressAltSvc;
+
+/// Links of resources to preload, added to the final response as values of `Link` headers.
+/// Found in the extensions of every request.
+///
+/// They are not sent in `103 Early Hints` ahead of the response, as neither hyper nor h3 can send
+/// informational responses yet.
+#[derive(Clone, Debug, Default)]
+pub struct PreloadLinks {
+    links: Arc<Mutex<Vec<HeaderValue>>>,
+}
+
+impl PreloadLinks {
+    pub fn add<I>(&self, links: I)
+    where
+        I: IntoIterator<Item = HeaderValue>,
+    {
+        self.links.lock().unwrap().extend(links);
+    }
+
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) {
+        for link in self.links.lock().unwrap().drain(..) {
+            headers.append(LINK, link);
+        }
+    }
+}
+
+const PRIORITY: HeaderName = HeaderName::from_static("priority");
+
+/// Priority of a response (RFC 9218), found in the extensions of every request.
+/// Parsed from the `Priority` header of the request, then overridden by the one of the response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Priority {
+    /// From 0 (the most urgent) to 7.
+    pub urgency: u8,
+
+    /// Whether the response can be used as it arrives, so that it can share the bandwidth with
+    /// other responses of the same urgency.
+    pub incremental: bool,
+}
+
+impl Priority {
+    pub const MAX_URGENCY: u8 = 7;
+
+    /// Overrides parameters with the ones in the `Priority` headers. Unknown or invalid ones are
+    /// ignored.
+    pub(crate) fn merge(mut self, headers: &HeaderMap) -> Self {
+        let members = headers
+            .get_all(PRIORITY)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(|m| m.split(';').next().unwrap_or_default().trim());
+
+        for member in members {
+            let (key, value) = member.split_once('=').unwrap_or((member, "?1"));
+            match (key.trim(), value.trim()) {
+                ("u", v) => match v.parse() {
+                    Ok(u) if u <= Self::MAX_URGENCY => self.urgency = u,
+                    _ => (),
+                },
+                ("i", "?1") => self.incremental = true,
+                ("i", "?0") => self.incremental = false,
+                _ => (),
+            }
+        }
+
+        self
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self {
+            urgency: 3,
+            incremental: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    #[test]
+    fn keeps_certificates_with_unreadable_subjects() {
+        let pem = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys/ec.crt");
+        let pem = std::fs::read_to_string(pem).unwrap();
+        let chain = vec![Certificate(x509::from_pem(&pem, "CERTIFICATE").remove(0))];
+
+        let client = ClientCertificate::new(&chain).unwrap();
+        assert_eq!(client.subject.as_deref(), Some("CN=localhost"));
+        assert_eq!(client.chain, chain);
+
+        let chain = vec![Certificate(vec![0x30, 0x00])];
+        let client = ClientCertificate::new(&chain).unwrap();
+        assert_eq!(client.subject, None);
+        assert_eq!(client.chain, chain);
+
+        assert!(ClientCertificate::new(&[]).is_none());
+    }
+
+    fn headers(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for v in values {
+            headers.append(PRIORITY, HeaderValue::from_str(v).unwrap());
+        }
+        headers
+    }
+
+    fn priority(urgency: u8, incremental: bool) -> Priority {
+        Priority {
+            urgency,
+            incremental,
+        }
+    }
+
+    #[test]
+    fn defaults_without_headers() {
+        assert_eq!(
+            Priority::default().merge(&HeaderMap::new()),
+            priority(3, false)
+        );
+    }
+
+    #[test]
+    fn parses_urgency_and_incremental() {
+        let merge = |values: &[&str]| Priority::default().merge(&headers(values));
+
+        assert_eq!(merge(&["u=0"]), priority(0, false));
+        assert_eq!(merge(&["u=5, i"]), priority(5, true));
+        assert_eq!(merge(&["i=?1"]), priority(3, true));
+        assert_eq!(merge(&[" u = 1 ,i=?0 "]), priority(1, false));
+        assert_eq!(merge(&["u=2;a=b, i;x"]), priority(2, true));
+        assert_eq!(merge(&["u=1", "u=6"]), priority(6, false));
+        assert_eq!(merge(&["u=1, u=4"]), priority(4, false));
+    }
+
+    #[test]
+    fn ignores_unknown_and_invalid_members() {
+        let merge = |values: &[&str]| Priority::default().merge(&headers(values));
+
+        for value in [
+            "u=8", "u=-1", "u=high", "U=1", "i=1", "i=?2", "x=1", "", ",",
+        ] {
+            assert_eq!(merge(&[value]), priority(3, false), "{}", value);
+        }
+    }
+
+    #[test]
+    fn responses_override_requests() {
+        let requested = Priority::default().merge(&headers(&["u=1, i"]));
+
+        assert_eq!(requested.merge(&headers(&["u=6"])), priority(6, true));
+        assert_eq!(requested.merge(&headers(&["i=?0"])), priority(1, false));
+        assert_eq!(requested.merge(&HeaderMap::new()), requested);
+    }
+}
@@ -0,0 +1,637 @@
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use http::header::CONTENT_TYPE;
+use http::{Method, Request, StatusCode};
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::Body;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use ring::digest::{digest, Algorithm, SHA1_FOR_LEGACY_USE_ONLY, SHA256};
+use rustls::{Certificate, ClientConfig, RootCertStore};
+use tokio::time::timeout;
+use tracing::{error, warn};
+
+use crate::der::{self, Reader};
+use crate::tls::CertResolver;
+use crate::x509;
+
+const SHA1: &[u64] = &[1, 3, 14, 3, 2, 26];
+const SHA_256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+const OCSP_BASIC: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1, 1];
+
+/// Time to wait for the response of the responder, including its body.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest response accepted from the responder. Responses about a single certificate take a few
+/// kilobytes at most.
+const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("HTTP error: {0}")]
+    Http(#[from] hyper::Error),
+
+    #[error("HTTP semantics error: {0}")]
+    HttpSemantics(#[from] http::Error),
+
+    #[error("Invalid URL: {0}")]
+    Uri(#[from] http::uri::InvalidUri),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("OCSP responder responded {0}")]
+    Status(StatusCode),
+
+    #[error("OCSP responder did not respond in time")]
+    Timeout,
+
+    #[error("OCSP response is larger than {} bytes", MAX_RESPONSE_SIZE)]
+    TooLarge,
+
+    #[error("No OCSP responder URL in the certificate")]
+    NoResponder,
+
+    #[error("No issuer certificate in the chain to identify the certificate by")]
+    NoIssuer,
+
+    #[error("Invalid certificate")]
+    InvalidCertificate,
+
+    #[error("Invalid OCSP response")]
+    Invalid,
+
+    #[error("OCSP responder returned the status {0}")]
+    Unsuccessful(u8),
+
+    #[error("No status of the certificate in the OCSP response")]
+    NoStatus,
+
+    #[error("The certificate is {0} according to the OCSP response")]
+    NotGood(&'static str),
+
+    #[error("The OCSP response has passed its nextUpdate")]
+    Expired,
+}
+
+/// Where to obtain OCSP responses from.
+#[derive(Clone, Debug)]
+pub enum Source {
+    /// DER file of a response, such as written by `openssl ocsp -respout`. The certificate chain
+    /// must contain the issuer, which the response identifies the certificate by.
+    File(PathBuf),
+
+    /// The responder in the certificate, or the one at the URL instead, such as a local stand-in.
+    Responder(Option<String>),
+}
+
+/// Fields of an OCSP response (RFC 6960) that this crate looks into.
+pub struct Response {
+    pub der: Vec<u8>,
+    pub this_update: SystemTime,
+    pub next_update: Option<SystemTime>,
+}
+
+impl Response {
+    /// Parses the response, which must be successful, current, and say the end-entity certificate
+    /// of the chain is good. The certificate is identified by its serial number and the hashes of
+    /// the name and the key of its issuer, the next in the chain. The signature is not verified,
+    /// as clients do.
+    pub fn parse(der: Vec<u8>, chain: &[Certificate]) -> Result<Self, Error> {
+        let (certificate, issuer) = certificate_and_issuer(chain)?;
+        let mut response = Reader::new(&der)
+            .expect(der::SEQUENCE)
+            .ok_or(Error::Invalid)?
+            .reader();
+
+        match response.expect(der::ENUMERATED).map(|e| e.content) {
+            Some([0]) => (),
+            Some([status]) => return Err(Error::Unsuccessful(*status)),
+            _ => return Err(Error::Invalid),
+        }
+
+        let single = response
+            .expect(der::context(0))
+            .and_then(|b| single_responses(b.content))
+            .ok_or(Error::Invalid)?
+            .into_iter()
+            .find(|r| r.identifies(&certificate, &issuer))
+            .ok_or(Error::NoStatus)?;
+
+        match single.status {
+            s if s == der::context_primitive(0) => (),
+            s if s == der::context(1) => return Err(Error::NotGood("revoked")),
+            _ => return Err(Error::NotGood("unknown")),
+        }
+
+        if single.next_update.is_some_and(|n| n <= SystemTime::now()) {
+            return Err(Error::Expired);
+        }
+
+        Ok(Self {
+            this_update: single.this_update,
+            next_update: single.next_update,
+            der,
+        })
+    }
+}
+
+/// Parses the end-entity certificate of the chain and its issuer, which identify it in OCSP.
+fn certificate_and_issuer(
+    chain: &[Certificate],
+) -> Result<(x509::Certificate<'_>, x509::Certificate<'_>), Error> {
+    let certificate = chain.first().ok_or(Error::InvalidCertificate)?;
+    let issuer = chain.get(1).ok_or(Error::NoIssuer)?;
+
+    match (
+        x509::Certificate::parse(&certificate.0),
+        x509::Certificate::parse(&issuer.0),
+    ) {
+        (Some(certificate), Some(issuer)) => Ok((certificate, issuer)),
+        _ => Err(Error::InvalidCertificate),
+    }
+}
+
+struct SingleResponse<'a> {
+    /// Whole encoding of the OID of the algorithm the issuer name and key are hashed with.
+    hash_algorithm: &'a [u8],
+    issuer_name_hash: &'a [u8],
+    issuer_key_hash: &'a [u8],
+    serial: &'a [u8],
+
+    /// Tag of the certStatus, which tells good, revoked or unknown.
+    status: u8,
+    this_update: SystemTime,
+    next_update: Option<SystemTime>,
+}
+
+impl SingleResponse<'_> {
+    /// Whether this is the status of the certificate, as serial numbers are only unique per
+    /// issuer.
+    fn identifies(&self, certificate: &x509::Certificate, issuer: &x509::Certificate) -> bool {
+        let algorithm: &Algorithm = match self.hash_algorithm {
+            a if a == der::oid(SHA1) => &SHA1_FOR_LEGACY_USE_ONLY,
+            a if a == der::oid(SHA_256) => &SHA256,
+            _ => return false,
+        };
+
+        self.serial == certificate.serial
+            && self.issuer_name_hash == digest(algorithm, issuer.subject).as_ref()
+            && self.issuer_key_hash == digest(algorithm, issuer.public_key).as_ref()
+    }
+}
+
+fn single_responses(response_bytes: &[u8]) -> Option<Vec<SingleResponse<'_>>> {
+    let mut response_bytes = Reader::new(response_bytes).expect(der::SEQUENCE)?.reader();
+    if response_bytes.expect(der::OID)?.raw != der::oid(OCSP_BASIC) {
+        return None;
+    }
+
+    let basic = response_bytes.expect(der::OCTET_STRING)?;
+    let mut basic = Reader::new(basic.content).expect(der::SEQUENCE)?.reader();
+    let mut data = basic.expect(der::SEQUENCE)?.reader();
+
+    data.optional(der::context(0));
+    data.optional(der::context(1))
+        .or_else(|| data.optional(der::context(2)))?;
+    data.expect(der::GENERALIZED_TIME)?;
+
+    data.expect(der::SEQUENCE)?
+        .reader()
+        .map(|single| {
+            let mut single = single.reader();
+            let mut cert_id = single.expect(der::SEQUENCE)?.reader();
+            let hash_algorithm = cert_id
+                .expect(der::SEQUENCE)?
+                .reader()
+                .expect(der::OID)?
+                .raw;
+            let issuer_name_hash = cert_id.expect(der::OCTET_STRING)?.content;
+            let issuer_key_hash = cert_id.expect(der::OCTET_STRING)?.content;
+            let serial = cert_id.expect(der::INTEGER)?.content;
+            let status = single.next()?.tag;
+            let this_update = single.expect(der::GENERALIZED_TIME)?.time()?;
+            let next_update = match single.optional(der::context(0)) {
+                Some(n) => Some(n.reader().expect(der::GENERALIZED_TIME)?.time()?),
+                None => None,
+            };
+
+            Some(SingleResponse {
+                hash_algorithm,
+                issuer_name_hash,
+                issuer_key_hash,
+                serial,
+                status,
+                this_update,
+                next_update,
+            })
+        })
+        .collect()
+}
+
+/// Builds a request for the status of the certificate, identified with SHA-1 as responders
+/// commonly expect.
+fn request(certificate: &x509::Certificate, issuer: &x509::Certificate) -> Vec<u8> {
+    let sha1 = |data: &[u8]| der::octet_string(digest(&SHA1_FOR_LEGACY_USE_ONLY, data).as_ref());
+    let cert_id = der::sequence(&[
+        der::sequence(&[der::oid(SHA1), der::tlv(der::NULL, &[])]),
+        sha1(issuer.subject),
+        sha1(issuer.public_key),
+        der::tlv(der::INTEGER, certificate.serial),
+    ]);
+
+    der::sequence(&[der::sequence(&[der::sequence(&[der::sequence(&[
+        cert_id,
+    ])])])])
+}
+
+/// Source of responses, with the client to fetch them by.
+enum Fetcher {
+    File(PathBuf),
+    Responder {
+        url: Option<String>,
+        http: hyper::Client<HttpsConnector<HttpConnector>>,
+    },
+}
+
+/// Sends the request to the responder and reads the response, up to [`MAX_RESPONSE_SIZE`].
+async fn fetch(
+    http: &hyper::Client<HttpsConnector<HttpConnector>>,
+    request: Request<Body>,
+) -> Result<Vec<u8>, Error> {
+    let response = http.request(request).await?;
+    if !response.status().is_success() {
+        return Err(Error::Status(response.status()));
+    }
+
+    let mut body = response.into_body();
+    let mut der = Vec::new();
+    while let Some(chunk) = body.data().await {
+        der.extend_from_slice(&chunk?);
+        if der.len() > MAX_RESPONSE_SIZE {
+            return Err(Error::TooLarge);
+        }
+    }
+
+    Ok(der)
+}
+
+/// Keeps an OCSP response stapled to the certificate of a [`CertResolver`], in handshakes of both
+/// endpoints.
+pub struct Stapler {
+    fetcher: Fetcher,
+    warn_before: Duration,
+
+    /// Certificate chain the stapled response is for, and its nextUpdate.
+    stapled: Mutex<Option<(Vec<Certificate>, Option<SystemTime>)>>,
+}
+
+impl Stapler {
+    /// Period before the nextUpdate of the stapled response to start warning at by default.
+    pub const DEFAULT_WARN_BEFORE: Duration = Duration::from_secs(86400);
+
+    pub fn new(source: Source, warn_before: Duration) -> Result<Self, Error> {
+        let fetcher = match source {
+            Source::File(path) => Fetcher::File(path),
+            Source::Responder(url) => {
+                let mut roots = RootCertStore::empty();
+                for cert in rustls_native_certs::load_native_certs()? {
+                    // Ignores certificates of the system that webpki cannot parse.
+                    let _ = roots.add(&Certificate(cert.0));
+                }
+
+                let tls = ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+
+                let http = hyper::Client::builder().build(
+                    HttpsConnectorBuilder::new()
+                        .with_tls_config(tls)
+                        .https_or_http()
+                        .enable_http1()
+                        .build(),
+                );
+
+                Fetcher::Responder { url, http }
+            }
+        };
+
+        Ok(Self {
+            fetcher,
+            warn_before,
+            stapled: Mutex::new(None),
+        })
+    }
+
+    /// Obtains a response for the current certificate of the resolver and staples it. On errors
+    /// the stapled response is kept until its nextUpdate.
+    pub async fn refresh(&self, resolver: &CertResolver) -> Result<(), Error> {
+        let chain = resolver.certificate_chain();
+        let result = self.obtain(&chain).await;
+
+        if let Ok(response) = &result {
+            if resolver.staple(&chain, Some(response.der.clone())) {
+                *self.stapled.lock().unwrap() = Some((chain.clone(), response.next_update));
+            }
+        }
+
+        self.monitor(resolver, &chain);
+        result.map(|_| ())
+    }
+
+    async fn obtain(&self, chain: &[Certificate]) -> Result<Response, Error> {
+        let (certificate, issuer) = certificate_and_issuer(chain)?;
+
+        let der = match &self.fetcher {
+            Fetcher::File(path) => tokio::fs::read(path).await?,
+            Fetcher::Responder { url, http } => {
+                let url = match url {
+                    Some(u) => u.as_str(),
+                    None => certificate.ocsp_responder.ok_or(Error::NoResponder)?,
+                };
+
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(url.parse::<http::Uri>()?)
+                    .header(CONTENT_TYPE, "application/ocsp-request")
+                    .body(Body::from(request(&certificate, &issuer)))?;
+
+                timeout(FETCH_TIMEOUT, fetch(http, request))
+                    .await
+                    .map_err(|_| Error::Timeout)??
+            }
+        };
+
+        Response::parse(der, chain)
+    }
+
+    /// Logs when the stapled response is close to its nextUpdate, and stops stapling it after.
+    fn monitor(&self, resolver: &CertResolver, chain: &[Certificate]) {
+        let mut stapled = self.stapled.lock().unwrap();
+        let next_update = match &*stapled {
+            Some((c, Some(next_update))) if c == chain => *next_update,
+            _ => return,
+        };
+
+        match next_update.duration_since(SystemTime::now()) {
+            Err(_) => {
+                resolver.staple(chain, None);
+                *stapled = None;
+                error!("The stapled OCSP response has passed its nextUpdate, so it is no longer stapled.");
+            }
+            Ok(left) if left < self.warn_before => warn!(
+                "The stapled OCSP response reaches its nextUpdate in {} minutes.",
+                left.as_secs() / 60
+            ),
+            Ok(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::fs;
+    use std::path::Path;
+
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response as HttpResponse, Server};
+
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// Certificates from the key fixtures, such as `rsa.crt` issued by `ec.crt`, as the responses
+    /// made by `openssl ocsp` say.
+    fn chain(names: &[&str]) -> Vec<Certificate> {
+        names
+            .iter()
+            .map(|n| {
+                let pem = fs::read_to_string(fixture("keys").join(n)).unwrap();
+                Certificate(x509::from_pem(&pem, "CERTIFICATE").remove(0))
+            })
+            .collect()
+    }
+
+    fn response(name: &str) -> Vec<u8> {
+        fs::read(fixture("ocsp").join(name)).unwrap()
+    }
+
+    /// Builds an unsigned response for the certificate issued by the issuer.
+    fn basic(
+        oid: &[u64],
+        hash: &'static Algorithm,
+        status: Vec<u8>,
+        next_update: &[u8],
+    ) -> Vec<u8> {
+        let chain = chain(&["rsa.crt", "ec.crt"]);
+        let (certificate, issuer) = certificate_and_issuer(&chain).unwrap();
+
+        let cert_id = der::sequence(&[
+            der::sequence(&[der::oid(oid), der::tlv(der::NULL, &[])]),
+            der::octet_string(digest(hash, issuer.subject).as_ref()),
+            der::octet_string(digest(hash, issuer.public_key).as_ref()),
+            der::tlv(der::INTEGER, certificate.serial),
+        ]);
+        let single = der::sequence(&[
+            cert_id,
+            status,
+            der::tlv(der::GENERALIZED_TIME, b"20260101000000Z"),
+            der::tlv(
+                der::context(0),
+                &der::tlv(der::GENERALIZED_TIME, next_update),
+            ),
+        ]);
+        let data = der::sequence(&[
+            der::tlv(der::context(2), &der::octet_string(&[0; 20])),
+            der::tlv(der::GENERALIZED_TIME, b"20260101000000Z"),
+            der::sequence(&[single]),
+        ]);
+        let basic = der::sequence(&[
+            data,
+            der::sequence(&[der::oid(&[1, 2, 840, 10045, 4, 3, 2])]),
+            der::bit_string(&[]),
+        ]);
+
+        der::sequence(&[
+            der::tlv(der::ENUMERATED, &[0]),
+            der::tlv(
+                der::context(0),
+                &der::sequence(&[der::oid(OCSP_BASIC), der::octet_string(&basic)]),
+            ),
+        ])
+    }
+
+    fn good() -> Vec<u8> {
+        der::tlv(der::context_primitive(0), &[])
+    }
+
+    #[test]
+    fn encodes_the_cert_id_as_openssl_does() {
+        let chain = chain(&["rsa.crt", "ec.crt"]);
+        let (certificate, issuer) = certificate_and_issuer(&chain).unwrap();
+
+        assert_eq!(request(&certificate, &issuer), response("request.der"));
+    }
+
+    #[test]
+    fn reads_single_responses() {
+        let der = response("good.der");
+        let mut response = Reader::new(&der).expect(der::SEQUENCE).unwrap().reader();
+        response.expect(der::ENUMERATED).unwrap();
+        let bytes = response.expect(der::context(0)).unwrap();
+        let singles = single_responses(bytes.content).unwrap();
+
+        let chain = chain(&["rsa.crt", "ec.crt"]);
+        let (certificate, issuer) = certificate_and_issuer(&chain).unwrap();
+        assert_eq!(singles.len(), 1);
+        assert_eq!(singles[0].hash_algorithm, der::oid(SHA1));
+        assert_eq!(singles[0].serial, certificate.serial);
+        assert_eq!(singles[0].status, der::context_primitive(0));
+        assert!(singles[0].identifies(&certificate, &issuer));
+        assert!(singles[0].next_update.is_some());
+    }
+
+    #[test]
+    fn parses_responses_of_the_certificate() {
+        let parse = |name: &str, names: &[&str]| Response::parse(response(name), &chain(names));
+        let issued = &["rsa.crt", "ec.crt"];
+
+        let good = parse("good.der", issued).unwrap();
+        assert!(good.this_update < SystemTime::now());
+        assert!(good.next_update.unwrap() > SystemTime::now());
+
+        assert!(matches!(
+            parse("revoked.der", issued),
+            Err(Error::NotGood("revoked"))
+        ));
+        assert!(matches!(
+            parse("unknown.der", issued),
+            Err(Error::NotGood("unknown"))
+        ));
+
+        // Another serial number, and the same serial number from another issuer.
+        assert!(matches!(
+            parse("good.der", &["ec.crt", "ec.crt"]),
+            Err(Error::NoStatus)
+        ));
+        assert!(matches!(
+            parse("good.der", &["rsa.crt", "rsa.crt"]),
+            Err(Error::NoStatus)
+        ));
+        assert!(matches!(
+            parse("good.der", &["rsa.crt"]),
+            Err(Error::NoIssuer)
+        ));
+    }
+
+    #[test]
+    fn rejects_expired_and_unsuccessful_responses() {
+        let chain = chain(&["rsa.crt", "ec.crt"]);
+        let parse = |der: Vec<u8>| Response::parse(der, &chain);
+
+        assert!(parse(basic(
+            SHA1,
+            &SHA1_FOR_LEGACY_USE_ONLY,
+            good(),
+            b"21000101000000Z"
+        ))
+        .is_ok());
+        assert!(parse(basic(SHA_256, &SHA256, good(), b"21000101000000Z")).is_ok());
+        assert!(matches!(
+            parse(basic(SHA_256, &SHA256, good(), b"20260102000000Z")),
+            Err(Error::Expired)
+        ));
+
+        let unauthorized = der::sequence(&[der::tlv(der::ENUMERATED, &[6])]);
+        assert!(matches!(parse(unauthorized), Err(Error::Unsuccessful(6))));
+        assert!(matches!(parse(vec![0x30]), Err(Error::Invalid)));
+    }
+
+    #[tokio::test]
+    async fn staples_the_response_of_the_responder() {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                let response = match body == response("request.der") {
+                    true => HttpResponse::new(Body::from(response("good.der"))),
+                    false => HttpResponse::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::empty())
+                        .unwrap(),
+                };
+
+                Ok::<_, Infallible>(response)
+            }))
+        }));
+        let url = format!("http://{}/", server.local_addr());
+        tokio::spawn(server);
+
+        let dir = std::env::temp_dir().join(format!("h123-ocsp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let pem = ["rsa.crt", "ec.crt"]
+            .iter()
+            .map(|n| fs::read_to_string(fixture("keys").join(n)).unwrap())
+            .collect::<String>();
+        fs::write(dir.join("cert.pem"), pem).unwrap();
+        let resolver = CertResolver::load(dir.join("cert.pem"), fixture("keys/rsa.pem")).unwrap();
+
+        let stapler =
+            Stapler::new(Source::Responder(Some(url)), Stapler::DEFAULT_WARN_BEFORE).unwrap();
+        stapler.refresh(&resolver).await.unwrap();
+
+        let stapled = stapler.stapled.lock().unwrap().clone().unwrap();
+        assert_eq!(stapled.0, resolver.certificate_chain());
+        assert!(stapled.1.unwrap() > SystemTime::now());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_oversized_responses() {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let size = match request.uri().path() {
+                    "/large" => MAX_RESPONSE_SIZE + 1,
+                    _ => MAX_RESPONSE_SIZE,
+                };
+
+                Ok::<_, Infallible>(HttpResponse::new(Body::from(vec![0; size])))
+            }))
+        }));
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let http = hyper::Client::builder().build(
+            HttpsConnectorBuilder::new()
+                .with_native_roots()
+                .https_or_http()
+                .enable_http1()
+                .build(),
+        );
+        let request = |path: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("http://{}{}", addr, path))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(
+            fetch(&http, request("/")).await.unwrap().len(),
+            MAX_RESPONSE_SIZE
+        );
+        assert!(matches!(
+            fetch(&http, request("/large")).await,
+            Err(Error::TooLarge)
+        ));
+    }
+}
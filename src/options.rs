@@ -0,0 +1,389 @@
+use std::io;
+use std::net::Ipv6Addr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use http::HeaderValue;
+
+/// Options to tune the behaviour of a [`Server`](crate::Server).
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    pub timeouts: Timeouts,
+    pub limits: Limits,
+    pub transport: Transport,
+    pub early_data: EarlyDataPolicy,
+    pub address_validation: AddressValidation,
+    pub alt_svc: AltSvc,
+
+    /// Number of sockets to accept TCP connections and QUIC packets on, each with its own accept
+    /// loop. More than one share the port by `SO_REUSEPORT`, which is only available on Unix.
+    ///
+    /// The kernel routes QUIC packets by their addresses, not by their connection IDs, so
+    /// connections cannot migrate to other client addresses with more than one.
+    pub acceptors: usize,
+
+    /// Directory to write a qlog trace of each QUIC connection into, in JSON-SEQ.
+    /// quinn does not expose packets and frames, so only HTTP/3 events and events derived from
+    /// the connection statistics are recorded. Files are named after the original destination
+    /// connection ID.
+    pub qlog_dir: Option<PathBuf>,
+
+    /// File to append TLS secrets of both endpoints to, in the NSS key log format.
+    /// Anyone who can read the file can decrypt the traffic, so this is for debugging only.
+    pub key_log: Option<PathBuf>,
+}
+
+/// Options that a [`Server`](crate::Server) cannot be built with.
+#[derive(Debug, thiserror::Error)]
+pub enum InvalidOptions {
+    #[error("Idle timeout too long for QUIC: {0:?}")]
+    IdleTimeout(Duration),
+
+    #[error("Invalid Alt-Svc host: {0}")]
+    AltSvcHost(String),
+
+    #[error("Invalid Alt-Svc protocol ID: {0}")]
+    AltSvcProtocol(String),
+
+    #[error("Failed to open the TLS key log file {}: {1}", .0.display())]
+    KeyLog(PathBuf, #[source] io::Error),
+}
+
+/// Timeouts applied to connections and requests. `None` disables the timeout.
+#[derive(Clone, Debug)]
+pub struct Timeouts {
+    /// Maximum duration of a TLS handshake on the HTTP/1.1 and HTTP/2 endpoint.
+    pub handshake: Option<Duration>,
+
+    /// Maximum duration to receive the whole header of a HTTP/1.1 request.
+    pub header_read: Option<Duration>,
+
+    /// Maximum duration a connection may stay without requests in flight and without any reads or
+    /// writes. This is also used as the idle timeout of QUIC connections. Zero disables it, as in
+    /// QUIC.
+    pub idle: Option<Duration>,
+
+    /// Maximum duration to receive the whole body of a request.
+    pub request_body: Option<Duration>,
+
+    /// Maximum duration for the service to produce a response.
+    pub response: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            handshake: Some(Duration::from_secs(10)),
+            header_read: Some(Duration::from_secs(30)),
+            idle: Some(Duration::from_secs(60)),
+            request_body: Some(Duration::from_secs(60)),
+            response: None,
+        }
+    }
+}
+
+/// Concurrency limits of connections and streams. `None` leaves the default of the underlying
+/// protocol implementation.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// Maximum number of concurrent TCP connections. Connections over the limit are closed
+    /// right after being accepted.
+    pub tcp_connections: Option<usize>,
+
+    /// Maximum number of concurrent QUIC connections. Connections over the limit are refused
+    /// with `CONNECTION_REFUSED`. Applied to each socket with more than one
+    /// [acceptor](Options::acceptors).
+    pub quic_connections: Option<u32>,
+
+    /// Maximum number of concurrent streams per HTTP/2 connection. Streams over the limit are
+    /// refused with `REFUSED_STREAM`.
+    pub h2_streams: Option<u32>,
+
+    /// Maximum number of concurrent bidirectional streams per HTTP/3 connection, advertised to the
+    /// client through QUIC flow control.
+    pub h3_streams: Option<u32>,
+}
+
+/// Congestion controller algorithms available for QUIC connections.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum CongestionController {
+    NewReno,
+    #[default]
+    Cubic,
+    Bbr,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown congestion controller: {0}")]
+pub struct UnknownCongestionController(String);
+
+impl FromStr for CongestionController {
+    type Err = UnknownCongestionController;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "newreno" | "new-reno" => Ok(Self::NewReno),
+            "cubic" => Ok(Self::Cubic),
+            "bbr" => Ok(Self::Bbr),
+            _ => Err(UnknownCongestionController(s.to_owned())),
+        }
+    }
+}
+
+/// Transport parameters of QUIC connections. `None` leaves the default of quinn.
+/// The maximum idle timeout is configured through [`Timeouts::idle`].
+#[derive(Clone, Debug, Default)]
+pub struct Transport {
+    pub congestion_controller: CongestionController,
+
+    /// Maximum number of bytes the client may send on a single stream before being acknowledged.
+    pub stream_receive_window: Option<u32>,
+
+    /// Maximum number of bytes the client may send across all streams before being acknowledged.
+    pub receive_window: Option<u32>,
+
+    /// Interval to send keep-alive packets at. `None` disables keep-alive.
+    pub keep_alive_interval: Option<Duration>,
+
+    /// RTT assumed before any samples are taken.
+    pub initial_rtt: Option<Duration>,
+
+    /// Maximum UDP payload size to accept. Path MTU discovery is not available in quinn 0.8,
+    /// so this is the only knob on the packet size for now.
+    pub max_udp_payload_size: Option<u16>,
+}
+
+/// Policy on TLS 1.3 and QUIC early data (0-RTT).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum EarlyDataPolicy {
+    /// Rejects early data, so that every request waits for the handshake to complete.
+    #[default]
+    Disabled,
+
+    /// Accepts early data, but answers `425 Too Early` to requests with unsafe methods in it.
+    SafeMethods,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown early data policy: {0}")]
+pub struct UnknownEarlyDataPolicy(String);
+
+impl FromStr for EarlyDataPolicy {
+    type Err = UnknownEarlyDataPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "disabled" => Ok(Self::Disabled),
+            "safe-methods" => Ok(Self::SafeMethods),
+            _ => Err(UnknownEarlyDataPolicy(s.to_owned())),
+        }
+    }
+}
+
+/// Address validation of QUIC clients, against amplification and spoofed connection floods.
+#[derive(Clone, Debug, Default)]
+pub struct AddressValidation {
+    pub retry: RetryPolicy,
+
+    /// Secret to protect Retry tokens with, preferably 64 random bytes.
+    /// Sharing it keeps tokens valid across restarts and across instances sharing a UDP port.
+    /// `None` generates a random one on startup.
+    pub token_key: Option<Vec<u8>>,
+
+    /// Secret to derive stateless reset tokens from, preferably 64 random bytes.
+    /// `None` generates a random one on startup.
+    pub reset_key: Option<Vec<u8>>,
+}
+
+/// When to validate addresses of QUIC clients by sending Retry packets.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RetryPolicy {
+    #[default]
+    Never,
+    Always,
+
+    /// Sends Retry packets while more handshakes than the threshold are in progress.
+    UnderLoad {
+        handshakes: usize,
+    },
+}
+
+impl RetryPolicy {
+    pub const DEFAULT_HANDSHAKES: usize = 100;
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown retry policy: {0}")]
+pub struct UnknownRetryPolicy(String);
+
+impl FromStr for RetryPolicy {
+    type Err = UnknownRetryPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "never" => Ok(Self::Never),
+            "always" => Ok(Self::Always),
+            "under-load" => Ok(Self::UnderLoad {
+                handshakes: Self::DEFAULT_HANDSHAKES,
+            }),
+            _ => Err(UnknownRetryPolicy(s.to_owned())),
+        }
+    }
+}
+
+/// Advertisement of the HTTP/3 endpoint in `Alt-Svc` headers of HTTP/1.1 and HTTP/2 responses.
+#[derive(Clone, Debug)]
+pub struct AltSvc {
+    pub enabled: bool,
+
+    /// ALPN protocol IDs to advertise.
+    pub protocols: Vec<String>,
+
+    /// Alternative host to advertise. `None` advertises the same host.
+    pub host: Option<String>,
+
+    /// Port to advertise. `None` advertises the port the server is bound to.
+    pub port: Option<u16>,
+
+    /// Duration the advertisement is fresh for.
+    pub max_age: Duration,
+}
+
+impl AltSvc {
+    pub(crate) fn header_value(
+        &self,
+        bound_port: u16,
+    ) -> Result<Option<HeaderValue>, InvalidOptions> {
+        if !self.enabled || self.protocols.is_empty() {
+            return Ok(None);
+        }
+
+        let host = match self.host.as_deref() {
+            None => String::new(),
+            Some(host) => {
+                let literal = host.strip_prefix('[').and_then(|h| h.strip_suffix(']'));
+                match literal.unwrap_or(host).parse::<Ipv6Addr>() {
+                    Ok(ip) => format!("[{}]", ip),
+                    Err(_) if is_reg_name(host) => host.to_owned(),
+                    Err(_) => return Err(InvalidOptions::AltSvcHost(host.to_owned())),
+                }
+            }
+        };
+
+        let authority = format!("{}:{}", host, self.port.unwrap_or(bound_port));
+        let value = self
+            .protocols
+            .iter()
+            .map(|p| match is_token(p) {
+                true => Ok(format!(
+                    "{}=\"{}\"; ma={}",
+                    p,
+                    authority,
+                    self.max_age.as_secs()
+                )),
+                false => Err(InvalidOptions::AltSvcProtocol(p.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .join(", ");
+
+        Ok(Some(HeaderValue::try_from(value).expect("validated above")))
+    }
+}
+
+/// Whether the host is a DNS name or an IPv4 address, which go into the header as they are.
+fn is_reg_name(host: &str) -> bool {
+    !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._~".contains(&b))
+}
+
+/// Whether the ALPN protocol ID is a token (RFC 9110, section 5.6.2), as IDs in the header are
+/// not percent-encoded here.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$&'*+-.^_`|~".contains(&b))
+}
+
+impl Default for AltSvc {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            protocols: vec!["h3".to_owned(), "h3-29".to_owned()],
+            host: None,
+            port: None,
+            max_age: Duration::from_secs(86400),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alt_svc(host: Option<&str>) -> AltSvc {
+        AltSvc {
+            host: host.map(str::to_owned),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn alt_svc_advertises_the_bound_port() {
+        let value = alt_svc(None).header_value(443).unwrap().unwrap();
+        assert_eq!(value, "h3=\":443\"; ma=86400, h3-29=\":443\"; ma=86400");
+    }
+
+    #[test]
+    fn alt_svc_brackets_ipv6_hosts() {
+        for host in ["2001:db8::1", "[2001:db8::1]"] {
+            let value = AltSvc {
+                protocols: vec!["h3".to_owned()],
+                port: Some(8443),
+                ..alt_svc(Some(host))
+            }
+            .header_value(443)
+            .unwrap()
+            .unwrap();
+
+            assert_eq!(value, "h3=\"[2001:db8::1]:8443\"; ma=86400");
+        }
+    }
+
+    #[test]
+    fn alt_svc_rejects_invalid_hosts_and_protocols() {
+        for host in [
+            "",
+            "a b",
+            "h3.example\"",
+            "user@example.com",
+            "[example.com]",
+        ] {
+            assert!(matches!(
+                alt_svc(Some(host)).header_value(443),
+                Err(InvalidOptions::AltSvcHost(_))
+            ));
+        }
+
+        let protocols = AltSvc {
+            protocols: vec!["h3 draft".to_owned()],
+            ..Default::default()
+        };
+        assert!(matches!(
+            protocols.header_value(443),
+            Err(InvalidOptions::AltSvcProtocol(_))
+        ));
+    }
+
+    #[test]
+    fn disabled_alt_svc_has_no_header() {
+        let disabled = AltSvc {
+            enabled: false,
+            host: Some("not a host".to_owned()),
+            ..Default::default()
+        };
+        assert!(disabled.header_value(443).unwrap().is_none());
+    }
+}
@@ -0,0 +1,435 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use http::header::{HeaderName, CONNECTION, HOST, PROXY_AUTHENTICATE, PROXY_AUTHORIZATION};
+use http::uri::Scheme;
+use http::{HeaderMap, HeaderValue, Method, Request, Response, StatusCode, Uri, Version};
+use hyper::client::HttpConnector;
+use hyper::ext::Protocol;
+use hyper::{Body, Client};
+use ring::constant_time::verify_slices_are_equal;
+use tokio::io::copy_bidirectional;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{error, info};
+
+use crate::h12::Activity;
+use crate::proxy::{status, ForwardProxy};
+use crate::shutdown::spawn_upgraded;
+
+const DEFAULT_HTTP_PORT: u16 = 80;
+
+/// Maximum duration to connect to a target, for tunnels and forwarded requests alike.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum duration for a target to answer a forwarded request with the response header.
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+pub(crate) struct Forwarder {
+    proxy: ForwardProxy,
+    client: Client<HttpConnector>,
+}
+
+impl Forwarder {
+    pub(crate) fn new(proxy: ForwardProxy) -> Self {
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(Some(CONNECT_TIMEOUT));
+
+        Self {
+            proxy,
+            client: Client::builder().build(connector),
+        }
+    }
+
+    /// Whether the request is a `CONNECT` tunnel or a HTTP/1.1 request in absolute form for plain
+    /// HTTP. Extended CONNECT requests are left to the other handlers, and `https` requests in
+    /// absolute form to the service, as they target this server (RFC 9112, section 3.2.2).
+    pub(crate) fn is_proxy_request(request: &Request<Body>) -> bool {
+        match *request.method() {
+            Method::CONNECT => request.extensions().get::<Protocol>().is_none(),
+            _ => {
+                request.version() <= Version::HTTP_11
+                    && request.uri().scheme() == Some(&Scheme::HTTP)
+            }
+        }
+    }
+
+    pub(crate) async fn serve(
+        &self,
+        request: Request<Body>,
+    ) -> Result<Response<Body>, http::Error> {
+        match *request.method() {
+            Method::CONNECT => match self.connect(&request).await {
+                Ok((stream, target)) => Ok(tunnel(request, stream, target)),
+                Err(response) => Ok(response.map(|_| Body::empty())),
+            },
+            _ => self.forward(request).await,
+        }
+    }
+
+    /// Authorizes a `CONNECT` request and connects to its target, or returns the response to
+    /// refuse it with.
+    pub(crate) async fn connect<T>(
+        &self,
+        request: &Request<T>,
+    ) -> Result<(TcpStream, String), Response<()>> {
+        self.authorize(request)?;
+
+        let (host, port) = match request.uri().authority() {
+            Some(a) => match a.port_u16() {
+                Some(p) => (
+                    a.host()
+                        .trim_start_matches('[')
+                        .trim_end_matches(']')
+                        .to_owned(),
+                    p,
+                ),
+                None => return Err(refusal(StatusCode::BAD_REQUEST)),
+            },
+            None => return Err(refusal(StatusCode::BAD_REQUEST)),
+        };
+
+        let address = self.resolve(&host, port).await?;
+        match timeout(CONNECT_TIMEOUT, TcpStream::connect(address)).await {
+            Ok(Ok(s)) => Ok((s, format!("{}:{}", host, port))),
+            Ok(Err(e)) => {
+                error!("Failed to connect to {}:{}: {}", host, port, e);
+
+                Err(refusal(StatusCode::BAD_GATEWAY))
+            }
+            Err(_) => {
+                error!("Timed out connecting to {}:{}", host, port);
+
+                Err(refusal(StatusCode::GATEWAY_TIMEOUT))
+            }
+        }
+    }
+
+    fn authorize<T>(&self, request: &Request<T>) -> Result<(), Response<()>> {
+        let credentials = match &self.proxy.credentials {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+
+        let expected = format!("{}:{}", credentials.user, credentials.password);
+        let authorized = request
+            .headers()
+            .get(PROXY_AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Basic "))
+            .and_then(|v| base64::decode(v.trim()).ok())
+            .map(|given| verify_slices_are_equal(&given, expected.as_bytes()).is_ok())
+            .unwrap_or(false);
+
+        if authorized {
+            return Ok(());
+        }
+
+        let mut response = refusal(StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        response.headers_mut().insert(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"h123\""),
+        );
+
+        Err(response)
+    }
+
+    /// Resolves the target to an address the allow-list allows, so that the connection is made
+    /// to the very address checked.
+    async fn resolve(&self, host: &str, port: u16) -> Result<SocketAddr, Response<()>> {
+        let allow_list = &self.proxy.allow_list;
+        if !allow_list.allows(host, port) {
+            return Err(refusal(StatusCode::FORBIDDEN));
+        }
+
+        match allow_list.resolve(host, port).await {
+            Ok(Some(a)) => Ok(a),
+            Ok(None) => Err(refusal(StatusCode::FORBIDDEN)),
+            Err(e) => {
+                error!("Failed to resolve {}:{}: {}", host, port, e);
+
+                Err(refusal(StatusCode::BAD_GATEWAY))
+            }
+        }
+    }
+
+    async fn forward(&self, mut request: Request<Body>) -> Result<Response<Body>, http::Error> {
+        if let Err(response) = self.authorize(&request) {
+            return Ok(response.map(|_| Body::empty()));
+        }
+
+        let (host, port) = match request.uri().host() {
+            Some(h) => (
+                h.trim_start_matches('[').trim_end_matches(']').to_owned(),
+                request.uri().port_u16().unwrap_or(DEFAULT_HTTP_PORT),
+            ),
+            None => return status(StatusCode::BAD_REQUEST),
+        };
+
+        let address = match self.resolve(&host, port).await {
+            Ok(a) => a,
+            Err(response) => return Ok(response.map(|_| Body::empty())),
+        };
+
+        // Sends the request to the resolved address, with the requested authority in Host.
+        if let Some(authority) = request.uri().authority() {
+            let host = HeaderValue::from_str(authority.as_str())?;
+            request.headers_mut().insert(HOST, host);
+        }
+
+        let mut uri = request.uri().clone().into_parts();
+        uri.authority = Some(address.to_string().parse()?);
+        *request.uri_mut() = Uri::from_parts(uri)?;
+
+        strip_hop_by_hop(request.headers_mut());
+
+        match timeout(RESPONSE_TIMEOUT, self.client.request(request)).await {
+            Ok(Ok(mut response)) => {
+                strip_hop_by_hop(response.headers_mut());
+
+                Ok(response)
+            }
+            Ok(Err(e)) => {
+                error!("Failed to forward a request to {}:{}: {}", host, port, e);
+
+                status(StatusCode::BAD_GATEWAY)
+            }
+            Err(_) => {
+                error!("Timed out waiting for {}:{} to respond", host, port);
+
+                status(StatusCode::GATEWAY_TIMEOUT)
+            }
+        }
+    }
+}
+
+/// Answers the `CONNECT` request, then copies between the upgraded connection and the target.
+fn tunnel(mut request: Request<Body>, mut stream: TcpStream, target: String) -> Response<Body> {
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    let active = request.extensions().get::<Activity>().map(Activity::begin);
+    spawn_upgraded(request.extensions().get(), async move {
+        let _active = active;
+        let result = match on_upgrade.await {
+            Ok(mut upgraded) => {
+                info!("Tunnelling to {}", target);
+
+                copy_bidirectional(&mut upgraded, &mut stream)
+                    .await
+                    .map(|_| ())
+            }
+            Err(e) => Err(io::Error::other(e)),
+        };
+
+        if let Err(e) = result {
+            error!("Tunnel to {} failed: {}", target, e);
+        }
+    });
+
+    Response::new(Body::empty())
+}
+
+fn refusal(status: StatusCode) -> Response<()> {
+    let mut response = Response::new(());
+    *response.status_mut() = status;
+    response
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|n| HeaderName::from_bytes(n.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+
+    for name in listed {
+        headers.remove(name);
+    }
+
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use bytes::Bytes;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::proxy::Credentials;
+
+    fn forwarder(allow: &[&str], credentials: Option<&str>) -> Forwarder {
+        Forwarder::new(ForwardProxy {
+            allow_list: allow.iter().map(|d| d.parse().unwrap()).collect(),
+            credentials: credentials.map(|c| c.parse::<Credentials>().unwrap()),
+        })
+    }
+
+    fn request(method: Method, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn https_requests_in_absolute_form_go_to_the_service() {
+        assert!(Forwarder::is_proxy_request(&request(
+            Method::GET,
+            "http://example.com/"
+        )));
+        assert!(Forwarder::is_proxy_request(&request(
+            Method::CONNECT,
+            "example.com:443"
+        )));
+        assert!(!Forwarder::is_proxy_request(&request(
+            Method::GET,
+            "https://example.com/"
+        )));
+        assert!(!Forwarder::is_proxy_request(&request(Method::GET, "/")));
+
+        let mut extended = request(Method::CONNECT, "https://example.com/chat");
+        extended
+            .extensions_mut()
+            .insert(Protocol::from_static("websocket"));
+        assert!(!Forwarder::is_proxy_request(&extended));
+    }
+
+    #[test]
+    fn requires_credentials_when_configured() {
+        let forwarder = forwarder(&["*:*"], Some("user:secret"));
+
+        let refused = forwarder
+            .authorize(&request(Method::CONNECT, "example.com:443"))
+            .unwrap_err();
+        assert_eq!(refused.status(), StatusCode::PROXY_AUTHENTICATION_REQUIRED);
+        assert!(refused.headers().contains_key(PROXY_AUTHENTICATE));
+
+        let mut authorized = request(Method::CONNECT, "example.com:443");
+        authorized.headers_mut().insert(
+            PROXY_AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", base64::encode("user:secret"))).unwrap(),
+        );
+        assert!(forwarder.authorize(&authorized).is_ok());
+    }
+
+    #[tokio::test]
+    async fn refuses_targets_outside_the_allow_list() {
+        let forwarder = forwarder(&["localhost:*"], None);
+
+        for (uri, status) in [
+            ("example.com:443", StatusCode::FORBIDDEN),
+            // Allowed by name, but resolves to a loopback address.
+            ("localhost:443", StatusCode::FORBIDDEN),
+        ] {
+            let refused = forwarder
+                .connect(&request(Method::CONNECT, uri))
+                .await
+                .unwrap_err();
+            assert_eq!(refused.status(), status, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_to_allowed_addresses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let forwarder = forwarder(&[&format!("127.0.0.1:{}", port)], None);
+
+        let (_stream, target) = forwarder
+            .connect(&request(Method::CONNECT, &format!("127.0.0.1:{}", port)))
+            .await
+            .unwrap();
+        assert_eq!(target, format!("127.0.0.1:{}", port));
+        assert!(listener.accept().await.is_ok());
+    }
+
+    /// Starts a target answering with the `Host` header it received.
+    fn serve_host() -> u16 {
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service_fn(|_| async {
+            Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                let host = request.headers().get(HOST).cloned().unwrap();
+                Ok::<_, Infallible>(Response::new(Body::from(host.as_bytes().to_vec())))
+            }))
+        }));
+        let port = server.local_addr().port();
+        tokio::spawn(server);
+
+        port
+    }
+
+    async fn forwarded_host(port: u16, host: Option<&str>) -> Bytes {
+        let forwarder = forwarder(&[&format!("127.0.0.1:{}", port)], None);
+        let mut request = request(Method::GET, &format!("http://127.0.0.1:{}/", port));
+        *request.version_mut() = Version::HTTP_11;
+        if let Some(h) = host {
+            request
+                .headers_mut()
+                .insert(HOST, HeaderValue::from_str(h).unwrap());
+        }
+
+        let response = forwarder.serve(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        hyper::body::to_bytes(response.into_body()).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn forwards_to_the_resolved_address_with_the_requested_host() {
+        let port = serve_host();
+
+        assert_eq!(
+            forwarded_host(port, None).await,
+            format!("127.0.0.1:{}", port)
+        );
+    }
+
+    #[tokio::test]
+    async fn replaces_a_host_mismatching_the_requested_authority() {
+        let port = serve_host();
+
+        // RFC 9112, section 3.2.2: the authority of the absolute form takes precedence.
+        assert_eq!(
+            forwarded_host(port, Some("internal.example")).await,
+            format!("127.0.0.1:{}", port)
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_tunnels_to_unreachable_targets() {
+        // Nothing listens on the port once the listener is dropped.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let forwarder = forwarder(&[&format!("127.0.0.1:{}", port)], None);
+
+        let refused = forwarder
+            .connect(&request(Method::CONNECT, &format!("127.0.0.1:{}", port)))
+            .await
+            .unwrap_err();
+        assert_eq!(refused.status(), StatusCode::BAD_GATEWAY);
+    }
+}
@@ -0,0 +1,259 @@
+pub(crate) mod forward;
+pub(crate) mod udp;
+
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+
+use http::{Response, StatusCode};
+use hyper::Body;
+use tokio::net::lookup_host;
+
+/// A destination a proxy may connect to. `None` matches any host or port.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Destination {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+}
+
+impl Destination {
+    fn matches(&self, host: &str, port: u16) -> bool {
+        self.host
+            .as_deref()
+            .map(|h| h.eq_ignore_ascii_case(host))
+            .unwrap_or(true)
+            && self.port.map(|p| p == port).unwrap_or(true)
+    }
+
+    /// Whether the destination names the address literally.
+    fn names(&self, address: SocketAddr) -> bool {
+        self.host.as_deref().and_then(|h| h.parse::<IpAddr>().ok()) == Some(address.ip())
+            && self.port.map(|p| p == address.port()).unwrap_or(true)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid destination: {0}")]
+pub struct InvalidDestination(String);
+
+impl FromStr for Destination {
+    type Err = InvalidDestination;
+
+    /// Parses `host:port`, where either of them can be `*`. IPv6 addresses are enclosed in
+    /// brackets, e.g. `[::1]:53`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| InvalidDestination(s.to_owned()))?;
+
+        Ok(Self {
+            host: match host.trim_start_matches('[').trim_end_matches(']') {
+                "" => return Err(InvalidDestination(s.to_owned())),
+                "*" => None,
+                h => Some(h.to_owned()),
+            },
+            port: match port {
+                "*" => None,
+                p => Some(p.parse().map_err(|_| InvalidDestination(s.to_owned()))?),
+            },
+        })
+    }
+}
+
+/// Destinations a proxy may connect to. An empty list allows nothing.
+/// Hosts are matched as requested by the client, and then the addresses they resolve to are
+/// checked by [`allows_address`](Self::allows_address).
+#[derive(Clone, Debug, Default)]
+pub struct AllowList {
+    destinations: Vec<Destination>,
+}
+
+impl AllowList {
+    pub fn allows(&self, host: &str, port: u16) -> bool {
+        self.destinations.iter().any(|d| d.matches(host, port))
+    }
+
+    /// Whether the proxy may connect to the address an allowed host resolved to. Loopback,
+    /// private, link-local and other non-global addresses are only allowed by destinations
+    /// naming them literally, so that an allowed name cannot lead into the network of the proxy.
+    pub fn allows_address(&self, address: SocketAddr) -> bool {
+        is_global(address.ip()) || self.destinations.iter().any(|d| d.names(address))
+    }
+
+    /// Resolves the host to the first address the list allows, or `None` if it resolves to none.
+    pub(crate) async fn resolve(&self, host: &str, port: u16) -> io::Result<Option<SocketAddr>> {
+        let mut addresses = lookup_host((host, port)).await?.peekable();
+        if addresses.peek().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no addresses found for the host",
+            ));
+        }
+
+        Ok(addresses.find(|a| self.allows_address(*a)))
+    }
+}
+
+fn is_global(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_global_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_global_v4(ip),
+            None => is_global_v6(ip),
+        },
+    }
+}
+
+fn is_global_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+
+    !(a == 0
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // Shared address space of carrier-grade NATs (RFC 6598).
+        || (a == 100 && (b & 0xc0) == 64))
+}
+
+fn is_global_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // Unique local addresses (RFC 4193).
+        || (first & 0xfe00) == 0xfc00
+        // Link-local unicast addresses.
+        || (first & 0xffc0) == 0xfe80
+        // Documentation prefix (RFC 3849).
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+impl FromIterator<Destination> for AllowList {
+    fn from_iter<T: IntoIterator<Item = Destination>>(iter: T) -> Self {
+        Self {
+            destinations: iter.into_iter().collect(),
+        }
+    }
+}
+
+/// Credentials required from clients of the forward proxy, by `Proxy-Authorization: Basic`.
+#[derive(Clone)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Credentials must be given as user:password.")]
+pub struct InvalidCredentials;
+
+impl FromStr for Credentials {
+    type Err = InvalidCredentials;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (user, password) = s.trim_end().split_once(':').ok_or(InvalidCredentials)?;
+
+        Ok(Self {
+            user: user.to_owned(),
+            password: password.to_owned(),
+        })
+    }
+}
+
+/// Forward proxy serving `CONNECT` tunnels over HTTP/1.1, HTTP/2 and HTTP/3, and plain HTTP
+/// requests in absolute form over HTTP/1.1.
+#[derive(Clone, Debug, Default)]
+pub struct ForwardProxy {
+    pub allow_list: AllowList,
+
+    /// `None` serves any client without authentication.
+    pub credentials: Option<Credentials>,
+}
+
+fn status(status: StatusCode) -> Result<Response<Body>, http::Error> {
+    Response::builder().status(status).body(Body::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allow_list(destinations: &[&str]) -> AllowList {
+        destinations.iter().map(|d| d.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_destinations() {
+        let destination = "[::1]:53".parse::<Destination>().unwrap();
+        assert_eq!(destination.host.as_deref(), Some("::1"));
+        assert_eq!(destination.port, Some(53));
+
+        let destination = "*:*".parse::<Destination>().unwrap();
+        assert_eq!(destination.host, None);
+        assert_eq!(destination.port, None);
+
+        for invalid in ["example.com", ":53", "example.com:dns", "example.com:65536"] {
+            assert!(invalid.parse::<Destination>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn allows_hosts_as_requested() {
+        let allow_list = allow_list(&["dns.example:53", "*:443"]);
+
+        assert!(allow_list.allows("DNS.example", 53));
+        assert!(!allow_list.allows("dns.example", 54));
+        assert!(allow_list.allows("other.example", 443));
+        assert!(!AllowList::default().allows("dns.example", 53));
+    }
+
+    #[test]
+    fn allows_non_global_addresses_only_by_literal_destinations() {
+        let allow_list = allow_list(&["*:*", "127.0.0.1:53", "[fd00::1]:*"]);
+
+        for address in [
+            "192.0.2.1:53",
+            "[2001:db8::1]:53",
+            "10.1.2.3:53",
+            "[::ffff:127.0.0.1]:54",
+        ] {
+            let address = address.parse().unwrap();
+            assert!(!allow_list.allows_address(address), "{}", address);
+        }
+
+        for address in [
+            "93.184.216.34:53",
+            "[2606:4700::1]:53",
+            "127.0.0.1:53",
+            "[fd00::1]:443",
+        ] {
+            let address = address.parse().unwrap();
+            assert!(allow_list.allows_address(address), "{}", address);
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_only_to_allowed_addresses() {
+        let by_name = allow_list(&["localhost:53"]);
+        assert_eq!(by_name.resolve("localhost", 53).await.unwrap(), None);
+
+        let by_address = allow_list(&["127.0.0.1:53"]);
+        assert_eq!(
+            by_address.resolve("127.0.0.1", 53).await.unwrap(),
+            Some(([127, 0, 0, 1], 53).into())
+        );
+    }
+}
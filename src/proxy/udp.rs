@@ -0,0 +1,372 @@
+use std::io;
+use std::net::SocketAddr;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::{HeaderValue, Method, Request, Response, StatusCode, Version};
+use hyper::ext::Protocol;
+use hyper::upgrade::Upgraded;
+use hyper::Body;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UdpSocket;
+use tracing::{error, info};
+
+use crate::h12::Activity;
+use crate::proxy::{status, AllowList};
+use crate::shutdown::spawn_upgraded;
+
+const PATH_PREFIX: &str = "/.well-known/masque/udp/";
+
+const DATAGRAM_CAPSULE: u64 = 0x00;
+const UDP_PAYLOAD_CONTEXT: u64 = 0x00;
+
+const MAX_UDP_PAYLOAD_SIZE: usize = 65527;
+const MAX_CAPSULE_SIZE: usize = MAX_UDP_PAYLOAD_SIZE + 8;
+
+/// Whether the request opens a UDP tunnel by HTTP/2 extended CONNECT (RFC 9298).
+pub(crate) fn is_connect_udp(request: &Request<Body>) -> bool {
+    request.version() == Version::HTTP_2
+        && request.method() == Method::CONNECT
+        && request
+            .extensions()
+            .get::<Protocol>()
+            .map(|p| p.as_str().eq_ignore_ascii_case("connect-udp"))
+            .unwrap_or(false)
+}
+
+/// Opens a UDP socket to the target, then forwards datagrams in DATAGRAM capsules over the
+/// upgraded stream.
+pub(crate) async fn connect(
+    mut request: Request<Body>,
+    allow_list: &AllowList,
+) -> Result<Response<Body>, http::Error> {
+    let (host, port) = match target_of(&request) {
+        Some(t) => t,
+        None => return status(StatusCode::BAD_REQUEST),
+    };
+
+    if !allow_list.allows(&host, port) {
+        return status(StatusCode::FORBIDDEN);
+    }
+
+    let target = match allow_list.resolve(&host, port).await {
+        Ok(Some(t)) => t,
+        Ok(None) => return status(StatusCode::FORBIDDEN),
+        Err(e) => {
+            error!("Failed to resolve {}:{}: {}", host, port, e);
+
+            return status(StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    let socket = match open(target).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to open a UDP socket to {}:{}: {}", host, port, e);
+
+            return status(StatusCode::BAD_GATEWAY);
+        }
+    };
+
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    let active = request.extensions().get::<Activity>().map(Activity::begin);
+    spawn_upgraded(request.extensions().get(), async move {
+        let _active = active;
+        let result = match on_upgrade.await {
+            Ok(upgraded) => {
+                info!("Proxying UDP to {}:{}", host, port);
+
+                forward(upgraded, socket).await
+            }
+            Err(e) => Err(io::Error::other(e)),
+        };
+
+        if let Err(e) = result {
+            error!("UDP tunnel to {}:{} failed: {}", host, port, e);
+        }
+    });
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("capsule-protocol", HeaderValue::from_static("?1"))
+        .body(Body::empty())
+}
+
+/// Reads the target from the default URI template,
+/// `/.well-known/masque/udp/{target_host}/{target_port}/`.
+fn target_of(request: &Request<Body>) -> Option<(String, u16)> {
+    let mut segments = request
+        .uri()
+        .path()
+        .strip_prefix(PATH_PREFIX)?
+        .trim_end_matches('/')
+        .split('/');
+
+    let host = percent_decode(segments.next()?)?;
+    let port = segments.next()?.parse().ok()?;
+    if host.is_empty() || port == 0 || segments.next().is_some() {
+        return None;
+    }
+
+    Some((host, port))
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next()?, bytes.next()?];
+                // from_str_radix alone would accept a sign, as in `%+1`.
+                if !hex.iter().all(u8::is_ascii_hexdigit) {
+                    return None;
+                }
+
+                decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            b => decoded.push(b),
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+async fn open(target: SocketAddr) -> io::Result<UdpSocket> {
+    let local: SocketAddr = match target {
+        SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+
+    Ok(socket)
+}
+
+async fn forward(upgraded: Upgraded, socket: UdpSocket) -> io::Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(upgraded);
+
+    let upstream = async {
+        let mut buffer = BytesMut::with_capacity(MAX_CAPSULE_SIZE);
+        loop {
+            while let Some((kind, payload)) = decode_capsule(&mut buffer)? {
+                // Capsules of unknown types and datagrams of unknown contexts are dropped.
+                if kind != DATAGRAM_CAPSULE {
+                    continue;
+                }
+
+                if let Some((UDP_PAYLOAD_CONTEXT, n)) = decode_varint(&payload) {
+                    socket.send(&payload[n..]).await?;
+                }
+            }
+
+            if reader.read_buf(&mut buffer).await? == 0 {
+                return Ok(());
+            }
+        }
+    };
+
+    let downstream = async {
+        let mut datagram = vec![0; MAX_UDP_PAYLOAD_SIZE];
+        let mut capsule = BytesMut::with_capacity(MAX_CAPSULE_SIZE);
+        loop {
+            let len = match socket.recv(&mut datagram).await {
+                Ok(len) => len,
+                // ICMP errors from the target must not tear down the tunnel.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(e) => return Err(e),
+            };
+
+            capsule.clear();
+            encode_varint(DATAGRAM_CAPSULE, &mut capsule);
+            encode_varint(len as u64 + 1, &mut capsule);
+            encode_varint(UDP_PAYLOAD_CONTEXT, &mut capsule);
+            capsule.put_slice(&datagram[..len]);
+
+            writer.write_all(&capsule).await?;
+        }
+    };
+
+    tokio::select! {
+        r = upstream => r,
+        r = downstream => r,
+    }
+}
+
+fn decode_capsule(buffer: &mut BytesMut) -> io::Result<Option<(u64, Bytes)>> {
+    let (kind, kind_len) = match decode_varint(buffer) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let (length, length_len) = match decode_varint(&buffer[kind_len..]) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    let length = length as usize;
+    if length > MAX_CAPSULE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "capsule is too large",
+        ));
+    }
+
+    if buffer.len() < kind_len + length_len + length {
+        return Ok(None);
+    }
+
+    buffer.advance(kind_len + length_len);
+
+    Ok(Some((kind, buffer.split_to(length).freeze())))
+}
+
+/// Decodes a variable-length integer of QUIC (RFC 9000), returning it with its length in bytes.
+fn decode_varint(buffer: &[u8]) -> Option<(u64, usize)> {
+    let first = *buffer.first()?;
+    let len = 1 << (first >> 6);
+    if buffer.len() < len {
+        return None;
+    }
+
+    let value = buffer[1..len]
+        .iter()
+        .fold((first & 0x3f) as u64, |v, b| (v << 8) | *b as u64);
+
+    Some((value, len))
+}
+
+fn encode_varint(value: u64, buffer: &mut BytesMut) {
+    match value {
+        0..=0x3f => buffer.put_u8(value as u8),
+        0x40..=0x3fff => buffer.put_u16(value as u16 | 0x4000),
+        0x4000..=0x3fff_ffff => buffer.put_u32(value as u32 | 0x8000_0000),
+        _ => buffer.put_u64(value | 0xc000_0000_0000_0000),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_round_trip() {
+        for (value, len) in [
+            (0, 1),
+            (0x3f, 1),
+            (0x40, 2),
+            (0x3fff, 2),
+            (0x4000, 4),
+            (0x3fff_ffff, 4),
+            (0x4000_0000, 8),
+            ((1 << 62) - 1, 8),
+        ] {
+            let mut buffer = BytesMut::new();
+            encode_varint(value, &mut buffer);
+
+            assert_eq!(buffer.len(), len);
+            assert_eq!(decode_varint(&buffer), Some((value, len)));
+        }
+    }
+
+    #[test]
+    fn decodes_varints_of_rfc_9000() {
+        assert_eq!(decode_varint(&[0x25]), Some((37, 1)));
+        assert_eq!(decode_varint(&[0x7b, 0xbd]), Some((15293, 2)));
+        assert_eq!(
+            decode_varint(&[0x9d, 0x7f, 0x3e, 0x7d]),
+            Some((494878333, 4))
+        );
+    }
+
+    #[test]
+    fn truncated_varints_are_incomplete() {
+        assert_eq!(decode_varint(&[]), None);
+        assert_eq!(decode_varint(&[0x7b]), None);
+        assert_eq!(decode_varint(&[0x9d, 0x7f, 0x3e]), None);
+        assert_eq!(
+            decode_varint(&[0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8]),
+            None
+        );
+    }
+
+    fn capsule(kind: u64, payload: &[u8]) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        encode_varint(kind, &mut buffer);
+        encode_varint(payload.len() as u64, &mut buffer);
+        buffer.put_slice(payload);
+        buffer
+    }
+
+    #[test]
+    fn capsules_round_trip() {
+        let mut buffer = capsule(DATAGRAM_CAPSULE, b"\x00hello");
+        buffer.unsplit(capsule(0x1234, &[0xaa; 100]));
+
+        let (kind, payload) = decode_capsule(&mut buffer).unwrap().unwrap();
+        assert_eq!(kind, DATAGRAM_CAPSULE);
+        assert_eq!(&payload[..], b"\x00hello");
+
+        let (kind, payload) = decode_capsule(&mut buffer).unwrap().unwrap();
+        assert_eq!(kind, 0x1234);
+        assert_eq!(&payload[..], &[0xaa; 100][..]);
+
+        assert!(buffer.is_empty());
+        assert!(decode_capsule(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_capsules_are_left_in_the_buffer() {
+        let whole = capsule(DATAGRAM_CAPSULE, &[0; 200]);
+        for len in 0..whole.len() {
+            let mut buffer = BytesMut::from(&whole[..len]);
+
+            assert!(decode_capsule(&mut buffer).unwrap().is_none());
+            assert_eq!(buffer.len(), len);
+        }
+    }
+
+    #[test]
+    fn oversized_capsules_are_an_error() {
+        let mut buffer = BytesMut::new();
+        encode_varint(DATAGRAM_CAPSULE, &mut buffer);
+        encode_varint(MAX_CAPSULE_SIZE as u64 + 1, &mut buffer);
+
+        assert!(decode_capsule(&mut buffer).is_err());
+    }
+
+    fn request(path: &str) -> Request<Body> {
+        Request::builder().uri(path).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn reads_targets_from_the_uri_template() {
+        assert_eq!(
+            target_of(&request("/.well-known/masque/udp/dns.example/53/")),
+            Some(("dns.example".to_owned(), 53))
+        );
+        assert_eq!(
+            target_of(&request("/.well-known/masque/udp/2001%3Adb8%3A%3A1/443/")),
+            Some(("2001:db8::1".to_owned(), 443))
+        );
+
+        for path in [
+            "/.well-known/masque/udp/dns.example/",
+            "/.well-known/masque/udp/dns.example/0/",
+            "/.well-known/masque/udp//53/",
+            "/.well-known/masque/udp/dns.example/53/extra/",
+            "/.well-known/masque/udp/%zz/53/",
+            "/.well-known/masque/tcp/dns.example/53/",
+        ] {
+            assert_eq!(target_of(&request(path)), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn percent_decoding_requires_two_hex_digits() {
+        assert_eq!(percent_decode("a%2Db%2d").as_deref(), Some("a-b-"));
+        for s in ["%+1", "%-1", "% 1", "%1", "%G0", "%"] {
+            assert_eq!(percent_decode(s), None, "{}", s);
+        }
+    }
+}
@@ -6,7 +6,13 @@ use futures::future::try_join;
 use http::{Request, Response};
 use hyper::service::Service;
 use rustls::ServerConfig;
+use tracing::warn;
 
+use crate::key_log::KeyLogFile;
+use crate::options::{InvalidOptions, Options};
+use crate::proxy::{AllowList, ForwardProxy};
+use crate::shutdown::{Shutdown, ShutdownHandle};
+use crate::websocket::WebSocketHandler;
 use crate::{h12, h3};
 
 #[derive(Debug, thiserror::Error)]
@@ -21,6 +27,7 @@ pub enum JoinError {
 pub struct Server<S, E> {
     h12: h12::Endpoint<S, E>,
     h3: h3::Endpoint<S, E>,
+    shutdown: ShutdownHandle,
 }
 
 impl<S, E> Server<S, E> {
@@ -28,10 +35,81 @@ impl<S, E> Server<S, E> {
     where
         A: Into<SocketAddr> + Copy,
     {
-        Self {
-            h12: h12::Endpoint::new(config, bind_to, Arc::clone(&service)),
-            h3: h3::Endpoint::new(config, bind_to, Arc::clone(&service)),
+        Self::with_options(config, bind_to, service, &Options::default())
+            .expect("the default options are valid")
+    }
+
+    pub fn with_options<A>(
+        config: &ServerConfig,
+        bind_to: A,
+        service: Arc<S>,
+        options: &Options,
+    ) -> Result<Self, InvalidOptions>
+    where
+        A: Into<SocketAddr> + Copy,
+    {
+        let (shutdown, handle) = Shutdown::new();
+        let mut config = config.clone();
+
+        if let Some(path) = &options.key_log {
+            let key_log =
+                KeyLogFile::open(path).map_err(|e| InvalidOptions::KeyLog(path.clone(), e))?;
+
+            warn!(
+                "!!! TLS key logging is enabled: secrets of every connection are written to {}. Anyone who can read the file can decrypt the traffic. Do not enable this in production. !!!",
+                path.display()
+            );
+
+            config.key_log = Arc::new(key_log);
         }
+
+        Ok(Self {
+            h12: h12::Endpoint::new(
+                &config,
+                bind_to,
+                Arc::clone(&service),
+                options,
+                shutdown.clone(),
+            )?,
+            h3: h3::Endpoint::new(&config, bind_to, Arc::clone(&service), options, shutdown)?,
+            shutdown: handle,
+        })
+    }
+
+    /// Serves WebSocket requests with the handler, over HTTP/1.1 and HTTP/2.
+    /// WebSocket over HTTP/3 (RFC 9220) is not supported yet, as h3 does not accept extended
+    /// CONNECT.
+    pub fn websocket(mut self, handler: Arc<dyn WebSocketHandler>) -> Self {
+        self.h12.websocket(handler);
+        self
+    }
+
+    /// Proxies UDP to the allowed destinations by CONNECT-UDP (RFC 9298) over HTTP/2, with
+    /// datagrams carried in capsules. HTTP/3 is not supported yet, as h3 neither accepts extended
+    /// CONNECT nor negotiates HTTP datagrams.
+    pub fn connect_udp(mut self, allow_list: AllowList) -> Self {
+        self.h12.connect_udp(allow_list);
+        self
+    }
+
+    /// Serves as a forward proxy to the allowed destinations, by `CONNECT` over every HTTP
+    /// version, and by absolute-form `http` requests over HTTP/1.1. Other requests go to the
+    /// service.
+    pub fn forward_proxy(mut self, proxy: ForwardProxy) -> Self {
+        self.h3.forward_proxy(proxy.clone());
+        self.h12.forward_proxy(proxy);
+        self
+    }
+
+    /// Answers TLS-ALPN-01 challenges (RFC 8737) on the HTTP/1.1 and HTTP/2 endpoint, with the
+    /// certificates from the resolver of the config, which must be an [`Acme`](crate::acme::Acme).
+    pub fn acme_tls_alpn(mut self) -> Self {
+        self.h12.acme_tls_alpn();
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
     }
 }
 
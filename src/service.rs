@@ -5,7 +5,7 @@ use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use bytes::Bytes;
-use http::header::CONTENT_TYPE;
+use http::header::{HeaderValue, CONTENT_TYPE};
 use http::{Request, Response, StatusCode};
 use hyper::service::Service;
 use tokio::fs::File;
@@ -13,8 +13,14 @@ use tokio::io::AsyncReadExt;
 use tokio::io::BufReader;
 use tracing::info;
 
+use crate::metadata::PreloadLinks;
+
 const INDEX_FILES: &[&str] = &["index.html", "index.htm"];
 
+/// Suffix of a sidecar manifest listing `Link` header values to add to a HTML file, one per line,
+/// e.g. `index.html.preload`. Without the manifest, links are taken from `<link rel=preload>` tags.
+const PRELOAD_MANIFEST_SUFFIX: &str = ".preload";
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("HTTP semantics error: {0}")]
@@ -58,7 +64,7 @@ impl StaticFileService {
         P: AsRef<Path>,
     {
         let path = self.real_path_of(path);
-        if !path.exists() {
+        if !path.exists() || is_preload_manifest(&path) {
             return None;
         }
 
@@ -84,6 +90,7 @@ impl Service<Request<Bytes>> for StaticFileService {
 
     fn call(&mut self, req: Request<Bytes>) -> Self::Future {
         let path = self.find_in_root(PathBuf::from(req.uri().path()));
+        let preload = req.extensions().get::<PreloadLinks>().cloned();
 
         Box::pin(async move {
             let path = match path {
@@ -98,12 +105,33 @@ impl Service<Request<Bytes>> for StaticFileService {
             info!("Real path is {}", path.to_str().unwrap());
 
             let content_type = mime_guess::from_path(path.clone()).first_or_octet_stream();
-            let file = File::open(path).await?;
+            let file = File::open(&path).await?;
             let mut buffer = Vec::<u8>::with_capacity(file.metadata().await?.len() as usize);
             let mut reader = BufReader::new(file);
 
             reader.read_to_end(&mut buffer).await?;
 
+            if let Some(preload) = preload.filter(|_| content_type == mime_guess::mime::TEXT_HTML) {
+                let mut manifest = path.into_os_string();
+                manifest.push(PRELOAD_MANIFEST_SUFFIX);
+
+                let links = match tokio::fs::read_to_string(&manifest).await {
+                    Ok(m) => m
+                        .lines()
+                        .map(str::trim)
+                        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                        .map(str::to_owned)
+                        .collect(),
+                    Err(_) => preload_links(&String::from_utf8_lossy(&buffer)),
+                };
+
+                preload.add(
+                    links
+                        .into_iter()
+                        .filter_map(|l| HeaderValue::try_from(l).ok()),
+                );
+            }
+
             Ok(Response::builder()
                 .status(StatusCode::OK)
                 .header(CONTENT_TYPE, content_type.as_ref())
@@ -112,9 +140,249 @@ impl Service<Request<Bytes>> for StaticFileService {
     }
 }
 
+/// Builds `Link` header values from `<link rel=preload>` tags in the HTML.
+fn preload_links(html: &str) -> Vec<String> {
+    let lower = html.to_ascii_lowercase();
+    let mut links = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find("<link").map(|i| offset + i) {
+        let end = match lower[start..].find('>') {
+            Some(i) => start + i,
+            None => break,
+        };
+
+        offset = end;
+
+        // Skips other tags starting alike, such as `<linkset>`.
+        let name_end = start + "<link".len();
+        if !lower[name_end..].starts_with(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>')
+        {
+            continue;
+        }
+
+        let attributes = attributes_of(&html[name_end..end]);
+        let get = |name: &str| {
+            attributes
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+
+        let preload = get("rel")
+            .map(|r| {
+                r.split_ascii_whitespace()
+                    .any(|t| t.eq_ignore_ascii_case("preload"))
+            })
+            .unwrap_or(false);
+
+        if let (true, Some(href)) = (preload, get("href")) {
+            let mut link = format!("<{}>; rel=preload", encode_uri_reference(href));
+            let mut valid = true;
+            for name in ["as", "type", "crossorigin"] {
+                match get(name).map(|v| (v, quoted_string(v))) {
+                    Some(("", _)) => link.push_str(&format!("; {}", name)),
+                    Some((_, Some(v))) => link.push_str(&format!("; {}={}", name, v)),
+                    // Drops the whole link rather than a parameter it might depend on.
+                    Some((_, None)) => valid = false,
+                    None => (),
+                }
+            }
+
+            if valid {
+                links.push(link);
+            }
+        }
+    }
+
+    links
+}
+
+/// Percent-encodes the characters that cannot appear in a URI-reference (RFC 3986), such as `>`
+/// and spaces, leaving valid percent-encodings as they are.
+fn encode_uri_reference(reference: &str) -> String {
+    let bytes = reference.as_bytes();
+    let mut encoded = String::with_capacity(reference.len());
+    for (i, &b) in bytes.iter().enumerate() {
+        let escape = match b {
+            b'%' => {
+                bytes
+                    .get(i + 1..i + 3)
+                    .map(|h| h.iter().all(u8::is_ascii_hexdigit))
+                    != Some(true)
+            }
+            b if b.is_ascii_alphanumeric() => false,
+            _ => !b"-._~:/?#[]@!$&'()*+,;=".contains(&b),
+        };
+
+        match escape {
+            true => encoded.push_str(&format!("%{:02X}", b)),
+            false => encoded.push(b as char),
+        }
+    }
+
+    encoded
+}
+
+/// Quotes a parameter value of a `Link` header, or returns `None` if it has control characters,
+/// which a quoted-string cannot carry (RFC 9110, section 5.6.4).
+fn quoted_string(value: &str) -> Option<String> {
+    if value.chars().any(|c| c.is_control() && c != '\t') {
+        return None;
+    }
+
+    Some(format!(
+        "\"{}\"",
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    ))
+}
+
+/// Manifests are served as `Link` headers of their HTML files only, not as files of their own.
+fn is_preload_manifest(path: &Path) -> bool {
+    path.as_os_str()
+        .to_string_lossy()
+        .ends_with(PRELOAD_MANIFEST_SUFFIX)
+}
+
+/// Parses attributes of a HTML tag into pairs of lowercased names and values.
+fn attributes_of(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut chars = tag.chars().peekable();
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == '/').is_some() {}
+
+        let name =
+            std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace() && !"=/".contains(*c)))
+                .collect::<String>()
+                .to_ascii_lowercase();
+        if name.is_empty() {
+            break;
+        }
+
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+        let value = match chars.next_if_eq(&'=') {
+            Some(_) => {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+                match chars.next_if(|c| *c == '"' || *c == '\'') {
+                    Some(quote) => chars.by_ref().take_while(|c| *c != quote).collect(),
+                    None => std::iter::from_fn(|| chars.next_if(|c| !c.is_whitespace())).collect(),
+                }
+            }
+            None => String::new(),
+        };
+
+        attributes.push((name, value));
+    }
+
+    attributes
+}
+
 pub async fn call_service<S, E, Req, Res>(service: &Arc<S>, request: Req) -> Result<Res, E>
 where
     S: Service<Req, Response = Res, Error = E> + Clone,
 {
     service.as_ref().clone().call(request).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(attributes: &[(&str, &str)]) -> Vec<(String, String)> {
+        attributes
+            .iter()
+            .map(|(n, v)| (n.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parses_attributes_in_every_syntax() {
+        assert_eq!(
+            attributes_of(r#" REL="preload" href='/a b.css' as=style crossorigin /"#),
+            pairs(&[
+                ("rel", "preload"),
+                ("href", "/a b.css"),
+                ("as", "style"),
+                ("crossorigin", ""),
+            ])
+        );
+        assert_eq!(
+            attributes_of(" href = \"/x.js\"\n\tdefer"),
+            pairs(&[("href", "/x.js"), ("defer", "")])
+        );
+        assert_eq!(attributes_of(""), pairs(&[]));
+        assert_eq!(attributes_of(" / "), pairs(&[]));
+    }
+
+    #[test]
+    fn keeps_values_unterminated_by_quotes() {
+        assert_eq!(
+            attributes_of(r#" href="/a.css"#),
+            pairs(&[("href", "/a.css")])
+        );
+    }
+
+    #[test]
+    fn builds_links_from_preload_tags() {
+        let html = r#"<!doctype html>
+            <html><head>
+            <LINK rel="preload" href="/style.css" as="style">
+            <link rel="stylesheet preload" href="/font.woff2" as="font" type="font/woff2" crossorigin/>
+            <link rel="icon" href="/favicon.ico">
+            <link rel="preload" as="script">
+            <linkset rel="preload" href="/not-a-link">
+            </head></html>"#;
+
+        assert_eq!(
+            preload_links(html),
+            vec![
+                r#"</style.css>; rel=preload; as="style""#,
+                r#"</font.woff2>; rel=preload; as="font"; type="font/woff2"; crossorigin"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_links_from_preload_tags() {
+        let html = "<link rel=preload href='/a b\"<.css?q=%7E&r=%zz' as='st\"y\\le'>
+            <link rel=preload href=\"/b.css\" as=\"style\" type=\"text/css\u{7}\">";
+
+        assert_eq!(
+            preload_links(html),
+            vec![r#"</a%20b%22%3C.css?q=%7E&r=%25zz>; rel=preload; as="st\"y\\le""#]
+        );
+    }
+
+    #[test]
+    fn percent_encodes_uri_references() {
+        assert_eq!(
+            encode_uri_reference("/ok/~a-b_c.d?x=1#f"),
+            "/ok/~a-b_c.d?x=1#f"
+        );
+        assert_eq!(encode_uri_reference("/é <>\"\\"), "/%C3%A9%20%3C%3E%22%5C");
+        assert_eq!(encode_uri_reference("%4"), "%254");
+    }
+
+    #[test]
+    fn does_not_serve_preload_manifests() {
+        let root = std::env::temp_dir().join(format!("h123-preload-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("index.html"), "<html></html>").unwrap();
+        std::fs::write(root.join("index.html.preload"), "</a.css>; rel=preload").unwrap();
+
+        let service = StaticFileService::new(&root);
+        assert_eq!(service.find_in_root("/"), Some(root.join("index.html")));
+        assert_eq!(service.find_in_root("/index.html.preload"), None);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn ignores_unterminated_tags() {
+        assert_eq!(
+            preload_links(r#"<link rel="preload" href="/a.css" as="style""#),
+            Vec::<String>::new()
+        );
+    }
+}
@@ -0,0 +1,131 @@
+use std::future::{pending, Future};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::{sleep_until, Instant};
+
+/// A handle to gracefully shut down a running [`Server`](crate::Server).
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    sender: Arc<watch::Sender<Option<Instant>>>,
+}
+
+impl ShutdownHandle {
+    /// Stops accepting new connections and waits for in-flight requests up to the grace period.
+    /// Connections still open after the grace period are closed.
+    pub fn shutdown(&self, grace: Duration) {
+        self.sender.send_if_modified(|deadline| match deadline {
+            Some(_) => false,
+            None => {
+                *deadline = Some(Instant::now() + grace);
+                true
+            }
+        });
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct Shutdown {
+    receiver: watch::Receiver<Option<Instant>>,
+}
+
+impl Shutdown {
+    pub(crate) fn new() -> (Self, ShutdownHandle) {
+        let (sender, receiver) = watch::channel(None);
+
+        (
+            Self { receiver },
+            ShutdownHandle {
+                sender: Arc::new(sender),
+            },
+        )
+    }
+
+    pub(crate) fn is_requested(&self) -> bool {
+        self.receiver.borrow().is_some()
+    }
+
+    /// Resolves with the deadline once a shutdown is requested.
+    pub(crate) async fn requested(&mut self) -> Instant {
+        loop {
+            if let Some(deadline) = *self.receiver.borrow_and_update() {
+                return deadline;
+            }
+
+            if self.receiver.changed().await.is_err() {
+                // All handles are gone, so no shutdown will ever be requested.
+                return pending().await;
+            }
+        }
+    }
+
+    /// Resolves once the grace period of a requested shutdown has elapsed.
+    pub(crate) async fn deadline(mut self) {
+        sleep_until(self.requested().await).await
+    }
+
+    /// Spawns a task that is cancelled once the grace period of a requested shutdown elapses.
+    pub(crate) fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let deadline = self.clone().deadline();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = future => {},
+                _ = deadline => {},
+            }
+        });
+    }
+}
+
+/// Spawns a task serving a connection upgraded from a request, such as a tunnel or a WebSocket.
+/// It is cancelled with the connections of the endpoint if the request carries its [`Shutdown`].
+pub(crate) fn spawn_upgraded<F>(shutdown: Option<&Shutdown>, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    match shutdown {
+        Some(s) => s.spawn(future),
+        None => {
+            tokio::spawn(future);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::oneshot;
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn upgraded_tasks_are_cancelled_at_the_deadline() {
+        let (shutdown, handle) = Shutdown::new();
+
+        let (held, held_rx) = oneshot::channel::<()>();
+        spawn_upgraded(Some(&shutdown), async move {
+            let _held = held;
+            pending::<()>().await
+        });
+
+        let (finished, finished_rx) = oneshot::channel();
+        spawn_upgraded(None, async move {
+            finished.send(()).unwrap();
+        });
+
+        timeout(Duration::from_secs(5), finished_rx)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // The sender is dropped only when the pending task is cancelled.
+        handle.shutdown(Duration::from_millis(10));
+        assert!(timeout(Duration::from_secs(5), held_rx)
+            .await
+            .unwrap()
+            .is_err());
+    }
+}
@@ -0,0 +1,100 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+const BACKLOG: i32 = 1024;
+
+/// Binds listeners to the address, sharing it by `SO_REUSEPORT` if there are more than one.
+pub(crate) fn bind_tcp(addr: SocketAddr, count: usize) -> io::Result<Vec<TcpListener>> {
+    bind(addr, count, Type::STREAM, Protocol::TCP, |s| {
+        s.listen(BACKLOG)?;
+        Ok(s.into())
+    })
+}
+
+/// Binds sockets to the address, sharing it by `SO_REUSEPORT` if there are more than one.
+pub(crate) fn bind_udp(addr: SocketAddr, count: usize) -> io::Result<Vec<UdpSocket>> {
+    bind(addr, count, Type::DGRAM, Protocol::UDP, |s| Ok(s.into()))
+}
+
+fn bind<T, F>(
+    mut addr: SocketAddr,
+    count: usize,
+    ty: Type,
+    protocol: Protocol,
+    into: F,
+) -> io::Result<Vec<T>>
+where
+    F: Fn(Socket) -> io::Result<T>,
+{
+    let count = count.max(1);
+    let mut sockets = Vec::with_capacity(count);
+    for _ in 0..count {
+        let socket = Socket::new(Domain::for_address(addr), ty, Some(protocol))?;
+        if ty == Type::STREAM {
+            socket.set_reuse_address(true)?;
+        }
+
+        if count > 1 {
+            set_reuse_port(&socket)?;
+        }
+
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+
+        // Binds the rest to the same port, even if the port was chosen by the system.
+        addr = socket.local_addr()?.as_socket().unwrap_or(addr);
+        sockets.push(into(socket)?);
+    }
+
+    Ok(sockets)
+}
+
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+fn set_reuse_port(socket: &Socket) -> io::Result<()> {
+    socket.set_reuse_port(true)
+}
+
+#[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+fn set_reuse_port(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is not available on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpStream;
+
+    use super::*;
+
+    fn loopback() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 0))
+    }
+
+    #[test]
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    fn binds_acceptors_to_the_same_port() {
+        let listeners = bind_tcp(loopback(), 3).unwrap();
+        let addr = listeners[0].local_addr().unwrap();
+        assert_ne!(addr.port(), 0);
+        assert!(listeners.iter().all(|l| l.local_addr().unwrap() == addr));
+        assert!(TcpStream::connect(addr).is_ok());
+
+        let sockets = bind_udp(loopback(), 3).unwrap();
+        let addr = sockets[0].local_addr().unwrap();
+        assert!(sockets.iter().all(|s| s.local_addr().unwrap() == addr));
+    }
+
+    #[test]
+    fn binds_one_without_sharing_the_port() {
+        assert_eq!(bind_tcp(loopback(), 0).unwrap().len(), 1);
+
+        // Only sockets that all set SO_REUSEPORT share a port.
+        let listener = bind_tcp(loopback(), 1).unwrap().remove(0);
+        let addr = listener.local_addr().unwrap();
+        assert!(bind_tcp(addr, 2).is_err());
+    }
+}
@@ -0,0 +1,881 @@
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use ring::digest::{digest, SHA256};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use rustls::client::HandshakeSignatureValid;
+use rustls::internal::msgs::handshake::DigitallySignedStruct;
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientCertVerified,
+    ClientCertVerifier, ClientHello, ResolvesServerCert,
+};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{
+    Certificate, ConfigBuilder, DistinguishedNames, PrivateKey, RootCertStore, ServerConfig,
+    SignatureScheme, SupportedCipherSuite, SupportedKxGroup, SupportedProtocolVersion,
+    WantsVerifier, ALL_CIPHER_SUITES, ALL_KX_GROUPS, DEFAULT_CIPHER_SUITES,
+};
+use rustls_pemfile::Item;
+
+use crate::x509;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Failed to read {0}: {1}")]
+    Io(PathBuf, #[source] std::io::Error),
+
+    #[error("No certificates found in {0}")]
+    NoCertificates(PathBuf),
+
+    #[error("No private keys in PKCS#8, PKCS#1 or SEC1 found in {0}")]
+    NoPrivateKey(PathBuf),
+
+    #[error("No private keys in {0} match the certificate")]
+    KeyMismatch(PathBuf),
+
+    #[error("Invalid certificate")]
+    InvalidCertificate,
+
+    #[error("Unsupported private key in {0}")]
+    UnsupportedKey(PathBuf),
+
+    #[error("Invalid certificate revocation list in {0}")]
+    InvalidCrl(PathBuf),
+
+    #[error("Failed to generate a certificate")]
+    Generation,
+
+    #[error("Incompatible with QUIC, which requires TLS 1.3: {0}")]
+    Quic(&'static str),
+
+    #[error("Invalid TLS parameters: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// Resolves the certificate from PEM files, which can be reloaded without a restart.
+/// Shared by both endpoints; handshakes after a reload get the new certificate, while existing
+/// connections keep the one they were established with.
+pub struct CertResolver {
+    cert_chain_pem: PathBuf,
+    private_key_pem: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+    modified: Mutex<[Option<SystemTime>; 2]>,
+}
+
+impl CertResolver {
+    pub fn load<P, Q>(cert_chain_pem: P, private_key_pem: Q) -> Result<Self, Error>
+    where
+        P: Into<PathBuf>,
+        Q: Into<PathBuf>,
+    {
+        let cert_chain_pem = cert_chain_pem.into();
+        let private_key_pem = private_key_pem.into();
+        let modified = [modified(&cert_chain_pem), modified(&private_key_pem)];
+        let current = load_certified_key(&cert_chain_pem, &private_key_pem)?;
+
+        Ok(Self {
+            cert_chain_pem,
+            private_key_pem,
+            current: RwLock::new(Arc::new(current)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reads the files again. On errors the current certificate is kept, and the stapled OCSP
+    /// response is kept as long as the certificate chain is the same.
+    pub fn reload(&self) -> Result<(), Error> {
+        *self.modified.lock().unwrap() = [
+            modified(&self.cert_chain_pem),
+            modified(&self.private_key_pem),
+        ];
+
+        let mut key = load_certified_key(&self.cert_chain_pem, &self.private_key_pem)?;
+        let mut current = self.current.write().unwrap();
+        if current.cert == key.cert {
+            key.ocsp = current.ocsp.clone();
+        }
+
+        *current = Arc::new(key);
+
+        Ok(())
+    }
+
+    /// The current certificate chain, from the end-entity certificate.
+    pub fn certificate_chain(&self) -> Vec<Certificate> {
+        self.current.read().unwrap().cert.clone()
+    }
+
+    /// Staples the DER of an OCSP response to handshakes, or stops stapling with `None`.
+    /// Does nothing and returns false if the certificate chain has been reloaded to another since
+    /// the response was obtained for it.
+    pub fn staple(&self, chain: &[Certificate], ocsp: Option<Vec<u8>>) -> bool {
+        let mut current = self.current.write().unwrap();
+        if current.cert != chain {
+            return false;
+        }
+
+        let mut key = CertifiedKey::clone(&current);
+        key.ocsp = ocsp;
+        *current = Arc::new(key);
+
+        true
+    }
+
+    /// Reads the files again if either has been modified since the last load, returning whether
+    /// they have.
+    pub fn reload_if_modified(&self) -> Result<bool, Error> {
+        let modified = [
+            modified(&self.cert_chain_pem),
+            modified(&self.private_key_pem),
+        ];
+
+        if *self.modified.lock().unwrap() == modified {
+            return Ok(false);
+        }
+
+        self.reload().map(|_| true)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap()))
+    }
+}
+
+/// Self-signed certificate generated in memory for development, valid for short enough to be
+/// pinned by WebTransport `serverCertificateHashes`, which accepts up to 14 days.
+pub struct DevCertificate {
+    key: x509::KeyPair,
+    names: Vec<String>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl DevCertificate {
+    /// Validity of the certificate, starting a little in the past to tolerate clock skews.
+    pub const VALIDITY: Duration = Duration::from_secs(13 * 86400);
+
+    /// Interval to [`renew`](Self::renew) the certificate at, a day before it expires.
+    pub const RENEWAL_INTERVAL: Duration = Duration::from_secs(12 * 86400);
+
+    /// Generates a certificate for `localhost`, `127.0.0.1`, `::1` and the other names, which are
+    /// DNS names or IP addresses.
+    pub fn generate(names: &[String]) -> Result<Self, Error> {
+        let mut all_names = vec![
+            "localhost".to_owned(),
+            "127.0.0.1".to_owned(),
+            "::1".to_owned(),
+        ];
+        for name in names {
+            if !all_names.contains(name) {
+                all_names.push(name.clone());
+            }
+        }
+
+        let key = x509::KeyPair::generate().map_err(|_| Error::Generation)?;
+        let current = issue(&key, &all_names)?;
+
+        Ok(Self {
+            key,
+            names: all_names,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Issues a new certificate with the same key, so that the SPKI hash stays valid while the
+    /// certificate hash changes.
+    pub fn renew(&self) -> Result<(), Error> {
+        *self.current.write().unwrap() = Arc::new(issue(&self.key, &self.names)?);
+
+        Ok(())
+    }
+
+    /// Base64 of the SHA-256 of the public key, as Chrome's `--ignore-certificate-errors-spki-list`
+    /// takes.
+    pub fn spki_sha256(&self) -> String {
+        base64::encode(digest(&SHA256, &self.key.subject_public_key_info()))
+    }
+
+    /// SHA-256 of the whole certificate, as WebTransport `serverCertificateHashes` takes.
+    pub fn certificate_sha256(&self) -> Vec<u8> {
+        let current = self.current.read().unwrap();
+        digest(&SHA256, &current.cert[0].0).as_ref().to_vec()
+    }
+}
+
+impl ResolvesServerCert for DevCertificate {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap()))
+    }
+}
+
+fn issue(key: &x509::KeyPair, names: &[String]) -> Result<CertifiedKey, Error> {
+    let not_before = SystemTime::now() - Duration::from_secs(3600);
+    let certificate = x509::self_signed(
+        key,
+        names,
+        not_before..not_before + DevCertificate::VALIDITY,
+        &[],
+    )
+    .map_err(|_| Error::Generation)?;
+    let signing_key =
+        any_supported_type(&PrivateKey(key.pkcs8().to_vec())).map_err(|_| Error::Generation)?;
+
+    Ok(CertifiedKey::new(
+        vec![Certificate(certificate)],
+        signing_key,
+    ))
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn open(path: &Path) -> Result<BufReader<File>, Error> {
+    Ok(BufReader::new(
+        File::open(path).map_err(|e| Error::Io(path.to_owned(), e))?,
+    ))
+}
+
+fn load_certified_key(
+    cert_chain_pem: &Path,
+    private_key_pem: &Path,
+) -> Result<CertifiedKey, Error> {
+    let certs = rustls_pemfile::certs(&mut open(cert_chain_pem)?)
+        .map_err(|e| Error::Io(cert_chain_pem.to_owned(), e))?;
+
+    if certs.is_empty() {
+        return Err(Error::NoCertificates(cert_chain_pem.to_owned()));
+    }
+
+    let certs = certs.into_iter().map(Certificate).collect::<Vec<_>>();
+    let key = load_private_key(private_key_pem, certs.first())?;
+    let key =
+        any_supported_type(&key).map_err(|_| Error::UnsupportedKey(private_key_pem.to_owned()))?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Reads a private key from a PEM file, in PKCS#8, PKCS#1 (`RSA PRIVATE KEY`) or SEC1
+/// (`EC PRIVATE KEY`). With the certificate, picks the key matching it, so that the file may
+/// contain other keys too.
+pub fn load_private_key(
+    path: &Path,
+    certificate: Option<&Certificate>,
+) -> Result<PrivateKey, Error> {
+    let mut keys = rustls_pemfile::read_all(&mut open(path)?)
+        .map_err(|e| Error::Io(path.to_owned(), e))?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::PKCS8Key(k) | Item::RSAKey(k) | Item::ECKey(k) => Some(PrivateKey(k)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if keys.is_empty() {
+        return Err(Error::NoPrivateKey(path.to_owned()));
+    }
+
+    let certificate = match certificate {
+        Some(c) => x509::Certificate::parse(&c.0).ok_or(Error::InvalidCertificate)?,
+        None => return Ok(keys.remove(0)),
+    };
+
+    // Keys whose public key cannot be derived cannot sign either, which is the error to report
+    // rather than a mismatch.
+    let mut unsupported = false;
+    for key in keys {
+        match public_key(&key) {
+            Some(k) if k == certificate.public_key => return Ok(key),
+            Some(_) => (),
+            None => unsupported = true,
+        }
+    }
+
+    match unsupported {
+        true => Err(Error::UnsupportedKey(path.to_owned())),
+        false => Err(Error::KeyMismatch(path.to_owned())),
+    }
+}
+
+/// Derives the public key as in certificates. Read from the structure of the key rather than
+/// through ring, so that keys ring cannot sign with, such as RSA keys under 2048 bits, still
+/// match their certificates and fail as unsupported.
+fn public_key(key: &PrivateKey) -> Option<Vec<u8>> {
+    // Ed25519 keys in PKCS#8 v1 carry only the seed, from which ring derives the public key.
+    if let Ok(k) = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&key.0) {
+        return Some(k.public_key().as_ref().to_vec());
+    }
+
+    x509::pkcs8_public_key(&key.0)
+        .or_else(|| x509::rsa_public_key(&key.0))
+        .or_else(|| x509::sec1_public_key(&key.0).map(<[u8]>::to_vec))
+}
+
+/// Whether clients must present a certificate.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ClientAuth {
+    #[default]
+    Required,
+
+    /// Accepts clients without certificates too, while still verifying the ones presented.
+    Optional,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown client authentication mode: {0}")]
+pub struct UnknownClientAuth(String);
+
+impl FromStr for ClientAuth {
+    type Err = UnknownClientAuth;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "required" => Ok(Self::Required),
+            "optional" => Ok(Self::Optional),
+            _ => Err(UnknownClientAuth(s.to_owned())),
+        }
+    }
+}
+
+/// TLS protocol version: 1.2 or 1.3, parsed from such as `1.3`, `TLS1.3` or `TLSv1.3`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Version {
+    Tls12,
+    Tls13,
+}
+
+impl Version {
+    fn supported(self) -> &'static SupportedProtocolVersion {
+        match self {
+            Self::Tls12 => &rustls::version::TLS12,
+            Self::Tls13 => &rustls::version::TLS13,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown TLS version: {0}")]
+pub struct UnknownVersion(String);
+
+impl FromStr for Version {
+    type Err = UnknownVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lowercase = s.to_ascii_lowercase();
+        let number = match lowercase.strip_prefix("tls") {
+            Some(n) => n.strip_prefix('v').unwrap_or(n),
+            None => &lowercase,
+        };
+
+        match number {
+            "1.2" => Ok(Self::Tls12),
+            "1.3" => Ok(Self::Tls13),
+            _ => Err(UnknownVersion(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown cipher suite: {0}")]
+pub struct UnknownCipherSuite(String);
+
+/// Finds a cipher suite by its name, such as `TLS13_AES_128_GCM_SHA256` or
+/// `TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256`. TLS 1.3 suites can be named as in IANA too, such as
+/// `TLS_AES_128_GCM_SHA256`.
+pub fn cipher_suite(name: &str) -> Result<SupportedCipherSuite, UnknownCipherSuite> {
+    ALL_CIPHER_SUITES
+        .iter()
+        .copied()
+        .find(|s| {
+            let suite = format!("{:?}", s.suite());
+            suite.eq_ignore_ascii_case(name)
+                || suite
+                    .replacen("TLS13_", "TLS_", 1)
+                    .eq_ignore_ascii_case(name)
+        })
+        .ok_or_else(|| UnknownCipherSuite(name.to_owned()))
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Unknown key exchange group: {0}")]
+pub struct UnknownKxGroup(String);
+
+/// Finds a key exchange group by its name: `X25519`, `secp256r1` or `secp384r1`.
+pub fn kx_group(name: &str) -> Result<&'static SupportedKxGroup, UnknownKxGroup> {
+    ALL_KX_GROUPS
+        .iter()
+        .copied()
+        .find(|g| format!("{:?}", g.name).eq_ignore_ascii_case(name))
+        .ok_or_else(|| UnknownKxGroup(name.to_owned()))
+}
+
+/// Protocol versions, cipher suites and key exchange groups to negotiate, in the order of
+/// preference. Defaults to the safe defaults of rustls.
+#[derive(Clone, Debug)]
+pub struct Parameters {
+    pub versions: Vec<Version>,
+    pub cipher_suites: Vec<SupportedCipherSuite>,
+    pub kx_groups: Vec<&'static SupportedKxGroup>,
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Self {
+            versions: vec![Version::Tls12, Version::Tls13],
+            cipher_suites: DEFAULT_CIPHER_SUITES.to_vec(),
+            kx_groups: ALL_KX_GROUPS.to_vec(),
+        }
+    }
+}
+
+impl Parameters {
+    /// Starts building a `ServerConfig` with the parameters, failing if the HTTP/3 endpoint could
+    /// not complete handshakes with them.
+    pub fn builder(&self) -> Result<ConfigBuilder<ServerConfig, WantsVerifier>, Error> {
+        if !self.versions.contains(&Version::Tls13) {
+            return Err(Error::Quic("TLS 1.3 is disabled"));
+        }
+
+        if !self
+            .cipher_suites
+            .iter()
+            .any(|s| s.version() == &rustls::version::TLS13)
+        {
+            return Err(Error::Quic("no TLS 1.3 cipher suites are enabled"));
+        }
+
+        let versions = self
+            .versions
+            .iter()
+            .map(|v| v.supported())
+            .collect::<Vec<_>>();
+
+        Ok(ServerConfig::builder()
+            .with_cipher_suites(&self.cipher_suites)
+            .with_kx_groups(&self.kx_groups)
+            .with_protocol_versions(&versions)?)
+    }
+}
+
+/// Builds a verifier of client certificates chaining to the CA bundle in PEM. Certificates
+/// revoked by the list are rejected too.
+pub fn client_cert_verifier(
+    ca_bundle: &Path,
+    mode: ClientAuth,
+    crl: Option<Arc<RevocationList>>,
+) -> Result<Arc<dyn ClientCertVerifier>, Error> {
+    let certs = rustls_pemfile::certs(&mut open(ca_bundle)?)
+        .map_err(|e| Error::Io(ca_bundle.to_owned(), e))?;
+
+    let mut roots = RootCertStore::empty();
+    if roots.add_parsable_certificates(&certs).0 == 0 {
+        return Err(Error::NoCertificates(ca_bundle.to_owned()));
+    }
+
+    let verifier = match mode {
+        ClientAuth::Required => AllowAnyAuthenticatedClient::new(roots),
+        ClientAuth::Optional => AllowAnyAnonymousOrAuthenticatedClient::new(roots),
+    };
+
+    match crl {
+        Some(crl) => Ok(Arc::new(RevocationChecker {
+            inner: verifier,
+            crl,
+        })),
+        None => Ok(verifier),
+    }
+}
+
+/// Certificate revocation lists from a file in PEM or DER, which can be reloaded without a
+/// restart. Their signatures are not verified, so the file must come from a trusted source.
+pub struct RevocationList {
+    path: PathBuf,
+    current: RwLock<Revocations>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+struct Revocations {
+    /// Pairs of the issuer and the serial number of revoked certificates.
+    revoked: HashSet<(Vec<u8>, Vec<u8>)>,
+
+    /// Earliest time by which the issuers publish newer lists.
+    next_update: Option<SystemTime>,
+}
+
+impl RevocationList {
+    pub fn load<P>(path: P) -> Result<Self, Error>
+    where
+        P: Into<PathBuf>,
+    {
+        let path = path.into();
+        let modified = modified(&path);
+        let current = load_revocations(&path)?;
+
+        Ok(Self {
+            path,
+            current: RwLock::new(current),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reads the file again. On errors the current lists are kept.
+    pub fn reload(&self) -> Result<(), Error> {
+        *self.modified.lock().unwrap() = modified(&self.path);
+        *self.current.write().unwrap() = load_revocations(&self.path)?;
+
+        Ok(())
+    }
+
+    /// Reads the file again if it has been modified since the last load, returning whether it
+    /// has.
+    pub fn reload_if_modified(&self) -> Result<bool, Error> {
+        if *self.modified.lock().unwrap() == modified(&self.path) {
+            return Ok(false);
+        }
+
+        self.reload().map(|_| true)
+    }
+
+    /// Time by which the issuers publish newer lists, after which the current ones are stale.
+    pub fn next_update(&self) -> Option<SystemTime> {
+        self.current.read().unwrap().next_update
+    }
+
+    fn is_revoked(&self, issuer: &[u8], serial: &[u8]) -> bool {
+        self.current
+            .read()
+            .unwrap()
+            .revoked
+            .contains(&(issuer.to_vec(), serial.to_vec()))
+    }
+}
+
+fn load_revocations(path: &Path) -> Result<Revocations, Error> {
+    let data = std::fs::read(path).map_err(|e| Error::Io(path.to_owned(), e))?;
+    let crls = match data.starts_with(b"-----") {
+        true => x509::from_pem(&String::from_utf8_lossy(&data), "X509 CRL"),
+        false => vec![data],
+    };
+
+    if crls.is_empty() {
+        return Err(Error::InvalidCrl(path.to_owned()));
+    }
+
+    let mut revocations = Revocations {
+        revoked: HashSet::new(),
+        next_update: None,
+    };
+
+    for der in &crls {
+        let crl = x509::Crl::parse(der).ok_or_else(|| Error::InvalidCrl(path.to_owned()))?;
+        revocations.revoked.extend(
+            crl.revoked
+                .iter()
+                .map(|s| (crl.issuer.to_vec(), s.to_vec())),
+        );
+
+        revocations.next_update = match (revocations.next_update, crl.next_update) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    Ok(revocations)
+}
+
+/// Rejects revoked certificates after the inner verifier has accepted them.
+/// webpki does not support CRLs yet.
+struct RevocationChecker {
+    inner: Arc<dyn ClientCertVerifier>,
+    crl: Arc<RevocationList>,
+}
+
+impl ClientCertVerifier for RevocationChecker {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        self.inner.client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+
+        for cert in std::iter::once(end_entity).chain(intermediates) {
+            let cert = x509::Certificate::parse(&cert.0)
+                .ok_or(rustls::Error::InvalidCertificateEncoding)?;
+
+            if self.crl.is_revoked(cert.issuer, cert.serial) {
+                return Err(rustls::Error::InvalidCertificateData(
+                    "certificate revoked".to_owned(),
+                ));
+            }
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &Certificate,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/keys")
+            .join(name)
+    }
+
+    /// Copies fixtures into a directory of the test, so that they can be replaced while loaded.
+    fn scratch(test: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("h123-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (from, to) in files {
+            fs::copy(fixture(from), dir.join(to)).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn reloading_a_mismatched_key_keeps_the_certificate() {
+        let dir = scratch("reload", &[("ec.crt", "cert.pem"), ("ec.pem", "key.pem")]);
+        let resolver = CertResolver::load(dir.join("cert.pem"), dir.join("key.pem")).unwrap();
+        let chain = resolver.certificate_chain();
+
+        fs::copy(fixture("rsa.pem"), dir.join("key.pem")).unwrap();
+        assert!(matches!(resolver.reload(), Err(Error::KeyMismatch(_))));
+        assert_eq!(resolver.certificate_chain(), chain);
+
+        fs::copy(fixture("rsa.crt"), dir.join("cert.pem")).unwrap();
+        resolver.reload().unwrap();
+        assert_ne!(resolver.certificate_chain(), chain);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reloads_the_revocation_list() {
+        let dir = scratch("crl", &[("revoked.crl", "crl.pem")]);
+        let crl = RevocationList::load(dir.join("crl.pem")).unwrap();
+
+        let issuer = fs::read_to_string(fixture("ec.crt")).unwrap();
+        let issuer = x509::from_pem(&issuer, "CERTIFICATE").remove(0);
+        let issuer = x509::Certificate::parse(&issuer).unwrap().subject.to_vec();
+        let revoked = fs::read_to_string(fixture("rsa.crt")).unwrap();
+        let revoked = x509::from_pem(&revoked, "CERTIFICATE").remove(0);
+        let serial = x509::Certificate::parse(&revoked).unwrap().serial.to_vec();
+
+        assert!(crl.is_revoked(&issuer, &serial));
+        assert!(!crl.is_revoked(&serial, &serial));
+        assert!(crl.next_update().unwrap() > SystemTime::now());
+        assert!(!crl.reload_if_modified().unwrap());
+
+        // A DER file, stale since 2020.
+        fs::copy(fixture("stale.crl"), dir.join("crl.pem")).unwrap();
+        crl.reload().unwrap();
+        assert!(!crl.is_revoked(&issuer, &serial));
+        assert!(crl.next_update().unwrap() < SystemTime::now());
+
+        fs::write(dir.join("crl.pem"), "-----BEGIN X509 CRL-----\n").unwrap();
+        assert!(matches!(crl.reload(), Err(Error::InvalidCrl(_))));
+        assert!(crl.next_update().unwrap() < SystemTime::now());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn certificate(name: &str) -> Certificate {
+        let pem = fs::read_to_string(fixture(name)).unwrap();
+        Certificate(x509::from_pem(&pem, "CERTIFICATE").remove(0))
+    }
+
+    #[test]
+    fn finds_the_key_of_the_certificate() {
+        for (key, cert) in [
+            ("ec.pem", "ec.crt"),
+            ("ec-pkcs8.pem", "ec.crt"),
+            ("rsa.pem", "rsa.crt"),
+            ("rsa-pkcs8.pem", "rsa.crt"),
+            ("ed25519.pem", "ed25519.crt"),
+        ] {
+            let result = load_private_key(&fixture(key), Some(&certificate(cert)));
+            assert!(result.is_ok(), "{}: {:?}", key, result);
+        }
+
+        // Picks the matching one out of several keys.
+        let dir = scratch("keys", &[]);
+        let keys = [
+            fixture("rsa.pem"),
+            fixture("ec-pkcs8.pem"),
+            fixture("ec.pem"),
+        ]
+        .iter()
+        .map(|p| fs::read_to_string(p).unwrap())
+        .collect::<String>();
+        fs::write(dir.join("keys.pem"), keys).unwrap();
+
+        let key = load_private_key(&dir.join("keys.pem"), Some(&certificate("ec.crt"))).unwrap();
+        assert_eq!(
+            x509::pkcs8_public_key(&key.0).as_deref(),
+            Some(
+                x509::Certificate::parse(&certificate("ec.crt").0)
+                    .unwrap()
+                    .public_key
+            )
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tells_unsupported_keys_from_mismatched_ones() {
+        let load =
+            |key: &str, cert: &str| load_private_key(&fixture(key), Some(&certificate(cert)));
+
+        assert!(matches!(
+            load("rsa.pem", "ec.crt"),
+            Err(Error::KeyMismatch(_))
+        ));
+        assert!(matches!(
+            load("ed25519.pem", "rsa.crt"),
+            Err(Error::KeyMismatch(_))
+        ));
+
+        // SEC1 without the public key, which ring requires to sign.
+        assert!(matches!(
+            load("ec-no-public.pem", "ec.crt"),
+            Err(Error::UnsupportedKey(_))
+        ));
+
+        // RSA under 2048 bits matches its certificate, but ring cannot sign with it.
+        assert!(load("rsa1024.pem", "rsa1024.crt").is_ok());
+        assert!(matches!(
+            CertResolver::load(fixture("rsa1024.crt"), fixture("rsa1024.pem")),
+            Err(Error::UnsupportedKey(_))
+        ));
+    }
+
+    #[test]
+    fn renews_the_dev_certificate_with_the_same_key() {
+        let names = ["example.test".to_string(), "::1".to_string()];
+        let certificate = DevCertificate::generate(&names).unwrap();
+
+        let chain = certificate.current.read().unwrap().cert.clone();
+        let parsed = x509::Certificate::parse(&chain[0].0).unwrap();
+        assert_eq!(x509::format_name(parsed.subject).unwrap(), "CN=localhost");
+        assert_eq!(
+            parsed
+                .validity
+                .end
+                .duration_since(parsed.validity.start)
+                .unwrap(),
+            DevCertificate::VALIDITY
+        );
+        assert_eq!(
+            certificate.names,
+            ["localhost", "127.0.0.1", "::1", "example.test"]
+        );
+
+        let (spki, hash) = (certificate.spki_sha256(), certificate.certificate_sha256());
+        certificate.renew().unwrap();
+        assert_eq!(certificate.spki_sha256(), spki);
+        assert_ne!(certificate.certificate_sha256(), hash);
+    }
+
+    #[test]
+    fn parses_versions() {
+        for (s, version) in [
+            ("1.2", Version::Tls12),
+            ("TLS1.2", Version::Tls12),
+            ("TLSv1.2", Version::Tls12),
+            ("tlsv1.3", Version::Tls13),
+            ("1.3", Version::Tls13),
+        ] {
+            assert_eq!(s.parse::<Version>().unwrap(), version, "{}", s);
+        }
+
+        for s in ["", "1.1", "v1.3", "TLSv", "tlstls1.3", "SSLv3"] {
+            assert!(s.parse::<Version>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn finds_cipher_suites_and_groups_by_name() {
+        let suite = cipher_suite("TLS13_AES_128_GCM_SHA256").unwrap();
+        assert_eq!(
+            cipher_suite("tls_aes_128_gcm_sha256").unwrap().suite(),
+            suite.suite()
+        );
+        assert!(cipher_suite("TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256").is_ok());
+        assert!(cipher_suite("TLS_RSA_WITH_AES_128_CBC_SHA").is_err());
+
+        assert!(kx_group("x25519").is_ok());
+        assert!(kx_group("secp384r1").is_ok());
+        assert!(kx_group("ffdhe2048").is_err());
+    }
+
+    #[test]
+    fn refuses_parameters_without_tls_1_3() {
+        assert!(Parameters::default().builder().is_ok());
+
+        let tls12 = Parameters {
+            versions: vec![Version::Tls12],
+            ..Default::default()
+        };
+        assert!(matches!(tls12.builder(), Err(Error::Quic(_))));
+
+        let tls12_suites = Parameters {
+            cipher_suites: vec![cipher_suite("TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256").unwrap()],
+            ..Default::default()
+        };
+        assert!(matches!(tls12_suites.builder(), Err(Error::Quic(_))));
+
+        let no_groups = Parameters {
+            kx_groups: Vec::new(),
+            ..Default::default()
+        };
+        assert!(matches!(no_groups.builder(), Err(Error::Rustls(_))));
+    }
+}
@@ -0,0 +1,266 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use http::header::{
+    HeaderName, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION, UPGRADE,
+};
+use http::{Method, Request, Response, StatusCode, Version};
+use hyper::ext::Protocol;
+use hyper::upgrade::Upgraded;
+use hyper::Body;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::WebSocketStream;
+use tracing::error;
+
+use crate::h12::Activity;
+use crate::shutdown::{spawn_upgraded, Shutdown};
+
+pub use tokio_tungstenite::tungstenite::{Error, Message};
+
+const VERSION: &str = "13";
+
+/// Serves WebSocket connections, opened by HTTP/1.1 `Upgrade` or by HTTP/2 extended CONNECT.
+#[async_trait]
+pub trait WebSocketHandler: Send + Sync {
+    /// Whether to accept the request. Rejected requests are answered with `404 Not Found`.
+    fn accepts(&self, _request: &Request<()>) -> bool {
+        true
+    }
+
+    async fn handle(&self, request: Request<()>, socket: WebSocket);
+}
+
+/// Echoes text and binary messages back on WebSocket connections opened at a path.
+pub struct Echo {
+    path: String,
+}
+
+impl Echo {
+    pub fn new<P>(path: P) -> Self
+    where
+        P: Into<String>,
+    {
+        Self { path: path.into() }
+    }
+}
+
+#[async_trait]
+impl WebSocketHandler for Echo {
+    fn accepts(&self, request: &Request<()>) -> bool {
+        request.uri().path() == self.path
+    }
+
+    async fn handle(&self, _request: Request<()>, mut socket: WebSocket) {
+        while let Some(message) = socket.next().await {
+            let result = match message {
+                Ok(m) if m.is_text() || m.is_binary() => socket.send(m).await,
+                // Pings are answered and closes are echoed by the stream itself.
+                Ok(_) => Ok(()),
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = result {
+                error!("WebSocket echo failed: {}", e);
+
+                break;
+            }
+        }
+    }
+}
+
+/// A bidirectional stream of WebSocket messages, regardless of the protocol carrying it.
+pub struct WebSocket {
+    inner: WebSocketStream<Upgraded>,
+}
+
+impl Stream for WebSocket {
+    type Item = Result<Message, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl Sink<Message> for WebSocket {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Message) -> Result<(), Error> {
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+enum Opening {
+    // RFC 6455
+    Upgrade,
+    // RFC 8441
+    ExtendedConnect,
+}
+
+fn has_token(request: &Request<Body>, name: HeaderName, token: &str) -> bool {
+    request
+        .headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|t| t.trim().eq_ignore_ascii_case(token))
+}
+
+fn opening_of(request: &Request<Body>) -> Option<Opening> {
+    match request.version() {
+        Version::HTTP_11
+            if request.method() == Method::GET
+                && has_token(request, CONNECTION, "upgrade")
+                && has_token(request, UPGRADE, "websocket") =>
+        {
+            Some(Opening::Upgrade)
+        }
+        Version::HTTP_2
+            if request.method() == Method::CONNECT
+                && request
+                    .extensions()
+                    .get::<Protocol>()
+                    .map(|p| p.as_str().eq_ignore_ascii_case("websocket"))
+                    .unwrap_or(false) =>
+        {
+            Some(Opening::ExtendedConnect)
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn is_websocket(request: &Request<Body>) -> bool {
+    opening_of(request).is_some()
+}
+
+/// Answers the opening handshake, then hands the upgraded connection to the handler.
+pub(crate) fn upgrade(
+    mut request: Request<Body>,
+    handler: Arc<dyn WebSocketHandler>,
+) -> Result<Response<Body>, http::Error> {
+    let opening = match opening_of(&request) {
+        Some(o) => o,
+        None => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+        }
+    };
+
+    if request
+        .headers()
+        .get(SEC_WEBSOCKET_VERSION)
+        .map(|v| v.as_bytes())
+        != Some(VERSION.as_bytes())
+    {
+        return Response::builder()
+            .status(StatusCode::UPGRADE_REQUIRED)
+            .header(SEC_WEBSOCKET_VERSION, VERSION)
+            .body(Body::empty());
+    }
+
+    let response = match opening {
+        Opening::Upgrade => match request.headers().get(SEC_WEBSOCKET_KEY) {
+            Some(key) => Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(CONNECTION, "upgrade")
+                .header(UPGRADE, "websocket")
+                .header(SEC_WEBSOCKET_ACCEPT, derive_accept_key(key.as_bytes())),
+            None => {
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::empty())
+            }
+        },
+        Opening::ExtendedConnect => Response::builder().status(StatusCode::OK),
+    };
+
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    let active = request.extensions().get::<Activity>().map(Activity::begin);
+    let shutdown = request.extensions().get::<Shutdown>().cloned();
+    let (parts, _) = request.into_parts();
+    let request = Request::from_parts(parts, ());
+
+    if !handler.accepts(&request) {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty());
+    }
+
+    spawn_upgraded(shutdown.as_ref(), async move {
+        let _active = active;
+        match on_upgrade.await {
+            Ok(upgraded) => {
+                let inner = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+
+                handler.handle(request, WebSocket { inner }).await
+            }
+            Err(e) => error!("WebSocket upgrade failed: {}", e),
+        }
+    });
+
+    response.body(Body::empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request(path: &str) -> Request<Body> {
+        Request::builder()
+            .uri(path)
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn recognizes_upgrades_and_extended_connects() {
+        assert!(is_websocket(&upgrade_request("/echo")));
+
+        let mut connect = Request::builder()
+            .method(Method::CONNECT)
+            .version(Version::HTTP_2)
+            .uri("https://localhost/echo")
+            .body(Body::empty())
+            .unwrap();
+        assert!(!is_websocket(&connect));
+
+        connect
+            .extensions_mut()
+            .insert(Protocol::from_static("websocket"));
+        assert!(is_websocket(&connect));
+
+        let mut plain = upgrade_request("/echo");
+        plain.headers_mut().remove(UPGRADE);
+        assert!(!is_websocket(&plain));
+    }
+
+    #[test]
+    fn echo_accepts_its_path_only() {
+        let echo = Echo::new("/echo");
+        let request = |path| Request::builder().uri(path).body(()).unwrap();
+
+        assert!(echo.accepts(&request("/echo")));
+        assert!(echo.accepts(&request("/echo?room=1")));
+        assert!(!echo.accepts(&request("/echo/more")));
+        assert!(!echo.accepts(&request("/")));
+    }
+}
@@ -0,0 +1,664 @@
+use std::net::IpAddr;
+use std::ops::Range;
+use std::time::SystemTime;
+
+use ring::error::{KeyRejected, Unspecified};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair as _, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+use crate::der::{self, Reader};
+
+const ECDSA_WITH_SHA256: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+const EC_PUBLIC_KEY: &[u64] = &[1, 2, 840, 10045, 2, 1];
+const RSA_ENCRYPTION: &[u64] = &[1, 2, 840, 113549, 1, 1, 1];
+const PRIME256V1: &[u64] = &[1, 2, 840, 10045, 3, 1, 7];
+const COMMON_NAME: &[u64] = &[2, 5, 4, 3];
+const SUBJECT_ALT_NAME: &[u64] = &[2, 5, 29, 17];
+const EXTENSION_REQUEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 14];
+const AUTHORITY_INFO_ACCESS: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 1, 1];
+const OCSP: &[u64] = &[1, 3, 6, 1, 5, 5, 7, 48, 1];
+
+/// ECDSA P-256 key pair, the only kind of keys generated by this crate.
+pub(crate) struct KeyPair {
+    pkcs8: Vec<u8>,
+    inner: EcdsaKeyPair,
+}
+
+impl KeyPair {
+    pub(crate) fn generate() -> Result<Self, Unspecified> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())?;
+
+        Self::from_pkcs8(pkcs8.as_ref()).map_err(|_| Unspecified)
+    }
+
+    pub(crate) fn from_pkcs8(pkcs8: &[u8]) -> Result<Self, KeyRejected> {
+        Ok(Self {
+            pkcs8: pkcs8.to_vec(),
+            inner: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8)?,
+        })
+    }
+
+    pub(crate) fn pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    pub(crate) fn subject_public_key_info(&self) -> Vec<u8> {
+        der::sequence(&[
+            der::sequence(&[der::oid(EC_PUBLIC_KEY), der::oid(PRIME256V1)]),
+            der::bit_string(self.inner.public_key().as_ref()),
+        ])
+    }
+
+    /// Signs the data, returning it followed by the signature algorithm and the signature.
+    fn sign(&self, data: Vec<u8>) -> Result<Vec<u8>, Unspecified> {
+        let signature = self.inner.sign(&SystemRandom::new(), &data)?;
+
+        Ok(der::sequence(&[
+            data,
+            der::sequence(&[der::oid(ECDSA_WITH_SHA256)]),
+            der::bit_string(signature.as_ref()),
+        ]))
+    }
+}
+
+pub(crate) fn extension(oid: &[u64], critical: bool, value: &[u8]) -> Vec<u8> {
+    let mut items = vec![der::oid(oid)];
+    if critical {
+        items.push(der::tlv(der::BOOLEAN, &[0xff]));
+    }
+
+    items.push(der::octet_string(value));
+    der::sequence(&items)
+}
+
+/// Lists the names as dNSName, or as iPAddress if they are IP literals, such as `::1` or `[::1]`.
+fn subject_alt_name(names: &[String]) -> Vec<u8> {
+    let names = names
+        .iter()
+        .map(|n| {
+            let literal = n.strip_prefix('[').and_then(|n| n.strip_suffix(']'));
+            match literal.unwrap_or(n).parse() {
+                Ok(IpAddr::V4(ip)) => der::tlv(der::context_primitive(7), &ip.octets()),
+                Ok(IpAddr::V6(ip)) => der::tlv(der::context_primitive(7), &ip.octets()),
+                Err(_) => der::tlv(der::context_primitive(2), n.as_bytes()),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    extension(SUBJECT_ALT_NAME, false, &der::sequence(&names))
+}
+
+fn name(common_name: &str) -> Vec<u8> {
+    der::sequence(&[der::set(&[der::sequence(&[
+        der::oid(COMMON_NAME),
+        der::utf8_string(common_name),
+    ])])])
+}
+
+/// Builds a self-signed certificate for the DNS names or IP addresses, with the first one as the
+/// common name.
+pub(crate) fn self_signed(
+    key: &KeyPair,
+    names: &[String],
+    validity: Range<SystemTime>,
+    extensions: &[Vec<u8>],
+) -> Result<Vec<u8>, Unspecified> {
+    let mut serial = [0; 16];
+    SystemRandom::new().fill(&mut serial)?;
+    serial[0] &= 0x7f;
+
+    let name = name(names.first().map(String::as_str).unwrap_or_default());
+    let extensions = [&[subject_alt_name(names)], extensions].concat();
+
+    key.sign(der::sequence(&[
+        der::tlv(der::context(0), &der::integer(&[2])),
+        der::integer(&serial),
+        der::sequence(&[der::oid(ECDSA_WITH_SHA256)]),
+        name.clone(),
+        der::sequence(&[der::time(validity.start), der::time(validity.end)]),
+        name,
+        key.subject_public_key_info(),
+        der::tlv(der::context(3), &der::sequence(&extensions)),
+    ]))
+}
+
+/// Builds a certificate signing request (PKCS#10) for the DNS names.
+pub(crate) fn csr(key: &KeyPair, dns_names: &[String]) -> Result<Vec<u8>, Unspecified> {
+    let extension_request = der::sequence(&[
+        der::oid(EXTENSION_REQUEST),
+        der::set(&[der::sequence(&[subject_alt_name(dns_names)])]),
+    ]);
+
+    key.sign(der::sequence(&[
+        der::integer(&[0]),
+        name(dns_names.first().map(String::as_str).unwrap_or_default()),
+        key.subject_public_key_info(),
+        der::tlv(der::context(0), &extension_request),
+    ]))
+}
+
+/// Encodes DER in PEM with the label, such as `CERTIFICATE`.
+pub(crate) fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let lines = encoded
+        .as_bytes()
+        .chunks(64)
+        .map(|l| String::from_utf8_lossy(l))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "-----BEGIN {}-----\n{}\n-----END {}-----\n",
+        label, lines, label
+    )
+}
+
+/// Decodes the DER of every PEM block with the label, skipping malformed ones.
+pub(crate) fn from_pem(pem: &str, label: &str) -> Vec<Vec<u8>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+
+    pem.split(&begin)
+        .skip(1)
+        .filter_map(|b| b.split(&end).next())
+        .filter_map(|b| base64::decode(b.split_whitespace().collect::<String>()).ok())
+        .collect()
+}
+
+/// Fields of a certificate that this crate looks into.
+pub(crate) struct Certificate<'a> {
+    /// Content of the serial number, comparable as is.
+    pub(crate) serial: &'a [u8],
+
+    /// Whole encodings of the names, comparable as is.
+    pub(crate) issuer: &'a [u8],
+    pub(crate) subject: &'a [u8],
+
+    pub(crate) validity: Range<SystemTime>,
+
+    /// Content of the subjectPublicKey, such as an EC point or a PKCS#1 RSA public key.
+    pub(crate) public_key: &'a [u8],
+
+    /// URL of the OCSP responder in the Authority Information Access extension.
+    pub(crate) ocsp_responder: Option<&'a str>,
+
+    /// DNS names and IP addresses in the Subject Alternative Name extension, IP addresses
+    /// formatted as by [`IpAddr`].
+    pub(crate) names: Vec<String>,
+
+    /// Content of the extensions field, empty if there is none.
+    extensions: &'a [u8],
+}
+
+impl<'a> Certificate<'a> {
+    pub(crate) fn parse(der: &'a [u8]) -> Option<Self> {
+        let certificate = Reader::new(der).expect(der::SEQUENCE)?;
+        let mut tbs = certificate.reader().expect(der::SEQUENCE)?.reader();
+
+        tbs.optional(der::context(0));
+        let serial = tbs.expect(der::INTEGER)?.content;
+        tbs.expect(der::SEQUENCE)?;
+        let issuer = tbs.expect(der::SEQUENCE)?.raw;
+
+        let mut validity = tbs.expect(der::SEQUENCE)?.reader();
+        let not_before = validity.next()?.time()?;
+        let not_after = validity.next()?.time()?;
+
+        let subject = tbs.expect(der::SEQUENCE)?.raw;
+        let mut spki = tbs.expect(der::SEQUENCE)?.reader();
+        spki.expect(der::SEQUENCE)?;
+        let public_key = spki.expect(der::BIT_STRING)?.content.get(1..)?;
+
+        tbs.optional(der::context_primitive(1));
+        tbs.optional(der::context_primitive(2));
+        let extensions = tbs.optional(der::context(3)).map_or(&[][..], |e| e.content);
+
+        let mut certificate = Self {
+            serial,
+            issuer,
+            subject,
+            validity: not_before..not_after,
+            public_key,
+            ocsp_responder: None,
+            names: Vec::new(),
+            extensions,
+        };
+
+        certificate.ocsp_responder = certificate
+            .extension(AUTHORITY_INFO_ACCESS)
+            .and_then(ocsp_responder);
+        certificate.names = certificate
+            .extension(SUBJECT_ALT_NAME)
+            .and_then(subject_alt_names)
+            .unwrap_or_default();
+
+        Some(certificate)
+    }
+
+    /// Returns the value of the extension of the OID, if present.
+    pub(crate) fn extension(&self, oid: &[u64]) -> Option<&'a [u8]> {
+        let oid = der::oid(oid);
+
+        Reader::new(self.extensions)
+            .expect(der::SEQUENCE)?
+            .reader()
+            .find_map(|e| {
+                let mut extension = e.reader();
+                if extension.expect(der::OID)?.raw != oid {
+                    return None;
+                }
+
+                extension.optional(der::BOOLEAN);
+                extension.expect(der::OCTET_STRING).map(|v| v.content)
+            })
+    }
+}
+
+fn subject_alt_names(value: &[u8]) -> Option<Vec<String>> {
+    let names = Reader::new(value)
+        .expect(der::SEQUENCE)?
+        .reader()
+        .filter_map(|n| match n.tag {
+            t if t == der::context_primitive(2) => {
+                std::str::from_utf8(n.content).ok().map(str::to_owned)
+            }
+            t if t == der::context_primitive(7) => match n.content.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(n.content).ok()?).to_string()),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(n.content).ok()?).to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect();
+
+    Some(names)
+}
+
+fn ocsp_responder(aia: &[u8]) -> Option<&str> {
+    Reader::new(aia)
+        .expect(der::SEQUENCE)?
+        .reader()
+        .find_map(|a| {
+            let mut description = a.reader();
+            if description.expect(der::OID)?.raw != der::oid(OCSP) {
+                return None;
+            }
+
+            let location = description.expect(der::context_primitive(6))?;
+            std::str::from_utf8(location.content).ok()
+        })
+}
+
+/// Reads the public key from a SEC1 EC private key, which carries it as `[1]`.
+pub(crate) fn sec1_public_key(der: &[u8]) -> Option<&[u8]> {
+    let mut key = Reader::new(der).expect(der::SEQUENCE)?.reader();
+    key.expect(der::INTEGER)?;
+    key.expect(der::OCTET_STRING)?;
+    key.optional(der::context(0));
+
+    let public_key = key.expect(der::context(1))?;
+    public_key
+        .reader()
+        .expect(der::BIT_STRING)?
+        .content
+        .get(1..)
+}
+
+/// Reads the public key from a PKCS#1 RSA private key, as a PKCS#1 RSA public key.
+pub(crate) fn rsa_public_key(der: &[u8]) -> Option<Vec<u8>> {
+    let mut key = Reader::new(der).expect(der::SEQUENCE)?.reader();
+    key.expect(der::INTEGER)?;
+    let modulus = key.expect(der::INTEGER)?;
+    let exponent = key.expect(der::INTEGER)?;
+
+    Some(der::sequence(&[
+        modulus.raw.to_vec(),
+        exponent.raw.to_vec(),
+    ]))
+}
+
+/// Reads the public key from a PKCS#8 private key of RSA or EC, as in certificates.
+pub(crate) fn pkcs8_public_key(der: &[u8]) -> Option<Vec<u8>> {
+    let mut key = Reader::new(der).expect(der::SEQUENCE)?.reader();
+    key.expect(der::INTEGER)?;
+    let algorithm = key.expect(der::SEQUENCE)?.reader().expect(der::OID)?;
+    let private_key = key.expect(der::OCTET_STRING)?.content;
+
+    match algorithm.raw {
+        a if a == der::oid(RSA_ENCRYPTION) => rsa_public_key(private_key),
+        a if a == der::oid(EC_PUBLIC_KEY) => sec1_public_key(private_key).map(<[u8]>::to_vec),
+        _ => None,
+    }
+}
+
+/// Certificate revocation list (RFC 5280). Its signature is not verified, so it must come from a
+/// trusted source.
+pub(crate) struct Crl<'a> {
+    pub(crate) issuer: &'a [u8],
+
+    /// Time by which the next list is issued, after which this one is stale.
+    pub(crate) next_update: Option<SystemTime>,
+
+    /// Serial numbers of the revoked certificates.
+    pub(crate) revoked: Vec<&'a [u8]>,
+}
+
+impl<'a> Crl<'a> {
+    pub(crate) fn parse(der: &'a [u8]) -> Option<Self> {
+        let crl = Reader::new(der).expect(der::SEQUENCE)?;
+        let mut tbs = crl.reader().expect(der::SEQUENCE)?.reader();
+
+        tbs.optional(der::INTEGER);
+        tbs.expect(der::SEQUENCE)?;
+        let issuer = tbs.expect(der::SEQUENCE)?.raw;
+        tbs.next()?.time()?;
+        let next_update = match tbs
+            .optional(der::UTC_TIME)
+            .or_else(|| tbs.optional(der::GENERALIZED_TIME))
+        {
+            Some(t) => Some(t.time()?),
+            None => None,
+        };
+
+        let revoked = match tbs.optional(der::SEQUENCE) {
+            Some(entries) => entries
+                .reader()
+                .map(|e| Some(e.reader().expect(der::INTEGER)?.content))
+                .collect::<Option<_>>()?,
+            None => Vec::new(),
+        };
+
+        Some(Self {
+            issuer,
+            next_update,
+            revoked,
+        })
+    }
+}
+
+/// Formats a distinguished name as in RFC 4514, such as `CN=client,O=Example`.
+pub(crate) fn format_name(name: &[u8]) -> Option<String> {
+    let mut attributes = Vec::new();
+    for rdn in Reader::new(name).expect(der::SEQUENCE)?.reader() {
+        let mut values = Vec::new();
+        for attribute in rdn.reader() {
+            let mut attribute = attribute.reader();
+            let kind = attribute.expect(der::OID)?;
+            let value = attribute.next()?;
+            let kind = match ATTRIBUTE_NAMES
+                .iter()
+                .find(|(oid, _)| der::oid(oid) == kind.raw)
+            {
+                Some((_, name)) => name.to_string(),
+                None => format_oid(kind.content),
+            };
+
+            let value = match value.tag {
+                der::UTF8_STRING | der::PRINTABLE_STRING | der::IA5_STRING => {
+                    escape(&String::from_utf8_lossy(value.content))
+                }
+                _ => format!("#{}", hex(value.raw)),
+            };
+
+            values.push(format!("{}={}", kind, value));
+        }
+
+        attributes.push(values.join("+"));
+    }
+
+    // RFC 4514 starts from the last RDN.
+    attributes.reverse();
+    Some(attributes.join(","))
+}
+
+const ATTRIBUTE_NAMES: &[(&[u64], &str)] = &[
+    (COMMON_NAME, "CN"),
+    (&[2, 5, 4, 6], "C"),
+    (&[2, 5, 4, 7], "L"),
+    (&[2, 5, 4, 8], "ST"),
+    (&[2, 5, 4, 9], "STREET"),
+    (&[2, 5, 4, 10], "O"),
+    (&[2, 5, 4, 11], "OU"),
+    (&[0, 9, 2342, 19200300, 100, 1, 1], "UID"),
+    (&[0, 9, 2342, 19200300, 100, 1, 25], "DC"),
+];
+
+fn format_oid(content: &[u8]) -> String {
+    let mut arcs = Vec::new();
+    let mut arc = 0u64;
+    for b in content {
+        arc = arc << 7 | u64::from(b & 0x7f);
+        if b & 0x80 == 0 {
+            if arcs.is_empty() {
+                let first = (arc / 40).min(2);
+                arcs.push(first);
+                arcs.push(arc - first * 40);
+            } else {
+                arcs.push(arc);
+            }
+
+            arc = 0;
+        }
+    }
+
+    arcs.iter()
+        .map(u64::to_string)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let leading = i == 0 && (c == ' ' || c == '#');
+        let trailing = i == last && c == ' ';
+        if leading || trailing || matches!(c, '"' | '+' | ',' | ';' | '<' | '>' | '\\') {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    fn fixture(name: &str, label: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/keys")
+            .join(name);
+        let pem = std::fs::read_to_string(path).unwrap();
+        from_pem(&pem, label).remove(0)
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn parses_a_certificate() {
+        let der = fixture("ec.crt", "CERTIFICATE");
+        let certificate = Certificate::parse(&der).unwrap();
+
+        assert_eq!(
+            hex(certificate.serial),
+            "32e75022613878ac9b780acd63f0ba3da3447fd2"
+        );
+        assert_eq!(certificate.issuer, certificate.subject);
+        assert_eq!(format_name(certificate.subject).unwrap(), "CN=localhost");
+        assert_eq!(certificate.validity, at(1_792_381_057)..at(4_945_981_057));
+        assert!(certificate.ocsp_responder.is_none());
+
+        let key = fixture("ec.pem", "EC PRIVATE KEY");
+        assert_eq!(Some(certificate.public_key), sec1_public_key(&key));
+    }
+
+    #[test]
+    fn parses_an_rsa_public_key() {
+        let der = fixture("rsa.crt", "CERTIFICATE");
+        let certificate = Certificate::parse(&der).unwrap();
+
+        // A PKCS#1 RSAPublicKey, whose modulus has a leading zero as its high bit is set.
+        let mut key = Reader::new(certificate.public_key)
+            .expect(der::SEQUENCE)
+            .unwrap()
+            .reader();
+        let modulus = key.expect(der::INTEGER).unwrap().content;
+        assert_eq!(modulus.len(), 257);
+        assert_eq!(&modulus[..3], [0x00, 0xc4, 0x12]);
+        assert_eq!(key.expect(der::INTEGER).unwrap().content, [1, 0, 1]);
+    }
+
+    #[test]
+    fn parses_a_self_signed_certificate() {
+        let key = KeyPair::generate().unwrap();
+        let names = ["example.test".to_string(), "www.example.test".to_string()];
+        let der = self_signed(&key, &names, at(0)..at(2_524_608_000), &[]).unwrap();
+        let certificate = Certificate::parse(&der).unwrap();
+
+        assert_eq!(certificate.issuer, name("example.test"));
+        assert_eq!(certificate.subject, certificate.issuer);
+        assert_eq!(certificate.validity, at(0)..at(2_524_608_000));
+        assert_eq!(certificate.public_key, key.inner.public_key().as_ref());
+        assert!((1..=16).contains(&certificate.serial.len()));
+        assert_eq!(certificate.names, names);
+    }
+
+    #[test]
+    fn lists_ip_literals_as_ip_addresses() {
+        let names = [
+            "localhost",
+            "127.0.0.1",
+            "::1",
+            "[2001:db8::1]",
+            "1.2.3.4.example",
+        ]
+        .map(String::from);
+        let mut v6 = [0; 16];
+        v6[15] = 1;
+        let mut documentation = [0; 16];
+        documentation[..4].copy_from_slice(&[0x20, 0x01, 0x0d, 0xb8]);
+        documentation[15] = 1;
+
+        assert_eq!(
+            subject_alt_name(&names),
+            extension(
+                SUBJECT_ALT_NAME,
+                false,
+                &der::sequence(&[
+                    der::tlv(der::context_primitive(2), b"localhost"),
+                    der::tlv(der::context_primitive(7), &[127, 0, 0, 1]),
+                    der::tlv(der::context_primitive(7), &v6),
+                    der::tlv(der::context_primitive(7), &documentation),
+                    der::tlv(der::context_primitive(2), b"1.2.3.4.example"),
+                ])
+            )
+        );
+
+        let key = KeyPair::generate().unwrap();
+        let der = self_signed(&key, &names, at(0)..at(2_524_608_000), &[]).unwrap();
+        assert_eq!(
+            Certificate::parse(&der).unwrap().names,
+            [
+                "localhost",
+                "127.0.0.1",
+                "::1",
+                "2001:db8::1",
+                "1.2.3.4.example"
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_certificates() {
+        let der = fixture("ec.crt", "CERTIFICATE");
+
+        assert!(Certificate::parse(&der[..der.len() - 1]).is_none());
+        assert!(Certificate::parse(&der[4..]).is_none());
+        assert!(Certificate::parse(&[]).is_none());
+    }
+
+    #[test]
+    fn parses_crls() {
+        let issuer = fixture("ec.crt", "CERTIFICATE");
+        let issuer = Certificate::parse(&issuer).unwrap();
+        let revoked = fixture("rsa.crt", "CERTIFICATE");
+        let revoked = Certificate::parse(&revoked).unwrap();
+
+        let der = fixture("revoked.crl", "X509 CRL");
+        let crl = Crl::parse(&der).unwrap();
+        assert_eq!(crl.issuer, issuer.subject);
+        assert_eq!(crl.revoked, [revoked.serial]);
+        assert_eq!(crl.next_update, Some(at(4_945_982_400)));
+
+        let der = std::fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys/stale.crl"),
+        )
+        .unwrap();
+        let crl = Crl::parse(&der).unwrap();
+        assert_eq!(crl.issuer, issuer.subject);
+        assert!(crl.revoked.is_empty());
+        assert_eq!(crl.next_update, Some(at(1_580_515_200)));
+
+        assert!(Crl::parse(&der[..der.len() - 1]).is_none());
+    }
+
+    fn attribute(oid: &[u64], value: Vec<u8>) -> Vec<u8> {
+        der::sequence(&[der::oid(oid), value])
+    }
+
+    #[test]
+    fn formats_names() {
+        let name = der::sequence(&[
+            der::set(&[attribute(
+                &[2, 5, 4, 6],
+                der::tlv(der::PRINTABLE_STRING, b"JP"),
+            )]),
+            der::set(&[
+                attribute(&[2, 5, 4, 10], der::utf8_string("Example, Inc.")),
+                attribute(&[2, 5, 4, 11], der::utf8_string("#dev ")),
+            ]),
+            der::set(&[attribute(&[1, 2, 3, 4], der::utf8_string("a+b"))]),
+            der::set(&[attribute(COMMON_NAME, der::integer(&[1]))]),
+        ]);
+
+        assert_eq!(
+            format_name(&name).unwrap(),
+            "CN=#020101,1.2.3.4=a\\+b,O=Example\\, Inc.+OU=\\#dev\\ ,C=JP"
+        );
+        assert_eq!(format_name(&der::sequence(&[])).unwrap(), "");
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        for name in [
+            der::set(&[]),
+            der::sequence(&[der::set(&[der::sequence(&[der::oid(COMMON_NAME)])])]),
+            der::sequence(&[der::set(&[der::sequence(&[der::utf8_string("x")])])]),
+        ] {
+            assert!(format_name(&name).is_none());
+        }
+    }
+
+    #[test]
+    fn round_trips_pem() {
+        let der = fixture("ec.crt", "CERTIFICATE");
+        let pem = pem("CERTIFICATE", &der);
+
+        assert!(pem.lines().all(|l| l.len() <= 64));
+        assert_eq!(from_pem(&pem, "CERTIFICATE"), [der]);
+        assert!(from_pem(&pem, "PRIVATE KEY").is_empty());
+    }
+}
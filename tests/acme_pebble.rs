@@ -0,0 +1,48 @@
+//! Obtains a certificate from a local Pebble instance (https://github.com/letsencrypt/pebble).
+//!
+//! Ignored by default, as it needs Pebble running with HTTP-01 validation pointed at this
+//! machine, such as:
+//!
+//! ```sh
+//! PEBBLE_VA_ALWAYS_VALID=1 pebble -config test/config/pebble-config.json &
+//! PEBBLE_CA=test/certs/pebble.minica.pem cargo test --test acme_pebble -- --ignored
+//! ```
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use h123::acme::{Acme, Challenge, Config};
+use h123::tls::CertResolver;
+
+#[tokio::test]
+#[ignore]
+async fn obtains_a_certificate_from_pebble() {
+    let directory = std::env::var("PEBBLE_DIRECTORY")
+        .unwrap_or_else(|_| "https://localhost:14000/dir".to_string());
+    let storage = std::env::temp_dir().join(format!("h123-pebble-{}", std::process::id()));
+
+    let mut config = Config::new(directory, vec!["localhost".to_string()], &storage);
+    config.agree_tos = true;
+    config.challenge = Challenge::Http01;
+    config.http01_bind = SocketAddr::from(([0, 0, 0, 0], 5002));
+    config.ca_cert = std::env::var_os("PEBBLE_CA").map(PathBuf::from);
+
+    let acme = Arc::new(Acme::new(config).unwrap());
+    let running = tokio::spawn(acme.run());
+
+    let cert_chain = storage.join("cert.pem");
+    for _ in 0..60 {
+        if cert_chain.exists() {
+            break;
+        }
+
+        assert!(!running.is_finished(), "{:?}", running.await);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+
+    running.abort();
+    CertResolver::load(cert_chain, storage.join("privkey.pem")).unwrap();
+    std::fs::remove_dir_all(storage).unwrap();
+}